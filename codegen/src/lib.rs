@@ -0,0 +1,851 @@
+//! Code generator for simpletcp message schemas
+//!
+//! A schema describes records and enums exchanged between peers:
+//! ```text
+//! /// Color of a user
+//! enum Color {
+//!     Red = 0;
+//!     Green = 1;
+//! }
+//!
+//! record User {
+//!     id: u64;
+//!     name: string;
+//!     color: Color;
+//!     tags: list<string>;
+//!     email: option<string> @since(2);
+//! }
+//! ```
+//! Field types are `bool`, `u8` to `u64`, `i8` to `i64`, `f32`, `f64`, `string`, `bytes`,
+//! `list<T>`, `map<K, V>`, `option<T>` and names of records and enums.
+//! Comments starting with `///` are copied to the generated code.
+//!
+//! Generated types implement `MessageEncode` and `MessageDecode` of simpletcp.
+//! Records are encoded as a buffer (see `Message::write_buffer`) containing fields in order, enums as `u32` value.
+//!
+//! # Compatibility
+//! Peers using different versions of a schema can communicate if the schema only changes by these rules:
+//! * New fields are appended to the end of a record with `@since(n)`, where `n` is greater than version of all existing fields
+//! * Existing fields are not removed, renamed, reordered or changed in any other way
+//! * New enum variants are added with unused values, existing variants are not changed
+//!
+//! Fields without `@since` are version 1. Older peers skip fields they do not know,
+//! newer peers use default values for fields missing in messages from older peers.
+//! Older peers fail to decode unknown enum variants.
+//! [check_compatible](fn.check_compatible.html) verifies that a new version of a schema follows these rules.
+//!
+//! # Usage
+//! In `build.rs`:
+//! ```no_run
+//! simpletcp_codegen::compile("schema/protocol.stcp").unwrap();
+//! ```
+//! Then in the crate:
+//! ```ignore
+//! include!(concat!(env!("OUT_DIR"), "/protocol.rs"));
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::{Formatter, Write};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::{env, fs, io};
+
+/// Error occurred when generating code
+pub enum Error {
+    /// Schema could not be read or generated code could not be written
+    Io(io::Error),
+
+    /// Schema is not valid
+    Schema { line: usize, message: String },
+
+    /// New version of a schema breaks compatibility, see [check_compatible](fn.check_compatible.html)
+    Incompatible(String),
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => f.write_fmt(format_args!("IO error: {}", err)),
+            Error::Schema { line, message } => {
+                f.write_fmt(format_args!("Line {}: {}", line, message))
+            }
+            Error::Incompatible(message) => f.write_str(message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Generates Rust code from a schema file, to be called from a build script
+///
+/// The code is written to `OUT_DIR` with the same name as the schema and `rs` extension.
+/// # Arguments
+///
+/// * `path` - Path to the schema
+pub fn compile<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let path = path.as_ref();
+    println!("cargo:rerun-if-changed={}", path.display());
+    let code = generate(&fs::read_to_string(path)?)?;
+
+    let out_dir = env::var_os("OUT_DIR")
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "OUT_DIR is not set"))?;
+    let name = path
+        .file_stem()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Invalid schema path"))?;
+    let mut out = Path::new(&out_dir).join(name);
+    out.set_extension("rs");
+    fs::write(out, code)?;
+    Ok(())
+}
+
+/// Generates Rust code from a schema
+///
+/// # Arguments
+///
+/// * `source` - Content of the schema
+/// # Returns
+/// Generated code or [Error](enum.Error.html) if the schema is not valid
+pub fn generate(source: &str) -> Result<String, Error> {
+    let schema = parse(source)?;
+    let mut out = String::from("// Generated by simpletcp-codegen, do not edit\n");
+    for item in &schema {
+        out.push('\n');
+        match item {
+            Item::Record(record) => generate_record(&mut out, record),
+            Item::Enum(e) => generate_enum(&mut out, e),
+        }
+    }
+    Ok(out)
+}
+
+/// Checks that a new version of a schema is compatible with the old one
+///
+/// See [compatibility rules](index.html#compatibility)
+/// # Arguments
+///
+/// * `old` - Content of the old schema
+/// * `new` - Content of the new schema
+/// # Returns
+/// [Incompatible](enum.Error.html#variant.Incompatible) if a rule is broken
+pub fn check_compatible(old: &str, new: &str) -> Result<(), Error> {
+    let old = parse(old)?;
+    let new = parse(new)?;
+    for old_item in &old {
+        let new_item = new
+            .iter()
+            .find(|item| item.name() == old_item.name())
+            .ok_or_else(|| Error::Incompatible(format!("{} was removed.", old_item.name())))?;
+        match (old_item, new_item) {
+            (Item::Record(old), Item::Record(new)) => check_record(old, new)?,
+            (Item::Enum(old), Item::Enum(new)) => check_enum(old, new)?,
+            _ => {
+                return Err(Error::Incompatible(format!(
+                    "{} was changed between record and enum.",
+                    old_item.name()
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_record(old: &Record, new: &Record) -> Result<(), Error> {
+    for (i, field) in old.fields.iter().enumerate() {
+        let same = new.fields.get(i).is_some_and(|new_field| {
+            new_field.name == field.name
+                && new_field.ty == field.ty
+                && new_field.since == field.since
+        });
+        if !same {
+            return Err(Error::Incompatible(format!(
+                "Field {}.{} was changed or removed.",
+                old.name, field.name
+            )));
+        }
+    }
+    let version = old.fields.iter().map(|field| field.since).fold(1, u64::max);
+    for field in &new.fields[old.fields.len()..] {
+        if field.since <= version {
+            return Err(Error::Incompatible(format!(
+                "Field {}.{} must be added with @since greater than {}.",
+                new.name, field.name, version
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_enum(old: &Enum, new: &Enum) -> Result<(), Error> {
+    for variant in &old.variants {
+        if !new
+            .variants
+            .iter()
+            .any(|v| v.name == variant.name && v.value == variant.value)
+        {
+            return Err(Error::Incompatible(format!(
+                "Variant {}::{} was changed or removed.",
+                old.name, variant.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Schema
+
+#[derive(PartialEq)]
+enum Type {
+    Primitive(&'static str),
+    String,
+    Bytes,
+    List(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+    Option(Box<Type>),
+    Named(String),
+}
+
+// Types encoded by `Message::write_*` methods of the same name
+const PRIMITIVES: [&str; 11] = [
+    "bool", "u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "f32", "f64",
+];
+
+// Raw identifiers cannot be used for these
+const RESERVED: [&str; 4] = ["self", "Self", "super", "crate"];
+
+const KEYWORDS: &[&str] = &[
+    "as", "break", "const", "continue", "else", "enum", "extern", "false", "fn", "for", "if",
+    "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn",
+    "abstract", "become", "box", "do", "final", "macro", "override", "priv", "typeof", "unsized",
+    "virtual", "yield", "try", "gen",
+];
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Primitive(name) => f.write_str(name),
+            Type::String => f.write_str("string"),
+            Type::Bytes => f.write_str("bytes"),
+            Type::List(ty) => f.write_fmt(format_args!("list<{}>", ty)),
+            Type::Map(key, value) => f.write_fmt(format_args!("map<{}, {}>", key, value)),
+            Type::Option(ty) => f.write_fmt(format_args!("option<{}>", ty)),
+            Type::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+impl Type {
+    fn rust(&self) -> String {
+        match self {
+            Type::Primitive(name) => name.to_string(),
+            Type::String => "::std::string::String".to_string(),
+            Type::Bytes => "::std::vec::Vec<u8>".to_string(),
+            Type::List(ty) => format!("::std::vec::Vec<{}>", ty.rust()),
+            Type::Map(key, value) => format!(
+                "::std::collections::HashMap<{}, {}>",
+                key.rust(),
+                value.rust()
+            ),
+            Type::Option(ty) => format!("::std::option::Option<{}>", ty.rust()),
+            Type::Named(name) => name.clone(),
+        }
+    }
+}
+
+struct Field {
+    docs: Vec<String>,
+    name: String,
+    ty: Type,
+    since: u64,
+    line: usize,
+}
+
+struct Record {
+    docs: Vec<String>,
+    name: String,
+    fields: Vec<Field>,
+}
+
+struct Variant {
+    docs: Vec<String>,
+    name: String,
+    value: u64,
+    line: usize,
+}
+
+struct Enum {
+    docs: Vec<String>,
+    name: String,
+    variants: Vec<Variant>,
+}
+
+enum Item {
+    Record(Record),
+    Enum(Enum),
+}
+
+impl Item {
+    fn name(&self) -> &str {
+        match self {
+            Item::Record(record) => &record.name,
+            Item::Enum(e) => &e.name,
+        }
+    }
+}
+
+// Parser
+
+#[derive(PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u64),
+    Symbol(char),
+    Doc(String),
+}
+
+fn schema_error<T>(line: usize, message: String) -> Result<T, Error> {
+    Err(Error::Schema { line, message })
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, Error> {
+    let mut tokens = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            if text[start..].starts_with("///") {
+                tokens.push((Token::Doc(text[start + 3..].trim().to_string()), line));
+                break;
+            }
+            if text[start..].starts_with("//") {
+                break;
+            }
+
+            let mut end = start + c.len_utf8();
+            while let Some(&(i, next)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_')
+                    || !(next.is_ascii_alphanumeric() || next == '_')
+                {
+                    break;
+                }
+                end = i + next.len_utf8();
+                chars.next();
+            }
+            let word = &text[start..end];
+            let token = if c.is_ascii_digit() {
+                match word.parse() {
+                    Ok(n) => Token::Number(n),
+                    Err(_) => return schema_error(line, format!("Invalid number {}.", word)),
+                }
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                Token::Ident(word.to_string())
+            } else if "{}()<>:;=,@".contains(c) {
+                Token::Symbol(c)
+            } else {
+                return schema_error(line, format!("Unexpected character {:?}.", c));
+            };
+            tokens.push((token, line));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos).or_else(|| self.tokens.last()) {
+            Some((_, line)) => *line,
+            None => 1,
+        }
+    }
+
+    fn next(&mut self) -> Result<&Token, Error> {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) => {
+                self.pos += 1;
+                Ok(token)
+            }
+            None => schema_error(self.line(), "Unexpected end of schema.".to_string()),
+        }
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if let Some((Token::Symbol(c), _)) = self.tokens.get(self.pos) {
+            if *c == symbol {
+                self.pos += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), Error> {
+        if !self.eat(symbol) {
+            return schema_error(self.line(), format!("Expected '{}'.", symbol));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        let line = self.line();
+        match self.next()? {
+            Token::Ident(name) => Ok(name.clone()),
+            _ => schema_error(line, "Expected name.".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, Error> {
+        let line = self.line();
+        match self.next()? {
+            Token::Number(n) => Ok(*n),
+            _ => schema_error(line, "Expected number.".to_string()),
+        }
+    }
+
+    fn docs(&mut self) -> Vec<String> {
+        let mut docs = Vec::new();
+        while let Some((Token::Doc(doc), _)) = self.tokens.get(self.pos) {
+            docs.push(doc.clone());
+            self.pos += 1;
+        }
+        docs
+    }
+
+    fn ty(&mut self) -> Result<Type, Error> {
+        let name = self.ident()?;
+        let ty = match name.as_str() {
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            "list" | "option" => {
+                self.expect('<')?;
+                let inner = Box::new(self.ty()?);
+                self.expect('>')?;
+                if name == "list" {
+                    Type::List(inner)
+                } else {
+                    Type::Option(inner)
+                }
+            }
+            "map" => {
+                self.expect('<')?;
+                let key = Box::new(self.ty()?);
+                self.expect(',')?;
+                let value = Box::new(self.ty()?);
+                self.expect('>')?;
+                Type::Map(key, value)
+            }
+            _ => match PRIMITIVES.iter().find(|p| **p == name) {
+                Some(p) => Type::Primitive(p),
+                None => Type::Named(name),
+            },
+        };
+        Ok(ty)
+    }
+
+    fn record(&mut self, docs: Vec<String>) -> Result<Record, Error> {
+        let name = self.ident()?;
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        loop {
+            let docs = self.docs();
+            if self.eat('}') {
+                break;
+            }
+            let line = self.line();
+            let name = self.ident()?;
+            self.expect(':')?;
+            let ty = self.ty()?;
+            let mut since = 1;
+            if self.eat('@') {
+                if self.ident()? != "since" {
+                    return schema_error(line, "Expected @since.".to_string());
+                }
+                self.expect('(')?;
+                since = self.number()?;
+                self.expect(')')?;
+            }
+            self.expect(';')?;
+            fields.push(Field {
+                docs,
+                name,
+                ty,
+                since,
+                line,
+            });
+        }
+        Ok(Record { docs, name, fields })
+    }
+
+    fn enumeration(&mut self, docs: Vec<String>) -> Result<Enum, Error> {
+        let name = self.ident()?;
+        self.expect('{')?;
+        let mut variants = Vec::new();
+        loop {
+            let docs = self.docs();
+            if self.eat('}') {
+                break;
+            }
+            let line = self.line();
+            let name = self.ident()?;
+            self.expect('=')?;
+            let value = self.number()?;
+            self.expect(';')?;
+            variants.push(Variant {
+                docs,
+                name,
+                value,
+                line,
+            });
+        }
+        Ok(Enum {
+            docs,
+            name,
+            variants,
+        })
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Item>, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let mut items = Vec::new();
+    let mut lines = Vec::new();
+    loop {
+        let docs = parser.docs();
+        if parser.pos == parser.tokens.len() {
+            break;
+        }
+        let line = parser.line();
+        let item = match parser.ident()?.as_str() {
+            "record" => Item::Record(parser.record(docs)?),
+            "enum" => Item::Enum(parser.enumeration(docs)?),
+            _ => return schema_error(line, "Expected record or enum.".to_string()),
+        };
+        items.push(item);
+        lines.push(line);
+    }
+    validate(&items, &lines)?;
+    Ok(items)
+}
+
+fn validate(items: &[Item], lines: &[usize]) -> Result<(), Error> {
+    let mut kinds = HashMap::new();
+    for (item, line) in items.iter().zip(lines) {
+        let name = item.name();
+        check_name(name, *line)?;
+        if PRIMITIVES.contains(&name)
+            || ["string", "bytes", "list", "map", "option"].contains(&name)
+        {
+            return schema_error(*line, format!("{} is a built-in type.", name));
+        }
+        if kinds.insert(name, item).is_some() {
+            return schema_error(*line, format!("{} is defined more than once.", name));
+        }
+    }
+
+    for (item, line) in items.iter().zip(lines) {
+        match item {
+            Item::Record(record) => {
+                let mut names = HashSet::new();
+                let mut version = 1;
+                for field in &record.fields {
+                    check_name(&field.name, field.line)?;
+                    if !names.insert(&field.name) {
+                        return schema_error(
+                            field.line,
+                            format!("Field {} is defined more than once.", field.name),
+                        );
+                    }
+                    check_type(&field.ty, &kinds, field.line)?;
+                    if field.since < version {
+                        return schema_error(
+                            field.line,
+                            format!(
+                                "Field {} must have @since at least {}, new fields are added to the end.",
+                                field.name, version
+                            ),
+                        );
+                    }
+                    version = field.since;
+                }
+                check_recursion(record, &record.name, &kinds, &mut HashSet::new(), *line)?;
+            }
+            Item::Enum(e) => {
+                if e.variants.is_empty() {
+                    return schema_error(*line, format!("Enum {} has no variants.", e.name));
+                }
+                let mut names = HashSet::new();
+                let mut values = HashSet::new();
+                for variant in &e.variants {
+                    check_name(&variant.name, variant.line)?;
+                    if !names.insert(&variant.name) {
+                        return schema_error(
+                            variant.line,
+                            format!("Variant {} is defined more than once.", variant.name),
+                        );
+                    }
+                    if variant.value > u32::MAX as u64 || !values.insert(variant.value) {
+                        return schema_error(
+                            variant.line,
+                            format!(
+                                "Value of variant {} is used more than once or too large.",
+                                variant.name
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_name(name: &str, line: usize) -> Result<(), Error> {
+    if RESERVED.contains(&name) {
+        return schema_error(line, format!("{} cannot be used as a name.", name));
+    }
+    Ok(())
+}
+
+fn check_type(ty: &Type, kinds: &HashMap<&str, &Item>, line: usize) -> Result<(), Error> {
+    match ty {
+        Type::Primitive(_) | Type::String | Type::Bytes => Ok(()),
+        Type::List(ty) | Type::Option(ty) => check_type(ty, kinds, line),
+        Type::Map(key, value) => {
+            // Keys must implement Eq and Hash
+            let hashable = match &**key {
+                Type::Primitive(name) => !name.starts_with('f'),
+                Type::String | Type::Bytes => true,
+                Type::Named(name) => matches!(kinds.get(name.as_str()), Some(Item::Enum(_))),
+                _ => false,
+            };
+            if !hashable {
+                return schema_error(line, format!("{} cannot be a map key.", key));
+            }
+            check_type(key, kinds, line)?;
+            check_type(value, kinds, line)
+        }
+        Type::Named(name) => {
+            if !kinds.contains_key(name.as_str()) {
+                return schema_error(line, format!("Unknown type {}.", name));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Records containing themselves by value would have infinite size
+fn check_recursion<'a>(
+    record: &'a Record,
+    root: &str,
+    kinds: &HashMap<&str, &'a Item>,
+    visited: &mut HashSet<&'a str>,
+    line: usize,
+) -> Result<(), Error> {
+    if !visited.insert(&record.name) {
+        return Ok(());
+    }
+    for field in &record.fields {
+        let mut ty = &field.ty;
+        while let Type::Option(inner) = ty {
+            ty = inner;
+        }
+        if let Type::Named(name) = ty {
+            if name == root {
+                return schema_error(
+                    line,
+                    format!("Record {} contains itself, use list instead.", root),
+                );
+            }
+            if let Some(Item::Record(inner)) = kinds.get(name.as_str()) {
+                check_recursion(inner, root, kinds, visited, line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Generator
+
+const SIMPLETCP: &str = "::simpletcp::simpletcp";
+
+fn ident(name: &str) -> String {
+    if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn generate_docs(out: &mut String, docs: &[String], indent: &str) {
+    for doc in docs {
+        if doc.is_empty() {
+            writeln!(out, "{}///", indent).unwrap();
+        } else {
+            writeln!(out, "{}/// {}", indent, doc).unwrap();
+        }
+    }
+}
+
+fn generate_record(out: &mut String, record: &Record) {
+    let name = ident(&record.name);
+    generate_docs(out, &record.docs, "");
+    writeln!(out, "#[derive(Clone, Debug, Default, PartialEq)]").unwrap();
+    writeln!(out, "pub struct {} {{", name).unwrap();
+    for field in &record.fields {
+        generate_docs(out, &field.docs, "    ");
+        if field.since > 1 {
+            if !field.docs.is_empty() {
+                writeln!(out, "    ///").unwrap();
+            }
+            writeln!(out, "    /// Since version {}", field.since).unwrap();
+        }
+        writeln!(out, "    pub {}: {},", ident(&field.name), field.ty.rust()).unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+
+    let body = if record.fields.is_empty() {
+        "body"
+    } else {
+        "mut body"
+    };
+    writeln!(out, "impl {}::MessageEncode for {} {{", SIMPLETCP, name).unwrap();
+    writeln!(
+        out,
+        "    fn encode(&self, msg: &mut {}::Message) {{",
+        SIMPLETCP
+    )
+    .unwrap();
+    writeln!(out, "        let {} = {}::Message::new();", body, SIMPLETCP).unwrap();
+    if !record.fields.is_empty() {
+        writeln!(out, "        body.set_tagged(msg.is_tagged());").unwrap();
+    }
+    for field in &record.fields {
+        writeln!(
+            out,
+            "        {}::MessageEncode::encode(&self.{}, &mut body);",
+            SIMPLETCP,
+            ident(&field.name)
+        )
+        .unwrap();
+    }
+    writeln!(out, "        msg.write_buffer(body.as_bytes());").unwrap();
+    writeln!(out, "    }}\n}}\n").unwrap();
+
+    writeln!(out, "impl {}::MessageDecode for {} {{", SIMPLETCP, name).unwrap();
+    writeln!(
+        out,
+        "    fn decode(msg: &mut {0}::Message) -> ::std::result::Result<Self, {0}::MessageError> {{",
+        SIMPLETCP
+    )
+    .unwrap();
+    if record.fields.is_empty() {
+        writeln!(out, "        msg.read_message()?;").unwrap();
+    } else {
+        writeln!(out, "        let mut body = msg.read_message()?;").unwrap();
+    }
+    writeln!(out, "        Ok({} {{", name).unwrap();
+    for field in &record.fields {
+        let decode = format!("{}::MessageDecode::decode(&mut body)?", SIMPLETCP);
+        if field.since > 1 {
+            // Missing in messages from older peers
+            writeln!(
+                out,
+                "            {}: if body.remaining() > 0 {{ {} }} else {{ ::std::default::Default::default() }},",
+                ident(&field.name),
+                decode
+            )
+            .unwrap();
+        } else {
+            writeln!(out, "            {}: {},", ident(&field.name), decode).unwrap();
+        }
+    }
+    writeln!(out, "        }})\n    }}\n}}").unwrap();
+}
+
+fn generate_enum(out: &mut String, e: &Enum) {
+    let name = ident(&e.name);
+    generate_docs(out, &e.docs, "");
+    writeln!(
+        out,
+        "#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]"
+    )
+    .unwrap();
+    writeln!(out, "pub enum {} {{", name).unwrap();
+    for (i, variant) in e.variants.iter().enumerate() {
+        generate_docs(out, &variant.docs, "    ");
+        if i == 0 {
+            writeln!(out, "    #[default]").unwrap();
+        }
+        writeln!(out, "    {},", ident(&variant.name)).unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl {}::MessageEncode for {} {{", SIMPLETCP, name).unwrap();
+    writeln!(
+        out,
+        "    fn encode(&self, msg: &mut {}::Message) {{",
+        SIMPLETCP
+    )
+    .unwrap();
+    writeln!(out, "        msg.write_u32(match self {{").unwrap();
+    for variant in &e.variants {
+        writeln!(
+            out,
+            "            {}::{} => {},",
+            name,
+            ident(&variant.name),
+            variant.value
+        )
+        .unwrap();
+    }
+    writeln!(out, "        }});\n    }}\n}}\n").unwrap();
+
+    writeln!(out, "impl {}::MessageDecode for {} {{", SIMPLETCP, name).unwrap();
+    writeln!(
+        out,
+        "    fn decode(msg: &mut {0}::Message) -> ::std::result::Result<Self, {0}::MessageError> {{",
+        SIMPLETCP
+    )
+    .unwrap();
+    writeln!(out, "        match msg.read_u32()? {{").unwrap();
+    for variant in &e.variants {
+        writeln!(
+            out,
+            "            {} => Ok({}::{}),",
+            variant.value,
+            name,
+            ident(&variant.name)
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "            _ => Err({}::MessageError::InvalidValue),",
+        SIMPLETCP
+    )
+    .unwrap();
+    writeln!(out, "        }}\n    }}\n}}").unwrap();
+}
@@ -0,0 +1,374 @@
+//! Derive macros for `MessageEncode` and `MessageDecode` traits of simpletcp
+//!
+//! Use through `derive` cargo feature of simpletcp.
+
+extern crate proc_macro;
+
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Field, Fields, GenericArgument, Generics, PathArguments,
+    Type,
+};
+
+/// Derives `MessageEncode`
+#[proc_macro_derive(MessageEncode, attributes(message))]
+pub fn derive_encode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `MessageDecode`
+#[proc_macro_derive(MessageDecode, attributes(message))]
+pub fn derive_decode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+// Types written by `Message::write_*` methods of the same name
+const PRIMITIVES: [&str; 14] = [
+    "u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "u128", "i128", "f32", "f64", "bool",
+    "char",
+];
+
+// Types written by `Message::write_varint_*` methods
+const VARINTS: [&str; 6] = ["u16", "u32", "u64", "i16", "i32", "i64"];
+
+// Types that are likely to be mistaken for supported ones
+const UNSUPPORTED: [&str; 6] = ["usize", "isize", "str", "VecDeque", "HashSet", "BTreeSet"];
+
+// How a field is written and read
+enum Encoding {
+    Fixed(Ident),
+    Varint(Ident),
+    VarintBuffer,
+    Buffer,
+    Nested,
+}
+
+struct FieldInfo {
+    ty: Type,
+    encoding: Encoding,
+}
+
+fn type_name(ty: &Type) -> Option<&Ident> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last().map(|s| &s.ident),
+        _ => None,
+    }
+}
+
+fn is_byte_vec(ty: &Type) -> bool {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => return false,
+    };
+    let segment = path.segments.last().unwrap();
+    if segment.ident != "Vec" {
+        return false;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+            matches!(&args.args[0], GenericArgument::Type(inner) if type_name(inner).is_some_and(|i| i == "u8"))
+        }
+        _ => false,
+    }
+}
+
+fn field_info(field: &Field) -> syn::Result<FieldInfo> {
+    let mut attr: Option<Ident> = None;
+    for a in &field.attrs {
+        if !a.path().is_ident("message") {
+            continue;
+        }
+        a.parse_nested_meta(|meta| {
+            let ident = match meta.path.get_ident() {
+                Some(ident) if ident == "fixed" || ident == "varint" || ident == "buffer" => ident,
+                _ => {
+                    return Err(meta.error(
+                        "unknown message attribute, expected `fixed`, `varint` or `buffer`",
+                    ))
+                }
+            };
+            if attr.is_some() {
+                return Err(meta.error("only one encoding can be specified"));
+            }
+            attr = Some(ident.clone());
+            Ok(())
+        })?;
+    }
+
+    let ty = field.ty.clone();
+    let name = type_name(&ty).map(|i| i.to_string()).filter(|_| match &ty {
+        Type::Path(path) => path.path.segments.len() == 1,
+        _ => false,
+    });
+    let primitive = name.as_deref().filter(|n| PRIMITIVES.contains(n));
+    let encoding = match attr.as_ref().map(|a| a.to_string()).as_deref() {
+        None => {
+            if let Some(primitive) = primitive {
+                Encoding::Fixed(Ident::new(primitive, Span::call_site()))
+            } else if is_byte_vec(&ty) {
+                Encoding::Buffer
+            } else {
+                match &ty {
+                    Type::Path(_) => {}
+                    _ => return Err(syn::Error::new(ty.span(), "unsupported field type")),
+                }
+                let last = type_name(&ty).unwrap().to_string();
+                if UNSUPPORTED.contains(&last.as_str()) {
+                    return Err(syn::Error::new(
+                        ty.span(),
+                        format!("unsupported field type `{}`", last),
+                    ));
+                }
+                Encoding::Nested
+            }
+        }
+        Some("fixed") => match primitive {
+            Some(primitive) if primitive.starts_with(&['u', 'i'][..]) => {
+                Encoding::Fixed(Ident::new(primitive, Span::call_site()))
+            }
+            _ => {
+                return Err(syn::Error::new(
+                    ty.span(),
+                    "`fixed` requires an integer field",
+                ))
+            }
+        },
+        Some("varint") => match primitive {
+            Some(primitive) if VARINTS.contains(&primitive) => {
+                Encoding::Varint(Ident::new(primitive, Span::call_site()))
+            }
+            _ if is_byte_vec(&ty) => Encoding::VarintBuffer,
+            _ => {
+                return Err(syn::Error::new(
+                    ty.span(),
+                    "`varint` requires a 16, 32 or 64-bit integer or `Vec<u8>` field",
+                ))
+            }
+        },
+        _ => {
+            if !is_byte_vec(&ty) {
+                return Err(syn::Error::new(
+                    ty.span(),
+                    "`buffer` requires a `Vec<u8>` field",
+                ));
+            }
+            Encoding::Buffer
+        }
+    };
+    Ok(FieldInfo { ty, encoding })
+}
+
+// Collects results, reporting all errors instead of the first one
+fn collect_all<T>(results: impl Iterator<Item = syn::Result<T>>) -> syn::Result<Vec<T>> {
+    let mut values = Vec::new();
+    let mut error: Option<syn::Error> = None;
+    for result in results {
+        match (result, &mut error) {
+            (Ok(value), _) => values.push(value),
+            (Err(err), Some(error)) => error.combine(err),
+            (Err(err), None) => error = Some(err),
+        }
+    }
+    match error {
+        None => Ok(values),
+        Some(error) => Err(error),
+    }
+}
+
+fn fields_info(fields: &Fields) -> syn::Result<Vec<FieldInfo>> {
+    collect_all(fields.iter().map(field_info))
+}
+
+fn variants_info(data: &syn::DataEnum) -> syn::Result<Vec<Vec<FieldInfo>>> {
+    collect_all(data.variants.iter().map(|v| fields_info(&v.fields)))
+}
+
+// Pattern binding fields to `__field0`, `__field1`, ...
+fn bind_fields(fields: &Fields) -> (TokenStream, Vec<Ident>) {
+    let names: Vec<Ident> = (0..fields.len())
+        .map(|i| format_ident!("__field{}", i))
+        .collect();
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote!({ #(#idents: #names),* })
+        }
+        Fields::Unnamed(_) => quote!(( #(#names),* )),
+        Fields::Unit => quote!(),
+    };
+    (pattern, names)
+}
+
+fn encode_fields(info: &[FieldInfo], names: &[Ident]) -> TokenStream {
+    let writes = info
+        .iter()
+        .zip(names)
+        .map(|(field, name)| match &field.encoding {
+            Encoding::Fixed(primitive) => {
+                let write = format_ident!("write_{}", primitive);
+                quote!(msg.#write(*#name);)
+            }
+            Encoding::Varint(primitive) => {
+                let write = format_ident!("write_varint_{}", primitive);
+                quote!(msg.#write(*#name);)
+            }
+            Encoding::VarintBuffer => quote!(msg.write_buffer_varint(#name);),
+            Encoding::Buffer => quote!(msg.write_buffer(#name);),
+            Encoding::Nested => quote!(::simpletcp::simpletcp::MessageEncode::encode(#name, msg);),
+        });
+    quote!(#(#writes)*)
+}
+
+fn decode_fields(fields: &Fields, info: &[FieldInfo]) -> TokenStream {
+    let reads = info.iter().map(|field| {
+        let ty = &field.ty;
+        match &field.encoding {
+            Encoding::Fixed(primitive) => {
+                let read = format_ident!("read_{}", primitive);
+                quote!(msg.#read()?)
+            }
+            Encoding::Varint(primitive) => {
+                let read = format_ident!("read_varint_{}", primitive);
+                quote!(msg.#read()?)
+            }
+            Encoding::VarintBuffer => quote!(msg.read_buffer_varint()?.to_vec()),
+            Encoding::Buffer => quote!(msg.read_buffer()?.to_vec()),
+            Encoding::Nested => {
+                quote!(<#ty as ::simpletcp::simpletcp::MessageDecode>::decode(msg)?)
+            }
+        }
+    });
+    match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote!({ #(#idents: #reads),* })
+        }
+        Fields::Unnamed(_) => quote!(( #(#reads),* )),
+        Fields::Unit => quote!(),
+    }
+}
+
+fn add_bounds(generics: &Generics, bound: TokenStream) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(syn::parse2(bound.clone()).unwrap());
+    }
+    generics
+}
+
+fn expand_encode(input: DeriveInput) -> syn::Result<TokenStream> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let info = fields_info(&data.fields)?;
+            let (pattern, names) = bind_fields(&data.fields);
+            let writes = encode_fields(&info, &names);
+            quote! {
+                let Self #pattern = self;
+                #writes
+            }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            let infos = variants_info(data)?;
+            for (index, (variant, info)) in data.variants.iter().zip(infos).enumerate() {
+                let (pattern, names) = bind_fields(&variant.fields);
+                let writes = encode_fields(&info, &names);
+                let ident = &variant.ident;
+                let index = index as u32;
+                arms.push(quote! {
+                    Self::#ident #pattern => {
+                        msg.write_u32(#index);
+                        #writes
+                    }
+                });
+            }
+            if arms.is_empty() {
+                quote!(match *self {})
+            } else {
+                quote! {
+                    match self {
+                        #(#arms)*
+                    }
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "unions are not supported",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let generics = add_bounds(
+        &input.generics,
+        quote!(::simpletcp::simpletcp::MessageEncode),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::simpletcp::simpletcp::MessageEncode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+                #body
+            }
+        }
+    })
+}
+
+fn expand_decode(input: DeriveInput) -> syn::Result<TokenStream> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let info = fields_info(&data.fields)?;
+            let construct = decode_fields(&data.fields, &info);
+            quote!(::std::result::Result::Ok(Self #construct))
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            let infos = variants_info(data)?;
+            for (index, (variant, info)) in data.variants.iter().zip(infos).enumerate() {
+                let construct = decode_fields(&variant.fields, &info);
+                let ident = &variant.ident;
+                let index = index as u32;
+                arms.push(quote!(#index => ::std::result::Result::Ok(Self::#ident #construct),));
+            }
+            quote! {
+                match msg.read_u32()? {
+                    #(#arms)*
+                    _ => ::std::result::Result::Err(::simpletcp::simpletcp::MessageError::InvalidValue),
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "unions are not supported",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let generics = add_bounds(
+        &input.generics,
+        quote!(::simpletcp::simpletcp::MessageDecode),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::simpletcp::simpletcp::MessageDecode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn decode(
+                msg: &mut ::simpletcp::simpletcp::Message,
+            ) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,27 @@
+use simpletcp::simpletcp::{Error, Handler, Message, TcpServer, TcpStream};
+
+struct Adder;
+
+impl Handler for Adder {
+    fn on_ready(&mut self, id: u64, _client: &mut TcpStream) {
+        println!("Client {} became ready!", id);
+    }
+
+    fn on_message(&mut self, _id: u64, client: &mut TcpStream, mut msg: Message) {
+        let a = msg.read_i32().unwrap();
+        let b = msg.read_i32().unwrap();
+
+        let mut response = Message::new();
+        response.write_i32(a + b);
+        client.write(&response).unwrap();
+    }
+
+    fn on_disconnect(&mut self, id: u64, err: Error) {
+        println!("Removed client {}: {:?}", id, err);
+    }
+}
+
+fn main() {
+    let server = TcpServer::new("0.0.0.0:4328").unwrap();
+    server.run(&mut Adder);
+}
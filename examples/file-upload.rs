@@ -1,6 +1,6 @@
-use simpletcp::simpletcp::{Message, TcpServer, TcpStream};
+use simpletcp::simpletcp::{TcpServer, TcpStream};
 use std::fs::File;
-use std::io::{stdin, Read};
+use std::io::{sink, stdin, copy};
 use std::thread::{sleep, spawn};
 use std::time::Duration;
 
@@ -20,12 +20,14 @@ fn server_thread(server: TcpServer) {
 
     println!("[Server] Accepted new client");
     client.wait_until_ready().unwrap();
-    let mut i = 1;
     loop {
-        match client.read_blocking() {
-            Ok(mut msg) => {
-                println!("Received chunk {} size {}", i, msg.read_i32().unwrap());
-                i += 1;
+        match client.read_stream_blocking() {
+            Ok(mut stream) => {
+                // Data is received in chunks, integrity is verified at the end
+                match copy(&mut stream, &mut sink()) {
+                    Ok(size) => println!("[Server] Received file of size {}", size),
+                    Err(err) => println!("[Server] Upload failed: {}", err),
+                }
             }
             Err(err) => {
                 println!("{:?}", err);
@@ -46,19 +48,8 @@ fn client_thread() {
 
     let mut file = File::open(filename).unwrap();
 
-    let mut i = 0;
-    let mut buf = [0; 1024 * 1024];
-    loop {
-        println!("[Client] Uploading chunk {}", i);
-        let length = file.read(&mut buf).unwrap();
-        if length == 0 {
-            break;
-        }
-        let mut msg = Message::new();
-        msg.write_i32(length as i32);
-        msg.write_buffer(&buf[..length]);
-        client.write_blocking(&msg).unwrap();
-        i += 1;
-    }
-    println!("[Client] Upload complete.");
+    println!("[Client] Uploading");
+    let size = client.write_stream(&mut file).unwrap();
+    client.close().unwrap();
+    println!("[Client] Upload complete, {} bytes sent.", size);
 }
@@ -28,6 +28,7 @@ fn server_thread(server: TcpServer) {
         sleep(Duration::from_secs(1));
         i += 1;
     }
+    client.close().unwrap();
 }
 
 fn client_thread() {
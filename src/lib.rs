@@ -1,3 +1,5 @@
 pub mod simpletcp;
 
+pub mod pubsub;
+pub mod rpc;
 pub mod utils;
@@ -0,0 +1,443 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::{Duration, Instant};
+
+use crate::simpletcp::{Acceptor, Error, Message, MessageError, TcpServer, TcpStream};
+use crate::utils::{get_fd, poll_set_ev_timeout, poll_timeout, EV_POLLIN, EV_POLLOUT};
+
+#[cfg(test)]
+mod tests;
+
+const OP_SUBSCRIBE: u8 = 0;
+const OP_UNSUBSCRIBE: u8 = 1;
+const OP_PUBLISH: u8 = 2;
+
+/// Default number of messages queued for one subscriber
+pub const DEFAULT_QUEUE_LIMIT: usize = 256;
+
+/// Default time a client of the [Broker](struct.Broker.html) has to finish the handshake (10 seconds)
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Error returned by [PubSubClient](struct.PubSubClient.html)
+pub enum PubSubError {
+    /// An error occurred on the underlying [TcpStream](../simpletcp/struct.TcpStream.html)
+    StreamError(Error),
+
+    /// Received message could not be decoded
+    MessageError(MessageError),
+}
+
+impl fmt::Debug for PubSubError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PubSubError::StreamError(err) => {
+                f.write_fmt(format_args!("PubSubError::StreamError: {:?}", err))
+            }
+            PubSubError::MessageError(err) => {
+                f.write_fmt(format_args!("PubSubError::MessageError: {:?}", err))
+            }
+        }
+    }
+}
+
+impl From<Error> for PubSubError {
+    fn from(err: Error) -> Self {
+        PubSubError::StreamError(err)
+    }
+}
+
+impl From<MessageError> for PubSubError {
+    fn from(err: MessageError) -> Self {
+        PubSubError::MessageError(err)
+    }
+}
+
+/// What the [Broker](struct.Broker.html) does when queue of a subscriber is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Drop the message being published
+    DropNewest,
+
+    /// Drop the oldest queued message
+    DropOldest,
+
+    /// Disconnect the subscriber
+    Disconnect,
+}
+
+struct Subscriber {
+    stream: TcpStream,
+    ready: bool,
+    accepted: Instant,
+    patterns: Vec<String>,
+    queue: VecDeque<Message>,
+    dead: bool,
+}
+
+/// Publish/subscribe broker
+///
+/// Accepts clients through [TcpServer](../simpletcp/struct.TcpServer.html) and routes published messages to all clients subscribed to a matching topic pattern.
+///
+/// Topics consist of segments separated by `.`. In patterns `*` matches exactly one segment and `#` at the end matches any number of remaining segments,
+/// e.g. `sensors.*.temperature` or `sensors.#`.
+pub struct Broker {
+    server: TcpServer,
+    acceptor: Acceptor,
+    subscribers: Vec<Subscriber>,
+    handshake_timeout: Duration,
+    queue_limit: usize,
+    policy: SlowConsumerPolicy,
+    dropped: u64,
+}
+
+impl Broker {
+    /// Creates new Broker
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - Server to accept clients from
+    pub fn new(server: TcpServer) -> Self {
+        Broker {
+            server,
+            acceptor: Acceptor::new(),
+            subscribers: Vec::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            queue_limit: DEFAULT_QUEUE_LIMIT,
+            policy: SlowConsumerPolicy::DropOldest,
+            dropped: 0,
+        }
+    }
+
+    /// Sets maximum number of messages queued for one subscriber
+    ///
+    /// Default is [DEFAULT_QUEUE_LIMIT](constant.DEFAULT_QUEUE_LIMIT.html)
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        self.queue_limit = limit;
+    }
+
+    /// Gets maximum number of messages queued for one subscriber
+    pub fn queue_limit(&self) -> usize {
+        self.queue_limit
+    }
+
+    /// Sets what happens when queue of a subscriber is full
+    ///
+    /// Default is [DropOldest](enum.SlowConsumerPolicy.html#variant.DropOldest)
+    pub fn set_slow_consumer_policy(&mut self, policy: SlowConsumerPolicy) {
+        self.policy = policy;
+    }
+
+    /// Gets what happens when queue of a subscriber is full
+    pub fn slow_consumer_policy(&self) -> SlowConsumerPolicy {
+        self.policy
+    }
+
+    /// Sets time a client has to finish the handshake
+    ///
+    /// Clients that are not ready in time are disconnected.
+    /// Default is [DEFAULT_HANDSHAKE_TIMEOUT](constant.DEFAULT_HANDSHAKE_TIMEOUT.html)
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Gets time a client has to finish the handshake
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Returns number of connected clients
+    pub fn clients(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Returns total number of messages dropped because of full queues
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Publishes a message from the broker itself
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Topic of the message
+    /// * `msg` - Message to be published
+    pub fn publish(&mut self, topic: &str, msg: &Message) {
+        self.route(topic, msg);
+    }
+
+    /// Handles clients non-blocking
+    ///
+    /// Accepts new clients, processes their requests and sends queued messages.
+    /// Clients that fail, send invalid requests or do not finish the handshake in time are disconnected.
+    /// After an accept error that was not caused by a single connection, accepting is paused for a while.
+    /// # Returns
+    /// The last error of accepting clients, clients are handled regardless
+    pub fn poll(&mut self) -> Result<(), Error> {
+        let subscribers = &mut self.subscribers;
+        let mut accepted = Ok(());
+        self.acceptor.accept(&self.server, |result| match result {
+            Ok(stream) => subscribers.push(Subscriber {
+                stream,
+                ready: false,
+                accepted: Instant::now(),
+                patterns: Vec::new(),
+                queue: VecDeque::new(),
+                dead: false,
+            }),
+            Err(err) => accepted = Err(err),
+        });
+
+        let mut published = Vec::new();
+        for subscriber in &mut self.subscribers {
+            if subscriber
+                .handle_requests(&mut published, self.handshake_timeout)
+                .is_err()
+            {
+                subscriber.dead = true;
+            }
+        }
+        for (topic, msg) in published {
+            self.route(&topic, &msg);
+        }
+
+        for subscriber in &mut self.subscribers {
+            if subscriber.ready && !subscriber.dead && subscriber.send_queued().is_err() {
+                subscriber.dead = true;
+            }
+        }
+        self.subscribers.retain(|subscriber| !subscriber.dead);
+        accepted
+    }
+
+    /// Runs the broker
+    ///
+    /// Blocks the thread and handles clients forever, accept errors are ignored
+    pub fn run(&mut self) -> ! {
+        loop {
+            let _ = self.poll();
+
+            let mut fds = Vec::new();
+            let mut events = Vec::new();
+            let mut timeout = 1000;
+            let paused = self.acceptor.timeout();
+            if paused < 0 {
+                fds.push(get_fd(&self.server));
+                events.push(EV_POLLIN);
+            } else {
+                timeout = timeout.min(paused);
+            }
+            fds.extend(self.subscribers.iter().map(|s| get_fd(&s.stream)));
+            for subscriber in &self.subscribers {
+                if subscriber.queue.is_empty() {
+                    events.push(EV_POLLIN);
+                } else {
+                    events.push(EV_POLLIN | EV_POLLOUT);
+                }
+            }
+            poll_set_ev_timeout(&mut fds, &mut events, timeout);
+        }
+    }
+
+    fn route(&mut self, topic: &str, payload: &Message) {
+        let mut msg = Message::new();
+        msg.write_buffer(topic.as_bytes());
+        msg.write_nested(payload);
+
+        for subscriber in &mut self.subscribers {
+            if !subscriber.ready
+                || subscriber.dead
+                || !subscriber.patterns.iter().any(|p| topic_matches(p, topic))
+            {
+                continue;
+            }
+            if subscriber.queue.len() >= self.queue_limit {
+                match self.policy {
+                    SlowConsumerPolicy::DropNewest => {
+                        self.dropped += 1;
+                        continue;
+                    }
+                    SlowConsumerPolicy::DropOldest => {
+                        self.dropped += 1;
+                        subscriber.queue.pop_front();
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        self.dropped += subscriber.queue.len() as u64 + 1;
+                        subscriber.dead = true;
+                        continue;
+                    }
+                }
+            }
+            subscriber.queue.push_back(msg.clone());
+        }
+    }
+}
+
+impl Subscriber {
+    fn handle_requests(
+        &mut self,
+        published: &mut Vec<(String, Message)>,
+        handshake_timeout: Duration,
+    ) -> Result<(), PubSubError> {
+        if !self.ready {
+            self.ready = self.stream.get_ready()?;
+            if !self.ready {
+                if self.accepted.elapsed() >= handshake_timeout {
+                    return Err(PubSubError::StreamError(Error::TcpError(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "handshake timed out",
+                    ))));
+                }
+                return Ok(());
+            }
+        }
+
+        while let Some(mut msg) = self.stream.read()? {
+            let op = msg.read_u8()?;
+            let topic = String::from_utf8_lossy(msg.read_buffer()?).into_owned();
+            match op {
+                OP_SUBSCRIBE => {
+                    if !self.patterns.contains(&topic) {
+                        self.patterns.push(topic);
+                    }
+                }
+                OP_UNSUBSCRIBE => self.patterns.retain(|p| p != &topic),
+                OP_PUBLISH => published.push((topic, msg.read_nested()?)),
+                _ => return Err(PubSubError::StreamError(Error::InvalidFrame)),
+            }
+        }
+        Ok(())
+    }
+
+    // Writes queued messages while the stream has no pending data, so the bounded queue holds the backlog
+    fn send_queued(&mut self) -> Result<(), Error> {
+        while self.stream.flush()? {
+            match self.queue.pop_front() {
+                None => break,
+                Some(msg) => self.stream.write(&msg)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Client of the [Broker](struct.Broker.html)
+pub struct PubSubClient {
+    stream: TcpStream,
+}
+
+impl PubSubClient {
+    /// Connects to the broker
+    ///
+    /// Blocks until the connection is ready
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.wait_until_ready()?;
+        Ok(PubSubClient::new(stream))
+    }
+
+    /// Creates new PubSubClient
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - [TcpStream](../simpletcp/struct.TcpStream.html) connected to the broker
+    pub fn new(stream: TcpStream) -> Self {
+        PubSubClient { stream }
+    }
+
+    /// Returns the underlying stream
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// Consumes the client and returns the underlying stream
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    /// Subscribes to topics matching the pattern
+    ///
+    /// See [Broker](struct.Broker.html) for pattern syntax
+    pub fn subscribe(&mut self, pattern: &str) -> Result<(), Error> {
+        self.request(OP_SUBSCRIBE, pattern, None)
+    }
+
+    /// Cancels subscription made by [subscribe](struct.PubSubClient.html#method.subscribe)
+    pub fn unsubscribe(&mut self, pattern: &str) -> Result<(), Error> {
+        self.request(OP_UNSUBSCRIBE, pattern, None)
+    }
+
+    /// Publishes a message
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Topic of the message
+    /// * `msg` - Message to be published
+    pub fn publish(&mut self, topic: &str, msg: &Message) -> Result<(), Error> {
+        self.request(OP_PUBLISH, topic, Some(msg))
+    }
+
+    /// Receives a published message non-blocking
+    ///
+    /// # Returns
+    /// `Some((topic, Message))` or `None` if no message has arrived
+    pub fn receive(&mut self) -> Result<Option<(String, Message)>, PubSubError> {
+        self.stream.flush()?;
+        match self.stream.read()? {
+            None => Ok(None),
+            Some(mut msg) => {
+                let topic = String::from_utf8_lossy(msg.read_buffer()?).into_owned();
+                let msg = msg.read_nested()?;
+                Ok(Some((topic, msg)))
+            }
+        }
+    }
+
+    /// Receives a published message blocking
+    ///
+    /// # Returns
+    /// Topic and [Message](../simpletcp/struct.Message.html)
+    pub fn receive_blocking(&mut self) -> Result<(String, Message), PubSubError> {
+        loop {
+            if let Some(res) = self.receive()? {
+                return Ok(res);
+            }
+            poll_timeout(&self.stream, EV_POLLIN, 1000);
+        }
+    }
+
+    fn request(&mut self, op: u8, topic: &str, msg: Option<&Message>) -> Result<(), Error> {
+        let mut request = Message::new();
+        request.write_u8(op);
+        request.write_buffer(topic.as_bytes());
+        if let Some(msg) = msg {
+            request.write_nested(msg);
+        }
+        self.stream.write(&request)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Checks whether topic matches the pattern
+///
+/// See [Broker](struct.Broker.html) for pattern syntax
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('.');
+    for segment in pattern.split('.') {
+        if segment == "#" {
+            return true;
+        }
+        match topic.next() {
+            None => return false,
+            Some(t) => {
+                if segment != "*" && segment != t {
+                    return false;
+                }
+            }
+        }
+    }
+    topic.next().is_none()
+}
@@ -0,0 +1,101 @@
+use crate::pubsub::{topic_matches, Broker, PubSubClient, SlowConsumerPolicy};
+use crate::simpletcp::{Message, TcpServer};
+use std::net;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+#[test]
+fn patterns() {
+    assert!(topic_matches("news", "news"));
+    assert!(!topic_matches("news", "news.sport"));
+    assert!(topic_matches("news.*", "news.sport"));
+    assert!(!topic_matches("news.*", "news"));
+    assert!(!topic_matches("news.*", "news.sport.football"));
+    assert!(topic_matches("news.*.football", "news.sport.football"));
+    assert!(topic_matches("news.#", "news"));
+    assert!(topic_matches("news.#", "news.sport.football"));
+    assert!(topic_matches("#", "weather"));
+    assert!(!topic_matches("news.#", "weather.news"));
+}
+
+#[test]
+fn publish_subscribe() {
+    let server = TcpServer::new("127.0.0.1:1555").expect("Failed to create server");
+    spawn(move || Broker::new(server).run());
+
+    let mut subscriber = PubSubClient::connect("127.0.0.1:1555").unwrap();
+    subscriber.subscribe("news.*").unwrap();
+    subscriber.subscribe("weather.#").unwrap();
+    // Requests of one client are handled in order, so this confirms the subscriptions
+    subscriber.publish("weather", &Message::new()).unwrap();
+    assert_eq!(subscriber.receive_blocking().unwrap().0, "weather");
+
+    let mut publisher = PubSubClient::connect("127.0.0.1:1555").unwrap();
+    for (i, topic) in ["news.sport", "news.local.city", "weather.eu.cz", "other"]
+        .iter()
+        .enumerate()
+    {
+        let mut msg = Message::new_tagged();
+        msg.write_u32(i as u32);
+        publisher.publish(topic, &msg).unwrap();
+    }
+
+    let (topic, mut msg) = subscriber.receive_blocking().unwrap();
+    assert_eq!(topic, "news.sport");
+    assert!(msg.is_tagged());
+    assert_eq!(msg.read_u32().unwrap(), 0);
+    let (topic, mut msg) = subscriber.receive_blocking().unwrap();
+    assert_eq!(topic, "weather.eu.cz");
+    assert_eq!(msg.read_u32().unwrap(), 2);
+
+    subscriber.unsubscribe("news.*").unwrap();
+    subscriber.publish("news.sport", &Message::new()).unwrap();
+    subscriber.publish("weather.us", &Message::new()).unwrap();
+    assert_eq!(subscriber.receive_blocking().unwrap().0, "weather.us");
+}
+
+#[test]
+fn slow_consumer_disconnect() {
+    let server = TcpServer::new("127.0.0.1:1556").expect("Failed to create server");
+    spawn(move || {
+        let mut broker = Broker::new(server);
+        broker.set_queue_limit(2);
+        broker.set_slow_consumer_policy(SlowConsumerPolicy::Disconnect);
+        broker.run()
+    });
+
+    let mut subscriber = PubSubClient::connect("127.0.0.1:1556").unwrap();
+    subscriber.subscribe("bulk").unwrap();
+    subscriber.publish("bulk", &Message::new()).unwrap();
+    subscriber.receive_blocking().unwrap();
+
+    let mut publisher = PubSubClient::connect("127.0.0.1:1556").unwrap();
+    let mut msg = Message::new();
+    msg.write_buffer(&[0; 1024 * 1024]);
+    for _ in 0..32 {
+        publisher.publish("bulk", &msg).unwrap();
+        while !publisher.stream().flush().unwrap() {}
+    }
+
+    let mut received = 0;
+    while subscriber.receive_blocking().is_ok() {
+        received += 1;
+    }
+    assert!(received < 32);
+}
+
+#[test]
+fn handshake_timeout() {
+    let server = TcpServer::new("127.0.0.1:1581").expect("Failed to create server");
+    let mut broker = Broker::new(server);
+    broker.set_handshake_timeout(Duration::from_millis(200));
+
+    // Client that never sends its key
+    let _client = net::TcpStream::connect("127.0.0.1:1581").unwrap();
+    while broker.clients() == 0 {
+        broker.poll().unwrap();
+    }
+    sleep(Duration::from_millis(300));
+    broker.poll().unwrap();
+    assert_eq!(broker.clients(), 0);
+}
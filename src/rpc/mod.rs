@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::time::{Duration, Instant};
+
+use crate::simpletcp::{Error, Message, MessageError, TcpStream};
+use crate::utils::{poll_timeout, EV_POLLIN};
+
+#[cfg(test)]
+mod tests;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_RESPONSE: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_METHOD: u8 = 1;
+
+/// Error returned by [RpcClient](struct.RpcClient.html) and [RpcServer](struct.RpcServer.html)
+pub enum RpcError {
+    /// An error occurred on the underlying [TcpStream](../simpletcp/struct.TcpStream.html)
+    StreamError(Error),
+
+    /// Received message could not be decoded
+    MessageError(MessageError),
+
+    /// Response has not arrived in time
+    Timeout,
+
+    /// Server has no handler registered for the method
+    UnknownMethod,
+
+    /// Handle does not belong to a pending call
+    UnknownCall,
+
+    /// Received message is neither a request nor a response of this protocol
+    InvalidMessage,
+}
+
+impl fmt::Debug for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::StreamError(err) => {
+                f.write_fmt(format_args!("RpcError::StreamError: {:?}", err))
+            }
+            RpcError::MessageError(err) => {
+                f.write_fmt(format_args!("RpcError::MessageError: {:?}", err))
+            }
+            RpcError::Timeout => f.write_str("RpcError::Timeout"),
+            RpcError::UnknownMethod => f.write_str("RpcError::UnknownMethod"),
+            RpcError::UnknownCall => f.write_str("RpcError::UnknownCall"),
+            RpcError::InvalidMessage => f.write_str("RpcError::InvalidMessage"),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        RpcError::StreamError(err)
+    }
+}
+
+impl From<MessageError> for RpcError {
+    fn from(err: MessageError) -> Self {
+        RpcError::MessageError(err)
+    }
+}
+
+/// Handle of a call made by [RpcClient](struct.RpcClient.html)
+pub struct CallHandle {
+    id: u64,
+    deadline: Instant,
+}
+
+impl CallHandle {
+    /// Returns ID of the call
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// RPC client
+///
+/// Sends requests over [TcpStream](../simpletcp/struct.TcpStream.html), every request gets an ID that is used to match the response,
+/// so responses can arrive in any order.
+pub struct RpcClient {
+    stream: TcpStream,
+    next_id: u64,
+    // Deadlines of calls waiting for a response
+    pending: HashMap<u64, Instant>,
+    // Received results with deadlines of their calls
+    responses: HashMap<u64, (Instant, Result<Message, RpcError>)>,
+}
+
+impl RpcClient {
+    /// Creates new RpcClient
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connected [TcpStream](../simpletcp/struct.TcpStream.html)
+    pub fn new(stream: TcpStream) -> Self {
+        RpcClient {
+            stream,
+            next_id: 0,
+            pending: HashMap::new(),
+            responses: HashMap::new(),
+        }
+    }
+
+    /// Returns the underlying stream
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// Consumes the client and returns the underlying stream
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    /// Sends a request
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - Name of the method
+    /// * `args` - Arguments of the method
+    /// * `timeout` - Time after which the call fails with [Timeout](enum.RpcError.html#variant.Timeout),
+    ///   result that has not been collected by then is dropped
+    /// # Returns
+    /// [CallHandle](struct.CallHandle.html) used to get the result
+    pub fn call(
+        &mut self,
+        method: &str,
+        args: &Message,
+        timeout: Duration,
+    ) -> Result<CallHandle, RpcError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut request = Message::new();
+        request.write_u8(KIND_REQUEST);
+        request.write_u64(id);
+        request.write_buffer(method.as_bytes());
+        request.write_nested(args);
+        self.stream.write(&request)?;
+        self.stream.flush()?;
+
+        let deadline = Instant::now() + timeout;
+        self.pending.insert(id, deadline);
+        Ok(CallHandle { id, deadline })
+    }
+
+    /// Sends a request and blocks until the response arrives
+    ///
+    /// See [call](struct.RpcClient.html#method.call)
+    pub fn call_blocking(
+        &mut self,
+        method: &str,
+        args: &Message,
+        timeout: Duration,
+    ) -> Result<Message, RpcError> {
+        let handle = self.call(method, args, timeout)?;
+        self.wait(handle)
+    }
+
+    /// Reads all available responses non-blocking
+    ///
+    /// Forgets calls whose timeout has elapsed, together with their uncollected results
+    pub fn poll(&mut self) -> Result<(), RpcError> {
+        while let Some(mut msg) = self.stream.read()? {
+            if msg.read_u8()? != KIND_RESPONSE {
+                return Err(RpcError::InvalidMessage);
+            }
+            let id = msg.read_u64()?;
+            let status = msg.read_u8()?;
+            let result = match status {
+                STATUS_OK => Ok(msg.read_nested()?),
+                _ => Err(RpcError::UnknownMethod),
+            };
+            // Responses to calls that have timed out are dropped
+            if let Some(deadline) = self.pending.remove(&id) {
+                self.responses.insert(id, (deadline, result));
+            }
+        }
+        let now = Instant::now();
+        self.pending.retain(|_, deadline| *deadline > now);
+        self.responses.retain(|_, (deadline, _)| *deadline > now);
+        Ok(())
+    }
+
+    /// Gets result of the call non-blocking
+    ///
+    /// # Returns
+    /// `Some(Message)` or `None` if the response has not arrived yet
+    pub fn try_result(&mut self, handle: &CallHandle) -> Result<Option<Message>, RpcError> {
+        self.poll()?;
+        if let Some((_, result)) = self.responses.remove(&handle.id) {
+            return result.map(Some);
+        }
+        if self.pending.contains_key(&handle.id) {
+            return Ok(None);
+        }
+        if Instant::now() >= handle.deadline {
+            return Err(RpcError::Timeout);
+        }
+        Err(RpcError::UnknownCall)
+    }
+
+    /// Blocks until result of the call is available
+    ///
+    /// # Returns
+    /// Response [Message](../simpletcp/struct.Message.html)
+    pub fn wait(&mut self, handle: CallHandle) -> Result<Message, RpcError> {
+        loop {
+            if let Some(msg) = self.try_result(&handle)? {
+                return Ok(msg);
+            }
+            let remaining = handle.deadline.saturating_duration_since(Instant::now());
+            poll_timeout(&self.stream, EV_POLLIN, remaining.as_millis() as i32 + 1);
+        }
+    }
+}
+
+/// RPC server
+///
+/// Dispatches requests to handlers registered by method name
+pub struct RpcServer {
+    handlers: HashMap<String, Box<dyn FnMut(Message) -> Message + Send>>,
+}
+
+impl Default for RpcServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RpcServer {
+    /// Creates new RpcServer without handlers
+    pub fn new() -> Self {
+        RpcServer {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Registers a handler
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - Name of the method
+    /// * `handler` - Function that takes arguments and returns the response
+    pub fn register<F>(&mut self, method: &str, handler: F)
+    where
+        F: FnMut(Message) -> Message + Send + 'static,
+    {
+        self.handlers.insert(method.to_string(), Box::new(handler));
+    }
+
+    /// Handles all available requests non-blocking
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Stream to read requests from and write responses to
+    /// # Returns
+    /// Number of handled requests
+    pub fn handle(&mut self, stream: &mut TcpStream) -> Result<usize, RpcError> {
+        let mut handled = 0;
+        while let Some(request) = stream.read()? {
+            let response = self.handle_message(request)?;
+            stream.write(&response)?;
+            handled += 1;
+        }
+        stream.flush()?;
+        Ok(handled)
+    }
+
+    /// Handles a request message
+    ///
+    /// Useful when messages are read by other means, e.g. an event loop
+    /// # Returns
+    /// Response message to be sent back
+    pub fn handle_message(&mut self, mut request: Message) -> Result<Message, RpcError> {
+        if request.read_u8()? != KIND_REQUEST {
+            return Err(RpcError::InvalidMessage);
+        }
+        let id = request.read_u64()?;
+        let method = String::from_utf8_lossy(request.read_buffer()?).into_owned();
+        let args = request.read_nested()?;
+
+        let mut response = Message::new();
+        response.write_u8(KIND_RESPONSE);
+        response.write_u64(id);
+        match self.handlers.get_mut(&method) {
+            Some(handler) => {
+                let result = handler(args);
+                response.write_u8(STATUS_OK);
+                response.write_nested(&result);
+            }
+            None => {
+                response.write_u8(STATUS_UNKNOWN_METHOD);
+            }
+        }
+        Ok(response)
+    }
+}
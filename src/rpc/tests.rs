@@ -0,0 +1,147 @@
+use crate::rpc::{RpcClient, RpcError, RpcServer};
+use crate::simpletcp::{Message, TcpServer, TcpStream};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+fn rpc_server() -> RpcServer {
+    let mut rpc = RpcServer::new();
+    rpc.register("add", |mut args| {
+        let a = args.read_i32().unwrap();
+        let b = args.read_i32().unwrap();
+        let mut result = Message::new();
+        result.write_i32(a + b);
+        result
+    });
+    rpc.register("negate", |mut args| {
+        let mut result = Message::new();
+        result.write_i32(-args.read_i32().unwrap());
+        result
+    });
+    rpc.register("echo", |args| args);
+    rpc
+}
+
+#[test]
+fn rpc_out_of_order() {
+    let server = TcpServer::new("127.0.0.1:1553").expect("Failed to create server");
+    spawn(move || {
+        let mut s_client = server.accept_blocking().unwrap();
+        s_client.wait_until_ready().unwrap();
+        let mut rpc = rpc_server();
+
+        // Respond to the two requests in reverse order
+        let first = rpc
+            .handle_message(s_client.read_blocking().unwrap())
+            .unwrap();
+        let second = rpc
+            .handle_message(s_client.read_blocking().unwrap())
+            .unwrap();
+        s_client.write(&second).unwrap();
+        s_client.write(&first).unwrap();
+        while !s_client.flush().unwrap() {}
+
+        loop {
+            if rpc.handle(&mut s_client).is_err() {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+    });
+
+    let mut client = TcpStream::connect("127.0.0.1:1553").expect("Failed to connect to server");
+    client.wait_until_ready().unwrap();
+    let mut client = RpcClient::new(client);
+
+    let mut args = Message::new();
+    args.write_i32(2);
+    args.write_i32(3);
+    let add = client.call("add", &args, Duration::from_secs(5)).unwrap();
+    let mut args = Message::new();
+    args.write_i32(7);
+    let negate = client
+        .call("negate", &args, Duration::from_secs(5))
+        .unwrap();
+    assert_ne!(add.id(), negate.id());
+
+    assert_eq!(client.wait(add).unwrap().read_i32().unwrap(), 5);
+    assert_eq!(client.wait(negate).unwrap().read_i32().unwrap(), -7);
+
+    match client.call_blocking("unknown", &Message::new(), Duration::from_secs(5)) {
+        Err(RpcError::UnknownMethod) => {}
+        _ => panic!("Expected UnknownMethod"),
+    }
+
+    let mut args = Message::new_tagged();
+    args.write_str("tagged");
+    let mut result = client
+        .call_blocking("echo", &args, Duration::from_secs(5))
+        .unwrap();
+    assert!(result.is_tagged());
+    assert_eq!(result.read_str().unwrap(), "tagged");
+}
+
+#[test]
+fn rpc_timeout() {
+    let server = TcpServer::new("127.0.0.1:1554").expect("Failed to create server");
+    spawn(move || {
+        let mut s_client = server.accept_blocking().unwrap();
+        s_client.wait_until_ready().unwrap();
+        let mut rpc = rpc_server();
+        rpc.register("slow", |args| {
+            sleep(Duration::from_millis(300));
+            args
+        });
+
+        loop {
+            if rpc.handle(&mut s_client).is_err() {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+    });
+
+    let mut client = TcpStream::connect("127.0.0.1:1554").expect("Failed to connect to server");
+    client.wait_until_ready().unwrap();
+    let mut client = RpcClient::new(client);
+
+    let slow = client
+        .call("slow", &Message::new(), Duration::from_millis(100))
+        .unwrap();
+    match client.try_result(&slow) {
+        Ok(None) => {}
+        _ => panic!("Expected no result yet"),
+    }
+    match client.wait(slow) {
+        Err(RpcError::Timeout) => {}
+        _ => panic!("Expected Timeout"),
+    }
+
+    // Late response of the timed out call must not be mistaken for this one
+    let mut args = Message::new();
+    args.write_i32(1);
+    let mut result = client
+        .call_blocking("negate", &args, Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(result.read_i32().unwrap(), -1);
+
+    // Calls that are never waited for are forgotten after their timeout
+    let mut args = Message::new();
+    args.write_i32(2);
+    let negate = client
+        .call("negate", &args, Duration::from_millis(200))
+        .unwrap();
+    client
+        .call("slow", &Message::new(), Duration::from_millis(100))
+        .unwrap();
+    sleep(Duration::from_millis(100));
+    client.poll().unwrap();
+    assert_eq!(client.responses.len(), 1);
+    sleep(Duration::from_millis(400));
+    client.poll().unwrap();
+    assert!(client.pending.is_empty());
+    assert!(client.responses.is_empty());
+    match client.try_result(&negate) {
+        Err(RpcError::Timeout) => {}
+        _ => panic!("Expected Timeout"),
+    }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+#[cfg(feature = "bytes")]
+use bytes::buf::UninitSlice;
+#[cfg(feature = "bytes")]
+use bytes::{Buf, BufMut};
+
+use super::Message;
+
+// Raw bytes are written and read without type tags, so that messages can be used with other encoders
+
+/// Appends raw bytes to the message
+///
+/// No type tag is written even if the message is [tagged](struct.Message.html#method.new_tagged),
+/// so typed reads and [inspect](struct.Message.html#method.inspect) see the bytes as corrupted values
+impl Write for Message {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads raw bytes and moves read cursor
+///
+/// Type tags of a [tagged](struct.Message.html#method.new_tagged) message are read as ordinary bytes
+impl Read for Message {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        buf[..len].copy_from_slice(&self.buffer[self.read_pos..self.read_pos + len]);
+        self.read_pos += len;
+        Ok(len)
+    }
+}
+
+/// Reads raw bytes like [Read](struct.Message.html#impl-Read-for-Message)
+impl BufRead for Message {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(&self.buffer[self.read_pos..self.end()])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read_pos += amt.min(self.remaining());
+    }
+}
+
+/// Reads raw bytes from read cursor, requires `bytes` cargo feature
+///
+/// Type tags are not interpreted, see [Read](struct.Message.html#impl-Read-for-Message)
+#[cfg(feature = "bytes")]
+impl Buf for Message {
+    fn remaining(&self) -> usize {
+        self.end() - self.read_pos
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &self.buffer[self.read_pos..self.end()]
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        assert!(
+            cnt <= Buf::remaining(self),
+            "Cannot advance past the end of the message."
+        );
+        self.read_pos += cnt;
+    }
+}
+
+/// Appends raw bytes to the message, requires `bytes` cargo feature
+///
+/// Like [Write](struct.Message.html#impl-Write-for-Message), no type tags are written
+// Safety: all methods are delegated to the implementation for Vec<u8>
+#[cfg(feature = "bytes")]
+unsafe impl BufMut for Message {
+    fn remaining_mut(&self) -> usize {
+        self.buffer.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.buffer.advance_mut(cnt);
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        self.buffer.chunk_mut()
+    }
+
+    fn put_slice(&mut self, src: &[u8]) {
+        self.buffer.extend_from_slice(src);
+    }
+}
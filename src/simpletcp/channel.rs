@@ -0,0 +1,356 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+use super::{
+    Error, Message, TcpStream, FRAME_CHANNEL_CLOSE, FRAME_CHANNEL_DATA, FRAME_CHANNEL_DATA_TAGGED,
+    FRAME_CHANNEL_OPEN,
+};
+use crate::simpletcp::State::Ready;
+use crate::utils::{poll_timeout, EV_POLLIN, EV_POLLOUT};
+
+/// Default limit of channels opened by the peer
+pub const DEFAULT_CHANNEL_LIMIT: usize = 256;
+
+/// Default limit of received messages queued on one channel
+pub const DEFAULT_CHANNEL_QUEUE_LIMIT: usize = 1024;
+
+pub(super) struct Channel {
+    incoming: VecDeque<Message>,
+    outgoing: VecDeque<Vec<u8>>,
+    local_closed: bool,
+    peer_closed: bool,
+    // Number of messages dropped because the incoming queue was full
+    dropped: usize,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Channel {
+            incoming: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            local_closed: false,
+            peer_closed: false,
+            dropped: 0,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.local_closed
+            && self.peer_closed
+            && self.incoming.is_empty()
+            && self.outgoing.is_empty()
+    }
+}
+
+impl TcpStream {
+    /// Opens a new logical channel
+    ///
+    /// Channels are multiplexed over one connection, each has its own read queue.
+    /// Frames of all channels with pending data are sent in turns, so a bulk transfer on one channel does not block the others.
+    /// Messages sent using [write](struct.TcpStream.html#method.write) bypass channel scheduling.
+    /// # Returns
+    /// ID of the channel
+    pub fn open_channel(&mut self) -> Result<u32, Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+        if self.local_closed {
+            return Err(Error::ConnectionClosed);
+        }
+
+        // Client uses odd and server even IDs, so they never collide
+        let id = self.next_channel_id;
+        self.next_channel_id = self.next_channel_id.wrapping_add(2);
+        self.channels.insert(id, Channel::new());
+        self.write_frame(FRAME_CHANNEL_OPEN, &id.to_le_bytes())?;
+        Ok(id)
+    }
+
+    /// Accepts a channel opened by the peer non-blocking
+    ///
+    /// # Returns
+    /// `Some(u32)` with ID of the channel or `None` if no channel was opened
+    pub fn accept_channel(&mut self) -> Result<Option<u32>, Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+
+        loop {
+            if let Some(id) = self.accepted_channels.pop_front() {
+                return Ok(Some(id));
+            }
+            if self.peer_closed {
+                return Err(Error::ConnectionClosed);
+            }
+            if !self.receive_frame()? {
+                self.heartbeat_tick()?;
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Accepts a channel opened by the peer blocking
+    ///
+    /// # Returns
+    /// ID of the channel
+    pub fn accept_channel_blocking(&mut self) -> Result<u32, Error> {
+        loop {
+            match self.accept_channel()? {
+                None => self.wait_channel_event(),
+                Some(id) => return Ok(id),
+            }
+        }
+    }
+
+    /// Writes a message to the channel
+    ///
+    /// Message is queued and sent when the channel gets its turn, you should call [flush](struct.TcpStream.html#method.flush) afterwards
+    /// # Arguments
+    ///
+    /// * `id` - ID of the channel
+    /// * `msg` - Message to be sent
+    /// # Returns
+    /// [Backpressure](enum.Error.html#variant.Backpressure) if pending data exceed the [write limit](struct.TcpStream.html#method.set_write_limit)
+    pub fn write_channel(&mut self, id: u32, msg: &Message) -> Result<(), Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+        if self.local_closed {
+            return Err(Error::ConnectionClosed);
+        }
+        match self.channels.get(&id) {
+            Some(channel) if !channel.local_closed => {}
+            _ => return Err(Error::ChannelClosed),
+        }
+        self.check_write_limit()?;
+
+        let kind = if msg.tagged {
+            FRAME_CHANNEL_DATA_TAGGED
+        } else {
+            FRAME_CHANNEL_DATA
+        };
+        let raw = self.encrypt_frame(kind, &[msg.as_bytes(), &id.to_le_bytes()])?;
+        self.channels.get_mut(&id).unwrap().outgoing.push_back(raw);
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Reads a message from the channel non-blocking
+    ///
+    /// Messages for other channels received in the meantime are queued
+    /// # Arguments
+    ///
+    /// * `id` - ID of the channel
+    /// # Returns
+    /// `Some(Message)`, `None` if no message has arrived or [ChannelClosed](enum.Error.html#variant.ChannelClosed) if the peer has closed the channel.
+    /// Returns [ChannelOverflow](enum.Error.html#variant.ChannelOverflow) once if messages were dropped because of the [queue limit](struct.TcpStream.html#method.set_channel_queue_limit)
+    pub fn read_channel(&mut self, id: u32) -> Result<Option<Message>, Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+
+        loop {
+            let channel = match self.channels.get_mut(&id) {
+                None => return Err(Error::ChannelClosed),
+                Some(channel) => channel,
+            };
+            if channel.dropped > 0 {
+                let dropped = channel.dropped;
+                channel.dropped = 0;
+                return Err(Error::ChannelOverflow { dropped });
+            }
+            if let Some(msg) = channel.incoming.pop_front() {
+                self.remove_finished_channel(id);
+                return Ok(Some(msg));
+            }
+            if channel.peer_closed {
+                self.remove_finished_channel(id);
+                return Err(Error::ChannelClosed);
+            }
+            if self.peer_closed {
+                return Err(Error::ConnectionClosed);
+            }
+            if !self.receive_frame()? {
+                self.heartbeat_tick()?;
+                self.flush()?;
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads a message from the channel blocking
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the channel
+    /// # Returns
+    /// [Message](struct.Message.html)
+    pub fn read_channel_blocking(&mut self, id: u32) -> Result<Message, Error> {
+        loop {
+            match self.read_channel(id)? {
+                None => self.wait_channel_event(),
+                Some(msg) => return Ok(msg),
+            }
+        }
+    }
+
+    /// Closes the channel
+    ///
+    /// Messages already written to the channel are sent before the close notification.
+    /// Remaining messages from the peer can still be read.
+    /// The channel is released once both sides have closed it and all its messages were read.
+    /// # Arguments
+    ///
+    /// * `id` - ID of the channel
+    pub fn close_channel(&mut self, id: u32) -> Result<(), Error> {
+        match self.channels.get(&id) {
+            Some(channel) if !channel.local_closed => {}
+            _ => return Err(Error::ChannelClosed),
+        }
+
+        let raw = self.encrypt_frame(FRAME_CHANNEL_CLOSE, &[&id.to_le_bytes()])?;
+        let channel = self.channels.get_mut(&id).unwrap();
+        channel.outgoing.push_back(raw);
+        channel.local_closed = true;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Sets limit of channels opened by the peer
+    ///
+    /// Channels opened by the peer over the limit are closed immediately and never accepted.
+    /// A channel closed by the peer still counts against the limit until it is also closed using [close_channel](struct.TcpStream.html#method.close_channel).
+    /// Default is [DEFAULT_CHANNEL_LIMIT](constant.DEFAULT_CHANNEL_LIMIT.html)
+    pub fn set_channel_limit(&mut self, limit: usize) {
+        self.channel_limit = limit;
+    }
+
+    /// Gets limit of channels opened by the peer
+    pub fn channel_limit(&self) -> usize {
+        self.channel_limit
+    }
+
+    /// Sets limit of received messages queued on one channel
+    ///
+    /// Messages arriving at a full queue are dropped and [read_channel](struct.TcpStream.html#method.read_channel)
+    /// returns [ChannelOverflow](enum.Error.html#variant.ChannelOverflow).
+    /// Default is [DEFAULT_CHANNEL_QUEUE_LIMIT](constant.DEFAULT_CHANNEL_QUEUE_LIMIT.html)
+    pub fn set_channel_queue_limit(&mut self, limit: usize) {
+        self.channel_queue_limit = limit;
+    }
+
+    /// Gets limit of received messages queued on one channel
+    pub fn channel_queue_limit(&self) -> usize {
+        self.channel_queue_limit
+    }
+
+    pub(super) fn handle_channel_frame(
+        &mut self,
+        kind: u8,
+        mut payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        if payload.len() < 4 {
+            return Err(Error::InvalidFrame);
+        }
+        let id_start = payload.len() - 4;
+        let id = u32::from_le_bytes(payload[id_start..].try_into().unwrap());
+        payload.truncate(id_start);
+
+        match kind {
+            FRAME_CHANNEL_OPEN => {
+                // The peer opens IDs of the other parity than ours
+                if id % 2 == self.next_channel_id % 2 || self.channels.contains_key(&id) {
+                    return Err(Error::InvalidFrame);
+                }
+                if self.peer_channel_count() >= self.channel_limit {
+                    return self.write_frame(FRAME_CHANNEL_CLOSE, &id.to_le_bytes());
+                }
+                self.channels.insert(id, Channel::new());
+                self.accepted_channels.push_back(id);
+            }
+            FRAME_CHANNEL_DATA | FRAME_CHANNEL_DATA_TAGGED => {
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    if channel.incoming.len() >= self.channel_queue_limit {
+                        channel.dropped += 1;
+                        return Ok(());
+                    }
+                    let mut msg = Message::from_buffer(payload);
+                    msg.tagged = kind == FRAME_CHANNEL_DATA_TAGGED;
+                    channel.incoming.push_back(msg);
+                }
+            }
+            FRAME_CHANNEL_CLOSE => {
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    channel.peer_closed = true;
+                }
+                self.remove_finished_channel(id);
+            }
+            _ => return Err(Error::InvalidFrame),
+        }
+        Ok(())
+    }
+
+    // Writes next frame of the next channel with pending data, returns `false` if there is none
+    pub(super) fn schedule_channel_frame(&mut self) -> Result<bool, Error> {
+        let cursor = self.channel_cursor;
+        let next = self
+            .channels
+            .range(cursor..)
+            .chain(self.channels.range(..cursor))
+            .find(|(_, channel)| !channel.outgoing.is_empty())
+            .map(|(id, _)| *id);
+
+        let id = match next {
+            None => return Ok(false),
+            Some(id) => id,
+        };
+        self.channel_cursor = id.wrapping_add(1);
+        let raw = self
+            .channels
+            .get_mut(&id)
+            .unwrap()
+            .outgoing
+            .pop_front()
+            .unwrap();
+        self.remove_finished_channel(id);
+        let mut raw = raw;
+        self.seal_frame(&mut raw, 0)?;
+        self.write_encoded_vec(raw)?;
+        Ok(true)
+    }
+
+    pub(super) fn pending_channel_bytes(&self) -> usize {
+        self.channels
+            .values()
+            .flat_map(|channel| channel.outgoing.iter())
+            .map(|raw| raw.len())
+            .sum()
+    }
+
+    pub(super) fn has_pending_channel_frames(&self) -> bool {
+        self.channels
+            .values()
+            .any(|channel| !channel.outgoing.is_empty())
+    }
+
+    fn peer_channel_count(&self) -> usize {
+        let parity = self.next_channel_id % 2;
+        self.channels.keys().filter(|id| *id % 2 != parity).count()
+    }
+
+    fn remove_finished_channel(&mut self, id: u32) {
+        if let Some(channel) = self.channels.get(&id) {
+            if channel.is_finished() {
+                self.channels.remove(&id);
+            }
+        }
+    }
+
+    fn wait_channel_event(&mut self) {
+        let mut events = EV_POLLIN;
+        if !self.write_buffer.is_empty() || self.has_pending_channel_frames() {
+            events |= EV_POLLOUT;
+        }
+        poll_timeout(self, events, self.heartbeat_timeout());
+    }
+}
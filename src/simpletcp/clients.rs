@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+
+use super::event::Fd;
+use super::{Error, Message, TcpServer, TcpStream};
+use crate::utils::{get_fd, poll_set_ev_timeout, EV_POLLIN, EV_POLLOUT};
+
+struct Entry {
+    stream: TcpStream,
+    ready: bool,
+}
+
+/// Set of connected clients
+///
+/// Every client gets an ID that is never reused by the set.
+/// Clients that fail are removed and reported by [take_closed](struct.ClientSet.html#method.take_closed).
+/// Errors after which the connection can still be used, such as [Backpressure](enum.Error.html#variant.Backpressure), do not remove the client.
+pub struct ClientSet {
+    clients: BTreeMap<u64, Entry>,
+    next_id: u64,
+    read_cursor: u64,
+    closed: Vec<(u64, Error)>,
+}
+
+impl ClientSet {
+    /// Creates empty ClientSet
+    pub fn new() -> Self {
+        ClientSet {
+            clients: BTreeMap::new(),
+            next_id: 0,
+            read_cursor: 0,
+            closed: Vec::new(),
+        }
+    }
+
+    /// Adds a client
+    ///
+    /// # Returns
+    /// ID of the client
+    pub fn insert(&mut self, stream: TcpStream) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let ready = stream.state == super::State::Ready;
+        self.clients.insert(id, Entry { stream, ready });
+        id
+    }
+
+    /// Accepts all pending clients of the server
+    ///
+    /// # Returns
+    /// IDs of accepted clients
+    pub fn accept(&mut self, server: &TcpServer) -> Result<Vec<u64>, Error> {
+        let mut ids = Vec::new();
+        while let Some(stream) = server.accept()? {
+            ids.push(self.insert(stream));
+        }
+        Ok(ids)
+    }
+
+    /// Removes a client
+    ///
+    /// # Returns
+    /// `Some(TcpStream)` or `None` if there is no client with this ID
+    pub fn remove(&mut self, id: u64) -> Option<TcpStream> {
+        self.clients.remove(&id).map(|entry| entry.stream)
+    }
+
+    /// Gets a client
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut TcpStream> {
+        self.clients.get_mut(&id).map(|entry| &mut entry.stream)
+    }
+
+    /// Checks whether the set contains a client
+    pub fn contains(&self, id: u64) -> bool {
+        self.clients.contains_key(&id)
+    }
+
+    /// Checks whether connection of the client is initialized
+    pub fn is_ready(&self, id: u64) -> bool {
+        self.clients.get(&id).is_some_and(|entry| entry.ready)
+    }
+
+    /// Returns number of clients, including those that are not ready
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Checks whether the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Returns IDs of all clients
+    pub fn ids(&self) -> Vec<u64> {
+        self.clients.keys().copied().collect()
+    }
+
+    /// Iterates over ready clients
+    pub fn ready(&self) -> impl Iterator<Item = (u64, &TcpStream)> {
+        self.clients
+            .iter()
+            .filter(|(_, entry)| entry.ready)
+            .map(|(id, entry)| (*id, &entry.stream))
+    }
+
+    /// Iterates over ready clients mutably
+    pub fn ready_mut(&mut self) -> impl Iterator<Item = (u64, &mut TcpStream)> {
+        self.clients
+            .iter_mut()
+            .filter(|(_, entry)| entry.ready)
+            .map(|(id, entry)| (*id, &mut entry.stream))
+    }
+
+    /// Initializes connections and flushes pending writes of all clients
+    ///
+    /// # Returns
+    /// IDs of clients that became ready
+    pub fn update(&mut self) -> Vec<u64> {
+        let mut ready = Vec::new();
+        let mut failed = Vec::new();
+        for (id, entry) in &mut self.clients {
+            let res = if entry.ready {
+                entry.stream.flush().map(|_| ())
+            } else {
+                entry.stream.get_ready().map(|r| {
+                    if r {
+                        entry.ready = true;
+                        ready.push(*id);
+                    }
+                })
+            };
+            if let Err(err) = res {
+                failed.push((*id, err));
+            }
+        }
+        for (id, err) in failed {
+            self.close(id, err);
+        }
+        ready
+    }
+
+    /// Reads a message from any ready client non-blocking
+    ///
+    /// Clients are read in turns, so a busy client does not starve the others
+    /// # Returns
+    /// ID of the client and the message or `None` if no message has arrived
+    pub fn read(&mut self) -> Option<(u64, Message)> {
+        let cursor = self.read_cursor;
+        let ids: Vec<u64> = self
+            .clients
+            .range(cursor..)
+            .chain(self.clients.range(..cursor))
+            .filter(|(_, entry)| entry.ready)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            match self.clients.get_mut(&id).unwrap().stream.read() {
+                Ok(None) => {}
+                Ok(Some(msg)) => {
+                    self.read_cursor = id + 1;
+                    return Some((id, msg));
+                }
+                // Oversized message was skipped
+                Err(err) if err.is_recoverable() => {}
+                Err(err) => self.close(id, err),
+            }
+        }
+        None
+    }
+
+    /// Writes a message to a client
+    ///
+    /// If the write fails, the client is removed and the error is reported by [take_closed](struct.ClientSet.html#method.take_closed)
+    /// # Returns
+    /// [ConnectionClosed](enum.Error.html#variant.ConnectionClosed) if there is no client with this ID or the write failed,
+    /// [NotReady](enum.Error.html#variant.NotReady) if the client is not ready
+    /// or [Backpressure](enum.Error.html#variant.Backpressure) if the client's [write limit](struct.TcpStream.html#method.set_write_limit) is reached
+    pub fn send_to(&mut self, id: u64, msg: &Message) -> Result<(), Error> {
+        let entry = match self.clients.get_mut(&id) {
+            None => return Err(Error::ConnectionClosed),
+            Some(entry) => entry,
+        };
+        if !entry.ready {
+            return Err(Error::NotReady);
+        }
+        match entry.stream.write(msg) {
+            Ok(()) => Ok(()),
+            Err(err) if err.is_recoverable() => Err(err),
+            Err(err) => {
+                self.close(id, err);
+                Err(Error::ConnectionClosed)
+            }
+        }
+    }
+
+    /// Writes a message to all ready clients
+    ///
+    /// Clients that fail are removed, clients whose [write limit](struct.TcpStream.html#method.set_write_limit) is reached are skipped
+    /// # Returns
+    /// Number of clients the message was written to
+    pub fn broadcast(&mut self, msg: &Message) -> usize {
+        let mut sent = 0;
+        let mut failed = Vec::new();
+        for (id, stream) in self.ready_mut() {
+            match stream.write(msg) {
+                Ok(()) => sent += 1,
+                Err(err) if err.is_recoverable() => {}
+                Err(err) => failed.push((id, err)),
+            }
+        }
+        for (id, err) in failed {
+            self.close(id, err);
+        }
+        sent
+    }
+
+    /// Takes clients removed since the last call
+    ///
+    /// # Returns
+    /// IDs of the clients with errors that caused the removal
+    pub fn take_closed(&mut self) -> Vec<(u64, Error)> {
+        std::mem::take(&mut self.closed)
+    }
+
+    /// Blocks until the server or some client has an event
+    ///
+    /// Returns earlier when a [heartbeat](struct.TcpStream.html#method.set_heartbeat) of some client is due,
+    /// so it is sent by the next [read](struct.ClientSet.html#method.read)
+    /// # Arguments
+    ///
+    /// * `server` - Server to wait for new clients on
+    /// * `timeout` - Timeout in milliseconds, -1 to wait indefinitely
+    pub fn wait(&self, server: &TcpServer, mut timeout: i32) {
+        let mut fds: Vec<Fd> = vec![get_fd(server)];
+        let mut events = vec![EV_POLLIN];
+        for entry in self.clients.values() {
+            let heartbeat_timeout = entry.stream.heartbeat_timeout();
+            if heartbeat_timeout >= 0 && (timeout < 0 || heartbeat_timeout < timeout) {
+                timeout = heartbeat_timeout;
+            }
+            fds.push(get_fd(&entry.stream));
+            if entry.stream.write_buffer.is_empty() {
+                events.push(EV_POLLIN);
+            } else {
+                events.push(EV_POLLIN | EV_POLLOUT);
+            }
+        }
+        poll_set_ev_timeout(&mut fds, &mut events, timeout);
+    }
+
+    fn close(&mut self, id: u64, err: Error) {
+        if self.clients.remove(&id).is_some() {
+            self.closed.push((id, err));
+        }
+    }
+}
+
+impl Default for ClientSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
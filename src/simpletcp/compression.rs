@@ -0,0 +1,209 @@
+#[cfg(feature = "deflate")]
+use std::io::{Read, Write};
+
+use std::convert::TryInto;
+
+use super::{Error, Message, TcpStream, FRAME_DATA, FRAME_DATA_COMPRESSED, FRAME_DATA_TAGGED};
+
+/// Default minimal size of a message to be compressed (1 KiB)
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+// Set in the algorithm byte of a compressed frame if the message is tagged
+const TAGGED_FLAG: u8 = 0x80;
+
+/// Compression algorithm
+///
+/// Available algorithms depend on enabled cargo features `zstd`, `lz4` and `deflate`.
+/// See [set_compression](struct.TcpStream.html#method.set_compression)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard, good ratio at reasonable speed
+    #[cfg(feature = "zstd")]
+    Zstd,
+
+    /// LZ4, very fast with lower ratio
+    #[cfg(feature = "lz4")]
+    Lz4,
+
+    /// Deflate, widely supported
+    #[cfg(feature = "deflate")]
+    Deflate,
+}
+
+// Without any compression feature the enum has no variants and arguments are unused
+#[allow(unused_variables)]
+impl Compression {
+    // Bit of the algorithm in the handshake
+    fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 1 << 0,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 1 << 1,
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => 1 << 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            #[cfg(feature = "zstd")]
+            1 => Some(Compression::Zstd),
+            #[cfg(feature = "lz4")]
+            2 => Some(Compression::Lz4),
+            #[cfg(feature = "deflate")]
+            4 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::compress(data, 0).ok(),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::block::compress(data)),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).ok()?;
+                encoder.finish().ok()
+            }
+        }
+    }
+
+    // Never produces more than `len` bytes, so a small frame cannot expand into a huge message
+    fn decompress(self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::decompress(data, len).ok(),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::block::decompress(data, len).ok(),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                let mut res = Vec::with_capacity(len);
+                flate2::read::DeflateDecoder::new(data)
+                    .take(len as u64 + 1)
+                    .read_to_end(&mut res)
+                    .ok()
+                    .map(|_| res)
+            }
+        }
+    }
+}
+
+// Algorithms this build can decompress, sent to the peer in the handshake
+#[allow(unused_mut)]
+pub(super) fn supported() -> u8 {
+    let mut mask = 0;
+    #[cfg(feature = "zstd")]
+    {
+        mask |= Compression::Zstd.id();
+    }
+    #[cfg(feature = "lz4")]
+    {
+        mask |= Compression::Lz4.id();
+    }
+    #[cfg(feature = "deflate")]
+    {
+        mask |= Compression::Deflate.id();
+    }
+    mask
+}
+
+impl TcpStream {
+    /// Sets compression of written messages
+    ///
+    /// Peers exchange supported algorithms during connection initialization.
+    /// Messages are compressed only if the peer supports the algorithm, they are at least [threshold](struct.TcpStream.html#method.set_compression_threshold) long
+    /// and compression actually makes them smaller. Compression can be disabled for a message by [set_compressible](struct.Message.html#method.set_compressible).
+    /// Received messages are decompressed regardless of this setting.
+    /// Supported algorithms are bound to the session keys, so if they are altered in transit, the first frame fails with [InvalidFrame](enum.Error.html#variant.InvalidFrame).
+    ///
+    /// # Security
+    /// Length of a compressed message reveals how well its content compresses.
+    /// If a message contains both secrets and data controlled by an attacker who can observe the traffic,
+    /// the secrets can be guessed byte by byte (CRIME/BREACH attacks).
+    /// Do not compress such messages, see [set_compressible](struct.Message.html#method.set_compressible).
+    ///
+    /// Default is `None`
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Gets compression of written messages
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Returns compression that is actually used, i.e. if the peer supports it
+    pub fn negotiated_compression(&self) -> Option<Compression> {
+        self.compression
+            .filter(|compression| self.peer_compression & compression.id() != 0)
+    }
+
+    /// Sets minimal size of a message to be compressed
+    ///
+    /// Default is [DEFAULT_COMPRESSION_THRESHOLD](constant.DEFAULT_COMPRESSION_THRESHOLD.html)
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Gets minimal size of a message to be compressed
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    pub(super) fn encrypt_message_into(
+        &mut self,
+        msg: &Message,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let bytes = msg.as_bytes();
+        if let Some(compression) = self.negotiated_compression() {
+            if msg.compressible && bytes.len() >= self.compression_threshold {
+                if let Some(compressed) = compression.compress(bytes) {
+                    if compressed.len() + 5 < bytes.len() {
+                        let len = bytes.len() as u32;
+                        let mut id = compression.id();
+                        if msg.tagged {
+                            id |= TAGGED_FLAG;
+                        }
+                        return self.encrypt_frame_into(
+                            FRAME_DATA_COMPRESSED,
+                            &[&[id], &len.to_le_bytes(), &compressed],
+                            out,
+                        );
+                    }
+                }
+            }
+        }
+        let kind = if msg.tagged {
+            FRAME_DATA_TAGGED
+        } else {
+            FRAME_DATA
+        };
+        self.encrypt_frame_into(kind, &[bytes], out)
+    }
+
+    pub(super) fn decompress_frame(&self, payload: &[u8]) -> Result<Message, Error> {
+        if payload.len() < 5 {
+            return Err(Error::InvalidFrame);
+        }
+        let compression =
+            Compression::from_id(payload[0] & !TAGGED_FLAG).ok_or(Error::InvalidFrame)?;
+        let len = u32::from_le_bytes(payload[1..5].try_into().unwrap()) as usize;
+        // Protects against decompression bombs
+        if len > self.size_limit {
+            return Err(Error::SizeLimitExceeded);
+        }
+        let buffer = compression
+            .decompress(&payload[5..], len)
+            .filter(|buffer| buffer.len() == len)
+            .ok_or(Error::InvalidFrame)?;
+        let mut msg = Message::from_buffer(buffer);
+        msg.tagged = payload[0] & TAGGED_FLAG != 0;
+        Ok(msg)
+    }
+}
@@ -0,0 +1,216 @@
+use std::ops::{Deref, DerefMut};
+
+use super::{Message, MessageError, ValueType};
+
+/// Nested message borrowed from a [Message](struct.Message.html)
+///
+/// Returned by [read_message](struct.Message.html#method.read_message).
+/// The view behaves as a message containing only the nested payload, its position starts at 0.
+/// When the view is dropped, read cursor of the parent message is moved past the payload, regardless of how much was read.
+/// Written values are appended to the parent message.
+pub struct MessageView<'a> {
+    msg: &'a mut Message,
+    parent_start: usize,
+    parent_end: Option<usize>,
+    parent_tagged: bool,
+    end: usize,
+}
+
+macro_rules! impl_peek {
+    ($($ty:ty => $peek:ident, $read:ident;)*) => {
+        $(
+            #[doc = concat!("Reads `", stringify!($ty), "` without moving read cursor")]
+            ///
+            #[doc = concat!("See [", stringify!($read), "](struct.Message.html#method.", stringify!($read), ")")]
+            pub fn $peek(&mut self) -> Result<$ty, MessageError> {
+                self.peek(Self::$read)
+            }
+        )*
+    };
+}
+
+impl Message {
+    /// Returns position of read cursor
+    pub fn position(&self) -> usize {
+        self.read_pos - self.read_start
+    }
+
+    /// Moves read cursor
+    ///
+    /// # Arguments
+    ///
+    /// * `pos` - New position, e.g. from [position](struct.Message.html#method.position)
+    /// # Returns
+    /// [UnexpectedEnd](enum.MessageError.html#variant.UnexpectedEnd) if `pos` is past the end of the message
+    pub fn set_position(&mut self, pos: usize) -> Result<(), MessageError> {
+        if pos > self.end() - self.read_start {
+            return Err(MessageError::UnexpectedEnd);
+        }
+        self.read_pos = self.read_start + pos;
+        Ok(())
+    }
+
+    /// Returns number of bytes after read cursor
+    pub fn remaining(&self) -> usize {
+        self.end() - self.read_pos
+    }
+
+    /// Moves read cursor to the beginning of the message
+    pub fn reset(&mut self) {
+        self.read_pos = self.read_start;
+    }
+
+    /// Returns the encoded message
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[self.read_start..self.end()]
+    }
+
+    /// Consumes the message and returns its buffer
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    /// Runs `read` and moves read cursor back
+    ///
+    /// # Arguments
+    ///
+    /// * `read` - Function reading from the message, e.g. `Message::read_u32`
+    pub fn peek<T, F>(&mut self, read: F) -> Result<T, MessageError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, MessageError>,
+    {
+        let pos = self.read_pos;
+        let res = read(self);
+        self.read_pos = pos;
+        res
+    }
+
+    impl_peek! {
+        u8 => peek_u8, read_u8;
+        i8 => peek_i8, read_i8;
+        u16 => peek_u16, read_u16;
+        i16 => peek_i16, read_i16;
+        u32 => peek_u32, read_u32;
+        i32 => peek_i32, read_i32;
+        u64 => peek_u64, read_u64;
+        i64 => peek_i64, read_i64;
+        u128 => peek_u128, read_u128;
+        i128 => peek_i128, read_i128;
+        f32 => peek_f32, read_f32;
+        f64 => peek_f64, read_f64;
+        bool => peek_bool, read_bool;
+        char => peek_char, read_char;
+        u16 => peek_varint_u16, read_varint_u16;
+        u32 => peek_varint_u32, read_varint_u32;
+        u64 => peek_varint_u64, read_varint_u64;
+        i16 => peek_varint_i16, read_varint_i16;
+        i32 => peek_varint_i32, read_varint_i32;
+        i64 => peek_varint_i64, read_varint_i64;
+    }
+
+    /// Reads buffer without moving read cursor
+    ///
+    /// See [read_buffer](struct.Message.html#method.read_buffer)
+    pub fn peek_buffer(&mut self) -> Result<&[u8], MessageError> {
+        let pos = self.read_pos;
+        let len = match self.read_buffer() {
+            Ok(buf) => buf.len(),
+            Err(e) => {
+                self.read_pos = pos;
+                return Err(e);
+            }
+        };
+        let end = std::mem::replace(&mut self.read_pos, pos);
+        Ok(&self.buffer[end - len..end])
+    }
+
+    /// Reads string without moving read cursor
+    ///
+    /// See [read_str](struct.Message.html#method.read_str)
+    pub fn peek_str(&mut self) -> Result<&str, MessageError> {
+        let pos = self.read_pos;
+        let len = match self.read_str() {
+            Ok(s) => s.len(),
+            Err(e) => {
+                self.read_pos = pos;
+                return Err(e);
+            }
+        };
+        let end = std::mem::replace(&mut self.read_pos, pos);
+        Ok(std::str::from_utf8(&self.buffer[end - len..end]).unwrap())
+    }
+
+    /// Reads nested message without copying and moves read cursor
+    ///
+    /// Nested message is stored as [buffer](struct.Message.html#method.write_buffer), e.g. `msg.write_buffer(nested.as_bytes())`.
+    /// The view is [tagged](struct.Message.html#method.set_tagged) if this message is.
+    /// # Returns
+    /// [MessageView](struct.MessageView.html) or [MessageError](enum.MessageError.html) if reading failed
+    /// or the message is nested too deeply, see [LimitExceeded](enum.MessageError.html#variant.LimitExceeded)
+    pub fn read_message(&mut self) -> Result<MessageView<'_>, MessageError> {
+        self.read_tag(ValueType::Buffer)?;
+        let len = u32::from_le_bytes(self.take()?) as usize;
+        if self.remaining() < len {
+            return Err(MessageError::UnexpectedEnd);
+        }
+        self.enter_nested()?;
+        let start = self.read_pos;
+        Ok(MessageView {
+            parent_start: std::mem::replace(&mut self.read_start, start),
+            parent_end: self.read_end.replace(start + len),
+            parent_tagged: self.tagged,
+            end: start + len,
+            msg: self,
+        })
+    }
+
+    // End of readable part of the buffer
+    pub(super) fn end(&self) -> usize {
+        self.read_end.unwrap_or(self.buffer.len())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(buffer: Vec<u8>) -> Self {
+        Message::from_buffer(buffer)
+    }
+}
+
+impl Clone for Message {
+    // A view is cloned into a standalone message containing only the nested payload
+    fn clone(&self) -> Self {
+        Message {
+            buffer: self.as_bytes().to_vec(),
+            read_pos: self.position(),
+            read_start: 0,
+            read_end: None,
+            depth: 0,
+            compressible: self.compressible,
+            tagged: self.tagged,
+        }
+    }
+}
+
+impl<'a> Deref for MessageView<'a> {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        self.msg
+    }
+}
+
+impl<'a> DerefMut for MessageView<'a> {
+    fn deref_mut(&mut self) -> &mut Message {
+        self.msg
+    }
+}
+
+impl<'a> Drop for MessageView<'a> {
+    fn drop(&mut self) {
+        self.msg.read_start = self.parent_start;
+        self.msg.read_end = self.parent_end;
+        self.msg.tagged = self.parent_tagged;
+        self.msg.depth -= 1;
+        self.msg.read_pos = self.end;
+    }
+}
@@ -0,0 +1,60 @@
+//! Field types rejected by `MessageEncode` and `MessageDecode` derive macros
+//!
+//! Unsupported field type
+//! ```compile_fail
+//! use simpletcp::simpletcp::MessageEncode;
+//!
+//! #[derive(MessageEncode)]
+//! struct Length(usize);
+//! ```
+//!
+//! `varint` on an 8-bit integer
+//! ```compile_fail
+//! use simpletcp::simpletcp::MessageEncode;
+//!
+//! #[derive(MessageEncode)]
+//! struct Flags(#[message(varint)] u8);
+//! ```
+//!
+//! `varint` on a float
+//! ```compile_fail
+//! use simpletcp::simpletcp::MessageEncode;
+//!
+//! #[derive(MessageEncode)]
+//! struct Ratio(#[message(varint)] f32);
+//! ```
+//!
+//! `buffer` on a field other than `Vec<u8>`
+//! ```compile_fail
+//! use simpletcp::simpletcp::MessageEncode;
+//!
+//! #[derive(MessageEncode)]
+//! struct Samples(#[message(buffer)] Vec<u16>);
+//! ```
+//!
+//! Unions
+//! ```compile_fail
+//! use simpletcp::simpletcp::MessageEncode;
+//!
+//! #[derive(MessageEncode)]
+//! union Bits {
+//!     int: u32,
+//!     float: f32,
+//! }
+//! ```
+//!
+//! More than one encoding attribute
+//! ```compile_fail
+//! use simpletcp::simpletcp::MessageEncode;
+//!
+//! #[derive(MessageEncode)]
+//! struct Id(#[message(varint)] #[message(fixed)] u32);
+//! ```
+//!
+//! All of the above compile when the field is supported
+//! ```
+//! use simpletcp::simpletcp::{MessageDecode, MessageEncode};
+//!
+//! #[derive(MessageEncode, MessageDecode)]
+//! struct Fields(#[message(varint)] u16, #[message(buffer)] Vec<u8>, #[message(fixed)] u32);
+//! ```
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+
+use super::{Message, MessageError, ValueType};
+
+// Nesting depth of decoded sequences, maps and nested messages, deeper values fail so untrusted input cannot overflow the stack
+const MAX_DECODE_DEPTH: usize = 64;
+
+/// Type that can be appended to a [Message](struct.Message.html)
+///
+/// With `derive` cargo feature, this can be derived for structs and enums.
+/// Fields are written in order using `Message::write_*` methods, enums start with `u32` variant index.
+/// Field encoding can be changed by attributes:
+/// * `#[message(fixed)]` - fixed-width integer, default for numbers
+/// * `#[message(varint)]` - [varint](struct.Message.html#method.write_varint_u64), zigzag for signed integers, varint length for `Vec<u8>`
+/// * `#[message(buffer)]` - length-prefixed [buffer](struct.Message.html#method.write_buffer), default for `Vec<u8>`
+///
+/// Fields of other types, including `String`, `Option`, `Vec` and maps, are encoded by their `MessageEncode` implementation.
+pub trait MessageEncode {
+    /// Appends the value to the message
+    fn encode(&self, msg: &mut Message);
+}
+
+/// Type that can be read from a [Message](struct.Message.html)
+///
+/// See [MessageEncode](trait.MessageEncode.html) for deriving.
+/// Recursive types fail with [LimitExceeded](enum.MessageError.html#variant.LimitExceeded) when nested too deeply.
+pub trait MessageDecode: Sized {
+    /// Reads the value and moves read cursor
+    /// # Returns
+    /// Value or [MessageError](enum.MessageError.html) if reading failed
+    fn decode(msg: &mut Message) -> Result<Self, MessageError>;
+}
+
+macro_rules! impl_primitive {
+    ($($ty:ty => $write:ident, $read:ident;)*) => {
+        $(
+            impl MessageEncode for $ty {
+                fn encode(&self, msg: &mut Message) {
+                    msg.$write(*self);
+                }
+            }
+
+            impl MessageDecode for $ty {
+                fn decode(msg: &mut Message) -> Result<Self, MessageError> {
+                    msg.$read()
+                }
+            }
+        )*
+    };
+}
+
+impl_primitive! {
+    u8 => write_u8, read_u8;
+    i8 => write_i8, read_i8;
+    u16 => write_u16, read_u16;
+    i16 => write_i16, read_i16;
+    u32 => write_u32, read_u32;
+    i32 => write_i32, read_i32;
+    u64 => write_u64, read_u64;
+    i64 => write_i64, read_i64;
+    u128 => write_u128, read_u128;
+    i128 => write_i128, read_i128;
+    f32 => write_f32, read_f32;
+    f64 => write_f64, read_f64;
+    bool => write_bool, read_bool;
+    char => write_char, read_char;
+}
+
+impl MessageEncode for str {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_str(self);
+    }
+}
+
+impl MessageEncode for String {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_str(self);
+    }
+}
+
+impl MessageDecode for String {
+    fn decode(msg: &mut Message) -> Result<Self, MessageError> {
+        msg.read_str().map(|s| s.to_string())
+    }
+}
+
+impl<T: MessageEncode> MessageEncode for Option<T> {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_option(self.as_ref());
+    }
+}
+
+impl<T: MessageDecode> MessageDecode for Option<T> {
+    fn decode(msg: &mut Message) -> Result<Self, MessageError> {
+        msg.read_option()
+    }
+}
+
+impl<T: MessageEncode> MessageEncode for [T] {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_seq(self);
+    }
+}
+
+impl<T: MessageEncode> MessageEncode for Vec<T> {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_seq(self);
+    }
+}
+
+// Decoded collections are limited to one element per remaining byte of the message
+impl<T: MessageDecode> MessageDecode for Vec<T> {
+    fn decode(msg: &mut Message) -> Result<Self, MessageError> {
+        msg.read_seq(msg.remaining())
+    }
+}
+
+impl<K: MessageEncode, V: MessageEncode, S> MessageEncode for HashMap<K, V, S> {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_map(self);
+    }
+}
+
+impl<K, V, S> MessageDecode for HashMap<K, V, S>
+where
+    K: MessageDecode + Eq + Hash,
+    V: MessageDecode,
+    S: BuildHasher + Default,
+{
+    fn decode(msg: &mut Message) -> Result<Self, MessageError> {
+        msg.read_map(msg.remaining())
+    }
+}
+
+impl<K: MessageEncode, V: MessageEncode> MessageEncode for BTreeMap<K, V> {
+    fn encode(&self, msg: &mut Message) {
+        msg.write_map(self);
+    }
+}
+
+impl<K: MessageDecode + Ord, V: MessageDecode> MessageDecode for BTreeMap<K, V> {
+    fn decode(msg: &mut Message) -> Result<Self, MessageError> {
+        msg.read_map(msg.remaining())
+    }
+}
+
+impl<T: MessageEncode + ?Sized> MessageEncode for &T {
+    fn encode(&self, msg: &mut Message) {
+        (**self).encode(msg);
+    }
+}
+
+impl Message {
+    /// Creates a message containing encoded value
+    ///
+    /// See [MessageEncode](trait.MessageEncode.html)
+    pub fn encode<T: MessageEncode + ?Sized>(value: &T) -> Message {
+        let mut msg = Message::new();
+        value.encode(&mut msg);
+        msg
+    }
+
+    /// Decodes value and moves read cursor
+    ///
+    /// See [MessageDecode](trait.MessageDecode.html)
+    pub fn decode<T: MessageDecode>(&mut self) -> Result<T, MessageError> {
+        T::decode(self)
+    }
+
+    /// Appends optional value to the message
+    ///
+    /// Encoded as `u8` 0 for `None` or 1 followed by the value
+    pub fn write_option<T: MessageEncode + ?Sized>(&mut self, value: Option<&T>) {
+        self.write_option_flag(value.is_some());
+        if let Some(value) = value {
+            value.encode(self);
+        }
+    }
+
+    /// Reads optional value and moves read cursor
+    /// # Returns
+    /// `Option<T>` or [MessageError](enum.MessageError.html) if reading failed
+    pub fn read_option<T: MessageDecode>(&mut self) -> Result<Option<T>, MessageError> {
+        if self.read_option_flag()? {
+            T::decode(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Appends sequence to the message
+    ///
+    /// Encoded as `u32` number of elements followed by the elements
+    /// # Panics
+    /// Panics if the sequence has more than `u32::MAX` elements
+    pub fn write_seq<T: MessageEncode>(&mut self, items: &[T]) {
+        self.write_count(ValueType::Seq, items.len());
+        for item in items {
+            item.encode(self);
+        }
+    }
+
+    /// Reads sequence and moves read cursor
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - Maximal number of elements
+    /// # Returns
+    /// `Vec<T>` or [MessageError](enum.MessageError.html) if reading failed, the sequence has more than `limit` elements
+    /// or it is nested too deeply
+    pub fn read_seq<T: MessageDecode>(&mut self, limit: usize) -> Result<Vec<T>, MessageError> {
+        let len = self.read_count(ValueType::Seq, limit)?;
+        self.nested(|msg| {
+            // Length is not trusted until the elements are actually read
+            let mut items = Vec::with_capacity(len.min(msg.remaining()));
+            for _ in 0..len {
+                items.push(T::decode(msg)?);
+            }
+            Ok(items)
+        })
+    }
+
+    /// Appends map to the message
+    ///
+    /// Encoded as `u32` number of entries followed by keys and values
+    /// # Arguments
+    ///
+    /// * `map` - Entries of the map, e.g. `&HashMap` or `&BTreeMap`
+    /// # Panics
+    /// Panics if the map has more than `u32::MAX` entries
+    pub fn write_map<'a, K, V, I>(&mut self, map: I)
+    where
+        K: MessageEncode + 'a,
+        V: MessageEncode + 'a,
+        I: IntoIterator<Item = (&'a K, &'a V)>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let entries = map.into_iter();
+        self.write_count(ValueType::Map, entries.len());
+        for (key, value) in entries {
+            key.encode(self);
+            value.encode(self);
+        }
+    }
+
+    /// Reads map and moves read cursor
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - Maximal number of entries
+    /// # Returns
+    /// Map, e.g. `HashMap<K, V>` or `BTreeMap<K, V>`, or [MessageError](enum.MessageError.html) if reading failed,
+    /// the map has more than `limit` entries or it is nested too deeply
+    pub fn read_map<K, V, M>(&mut self, limit: usize) -> Result<M, MessageError>
+    where
+        K: MessageDecode,
+        V: MessageDecode,
+        M: FromIterator<(K, V)>,
+    {
+        let len = self.read_count(ValueType::Map, limit)?;
+        self.nested(|msg| {
+            (0..len)
+                .map(|_| Ok((K::decode(msg)?, V::decode(msg)?)))
+                .collect()
+        })
+    }
+
+    // Decodes elements of a sequence or map one level deeper
+    fn nested<T, F>(&mut self, f: F) -> Result<T, MessageError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, MessageError>,
+    {
+        self.enter_nested()?;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    pub(super) fn enter_nested(&mut self) -> Result<(), MessageError> {
+        if self.depth >= MAX_DECODE_DEPTH {
+            return Err(MessageError::LimitExceeded);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+}
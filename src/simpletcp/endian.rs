@@ -0,0 +1,38 @@
+use super::{Message, MessageError, ValueType};
+
+macro_rules! impl_big_endian {
+    ($($ty:ty => $tag:ident, $write:ident, $read:ident;)*) => {
+        $(
+            #[doc = concat!("Appends `", stringify!($ty), "` in big-endian (network) byte order to the message")]
+            ///
+            #[doc = concat!("Tagged messages store [ValueType::", stringify!($tag), "](enum.ValueType.html#variant.", stringify!($tag), ")")]
+            pub fn $write(&mut self, n: $ty) {
+                self.write_tag(ValueType::$tag);
+                self.buffer.extend_from_slice(&n.to_be_bytes());
+            }
+
+            #[doc = concat!("Reads big-endian `", stringify!($ty), "` and moves read cursor")]
+            /// # Returns
+            #[doc = concat!("`", stringify!($ty), "` or [MessageError](enum.MessageError.html) if reading failed")]
+            pub fn $read(&mut self) -> Result<$ty, MessageError> {
+                self.read_tag(ValueType::$tag)?;
+                Ok(<$ty>::from_be_bytes(self.take()?))
+            }
+        )*
+    };
+}
+
+impl Message {
+    impl_big_endian! {
+        u16 => U16Be, write_u16_be, read_u16_be;
+        i16 => I16Be, write_i16_be, read_i16_be;
+        u32 => U32Be, write_u32_be, read_u32_be;
+        i32 => I32Be, write_i32_be, read_i32_be;
+        u64 => U64Be, write_u64_be, read_u64_be;
+        i64 => I64Be, write_i64_be, read_i64_be;
+        u128 => U128Be, write_u128_be, read_u128_be;
+        i128 => I128Be, write_i128_be, read_i128_be;
+        f32 => F32Be, write_f32_be, read_f32_be;
+        f64 => F64Be, write_f64_be, read_f64_be;
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::ErrorKind;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use super::{Error, Message, TcpServer, TcpStream};
+use crate::utils::{get_fd, poll_set_ev_timeout, EV_POLLIN, EV_POLLOUT};
+
+// Pause of accepting clients after an error that was not caused by a single connection
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Callbacks of the event loop started by [run](struct.TcpServer.html#method.run)
+///
+/// Every client gets an ID that is unique for the lifetime of the loop
+pub trait Handler {
+    /// Called when a client is accepted, before the connection is initialized
+    fn on_connect(&mut self, _id: u64, _client: &mut TcpStream) {}
+
+    /// Called when the connection is initialized and ready to read and write messages
+    fn on_ready(&mut self, _id: u64, _client: &mut TcpStream) {}
+
+    /// Called for every received message
+    fn on_message(&mut self, id: u64, client: &mut TcpStream, msg: Message);
+
+    /// Called when the client is removed
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - ID of the client
+    /// * `err` - Error that caused the disconnection, [ConnectionClosed](enum.Error.html#variant.ConnectionClosed) if the client has closed the connection
+    ///   or [Truncated](enum.Error.html#variant.Truncated) if the connection was closed without close notification
+    fn on_disconnect(&mut self, _id: u64, _err: Error) {}
+
+    /// Called when accepting a client fails
+    ///
+    /// Unless the error was caused by a single connection (e.g. it was reset by the client),
+    /// accepting is paused for a while, so a persistent error such as too many open files does not spin the loop.
+    fn on_accept_error(&mut self, _err: Error) {}
+}
+
+struct Client {
+    id: u64,
+    stream: TcpStream,
+    ready: bool,
+    error: Option<Error>,
+}
+
+impl Client {
+    fn process<H: Handler>(&mut self, handler: &mut H) -> Result<(), Error> {
+        if !self.ready {
+            if !self.stream.get_ready()? {
+                return Ok(());
+            }
+            self.ready = true;
+            handler.on_ready(self.id, &mut self.stream);
+        }
+
+        // Drain everything that has arrived, poll only reports new data
+        loop {
+            match self.stream.read() {
+                Ok(Some(msg)) => handler.on_message(self.id, &mut self.stream, msg),
+                Ok(None) => break,
+                // Oversized message is skipped, the client stays connected
+                Err(Error::SizeLimitExceeded) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn events(&self) -> i16 {
+        if !self.stream.write_buffer.is_empty() || self.stream.has_pending_channel_frames() {
+            EV_POLLIN | EV_POLLOUT
+        } else {
+            EV_POLLIN
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(super) type Fd = i32;
+#[cfg(windows)]
+pub(super) type Fd = u64;
+
+// Clients handled by one thread, either the thread calling run or a worker of the pool
+pub(super) struct EventLoop {
+    clients: Vec<Client>,
+}
+
+impl EventLoop {
+    pub(super) fn new() -> Self {
+        EventLoop {
+            clients: Vec::new(),
+        }
+    }
+
+    pub(super) fn add<H: Handler>(&mut self, id: u64, mut stream: TcpStream, handler: &mut H) {
+        handler.on_connect(id, &mut stream);
+        self.clients.push(Client {
+            id,
+            stream,
+            ready: false,
+            error: None,
+        });
+    }
+
+    pub(super) fn process<H: Handler>(&mut self, handler: &mut H) {
+        for client in &mut self.clients {
+            if let Err(err) = client.process(handler) {
+                client.error = Some(err);
+            }
+        }
+        self.clients.retain_mut(|client| match client.error.take() {
+            None => true,
+            Some(err) => {
+                handler.on_disconnect(client.id, err);
+                false
+            }
+        });
+    }
+
+    // Blocks until a client or the extra socket has an event, a heartbeat is due or the timeout elapses
+    pub(super) fn wait(&self, extra: Option<Fd>, mut timeout: i32) {
+        let mut fds = Vec::new();
+        let mut events = Vec::new();
+        if let Some(extra) = extra {
+            fds.push(extra);
+            events.push(EV_POLLIN);
+        }
+        for client in &self.clients {
+            fds.push(get_fd(&client.stream));
+            events.push(client.events());
+            let client_timeout = client.stream.heartbeat_timeout();
+            if client_timeout >= 0 && (timeout < 0 || client_timeout < timeout) {
+                timeout = client_timeout;
+            }
+        }
+        if fds.is_empty() {
+            sleep(Duration::from_millis(timeout.max(0) as u64));
+            return;
+        }
+        poll_set_ev_timeout(&mut fds, &mut events, timeout);
+    }
+}
+
+// Accepts clients of the server, pausing after persistent errors
+pub(crate) struct Acceptor {
+    resume: Option<Instant>,
+}
+
+impl Acceptor {
+    pub(crate) fn new() -> Self {
+        Acceptor { resume: None }
+    }
+
+    // Passes every pending client or accept error to `f`
+    pub(crate) fn accept<F: FnMut(Result<TcpStream, Error>)>(
+        &mut self,
+        server: &TcpServer,
+        mut f: F,
+    ) {
+        if self.timeout() > 0 {
+            return;
+        }
+        self.resume = None;
+        loop {
+            match server.accept() {
+                Ok(Some(stream)) => f(Ok(stream)),
+                Ok(None) => return,
+                Err(err) => {
+                    let persistent = !is_connection_error(&err);
+                    f(Err(err));
+                    if persistent {
+                        self.resume = Some(Instant::now() + ACCEPT_BACKOFF);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Milliseconds until accepting resumes, -1 if it is not paused
+    pub(crate) fn timeout(&self) -> i32 {
+        match self.resume {
+            None => -1,
+            Some(resume) => resume.saturating_duration_since(Instant::now()).as_millis() as i32,
+        }
+    }
+}
+
+// Errors caused by a single connection, other clients can still be accepted
+fn is_connection_error(err: &Error) -> bool {
+    match err {
+        Error::TcpError(io_err) => matches!(
+            io_err.kind(),
+            ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::BrokenPipe
+                | ErrorKind::Interrupted
+                | ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+impl TcpServer {
+    /// Runs an event loop
+    ///
+    /// Accepts clients, initializes connections, reads messages and flushes pending writes.
+    /// The thread sleeps in poll until some client or the server socket has an event, or a heartbeat is due.
+    /// Clients are removed on any error, including [ConnectionClosed](enum.Error.html#variant.ConnectionClosed),
+    /// except [SizeLimitExceeded](enum.Error.html#variant.SizeLimitExceeded) whose messages are skipped.
+    /// Errors of accepting clients are reported to [on_accept_error](trait.Handler.html#method.on_accept_error).
+    /// # Arguments
+    ///
+    /// * `handler` - [Handler](trait.Handler.html) to be called on events
+    pub fn run<H: Handler>(&self, handler: &mut H) -> ! {
+        let mut event_loop = EventLoop::new();
+        let mut acceptor = Acceptor::new();
+        let mut next_id = 0;
+        loop {
+            acceptor.accept(self, |result| match result {
+                Ok(stream) => {
+                    event_loop.add(next_id, stream, handler);
+                    next_id += 1;
+                }
+                Err(err) => handler.on_accept_error(err),
+            });
+            event_loop.process(handler);
+            let paused = acceptor.timeout();
+            if paused < 0 {
+                event_loop.wait(Some(get_fd(self)), -1);
+            } else {
+                event_loop.wait(None, paused);
+            }
+        }
+    }
+}
@@ -2,11 +2,12 @@ use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Formatter;
 use std::io;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, IoSlice, Read, Write};
 use std::net;
-use std::net::ToSocketAddrs;
-use std::collections::VecDeque;
-use std::time::Instant;
+use std::net::Shutdown;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
 
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -17,12 +18,15 @@ use std::os::windows::io::{AsRawSocket, RawSocket};
 extern crate openssl;
 
 use openssl::error::ErrorStack;
-use openssl::pkey::Private;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private};
 use openssl::rsa::Padding;
 use openssl::rsa::Rsa;
 use openssl::symm;
-use openssl::symm::Cipher;
+use openssl::symm::{Cipher, Crypter, Mode};
 use openssl::sha::sha256;
+use openssl::sign::Signer;
 
 extern crate rand;
 
@@ -35,7 +39,85 @@ use crate::utils::{poll, poll_timeout, EV_POLLIN, EV_POLLOUT};
 use MessageError::UnexpectedEnd;
 use State::{NotInitialized, Ready, WaitingForPublicKey, WaitingForSymmKey};
 
-const MSG_SIZE_LIMIT: usize = 4 * 1024 * 1024;
+/// Default size limit of received messages (4 MiB)
+pub const DEFAULT_SIZE_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Default size limit of messages received during connection initialization (16 KiB)
+pub const DEFAULT_HANDSHAKE_SIZE_LIMIT: usize = 16 * 1024;
+
+// Kind of an encrypted frame, stored as the last byte of decrypted data
+const FRAME_DATA: u8 = 0;
+const FRAME_PING: u8 = 1;
+const FRAME_PONG: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+const FRAME_STREAM_BEGIN: u8 = 4;
+const FRAME_STREAM_CHUNK: u8 = 5;
+const FRAME_STREAM_END: u8 = 6;
+const FRAME_STREAM_ACK: u8 = 7;
+const FRAME_STREAM_CANCEL: u8 = 8;
+const FRAME_CHANNEL_OPEN: u8 = 9;
+const FRAME_CHANNEL_DATA: u8 = 10;
+const FRAME_CHANNEL_CLOSE: u8 = 11;
+const FRAME_DATA_COMPRESSED: u8 = 12;
+const FRAME_DATA_TAGGED: u8 = 13;
+const FRAME_CHANNEL_DATA_TAGGED: u8 = 14;
+
+// IV, maximal padding (including frame kind) and MAC of an encrypted frame
+const FRAME_OVERHEAD: usize = 64;
+
+// Length of HMAC-SHA256 appended to every frame
+const MAC_LEN: usize = 32;
+
+// Capacity of the encryption buffer that is kept between writes
+const FRAME_BUFFER_RETAIN: usize = 64 * 1024;
+
+// Size of one read from the socket
+const READ_CHUNK: usize = 64 * 1024;
+
+// Capacity of the read buffer that is kept when it becomes empty
+const READ_BUFFER_RETAIN: usize = 4 * READ_CHUNK;
+
+// Maximal number of queued buffers written by one syscall
+const MAX_WRITE_SLICES: usize = 64;
+
+mod buf;
+mod channel;
+mod clients;
+mod compression;
+mod cursor;
+#[cfg(all(doctest, feature = "derive"))]
+mod derive_errors;
+mod encode;
+mod endian;
+mod event;
+mod pool;
+mod reconnect;
+mod tagged;
+mod transfer;
+mod typed;
+mod varint;
+#[cfg(feature = "serde")]
+mod value;
+
+pub use channel::{DEFAULT_CHANNEL_LIMIT, DEFAULT_CHANNEL_QUEUE_LIMIT};
+pub use clients::ClientSet;
+pub use compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+pub use cursor::MessageView;
+pub use encode::{MessageDecode, MessageEncode};
+pub use event::Handler;
+pub use reconnect::{ReconnectEvent, ReconnectingStream, DEFAULT_CONNECT_TIMEOUT};
+pub use tagged::ValueType;
+pub use transfer::StreamReader;
+pub use typed::{Codec, MessageCodec, TypedStream};
+#[cfg(feature = "serde")]
+pub use typed::SerdeCodec;
+#[cfg(feature = "serde")]
+pub use value::{Deserializer, Serializer};
+#[cfg(feature = "derive")]
+pub use simpletcp_derive::{MessageDecode, MessageEncode};
+use channel::Channel;
+pub(crate) use event::Acceptor;
+use transfer::{IncomingTransfer, OutgoingTransfer, MAX_CHUNK_FRAME};
 
 #[cfg(test)]
 mod tests;
@@ -54,10 +136,61 @@ pub enum Error {
     TcpError(io::Error),
 
     /// TCP connection was closed
+    ///
+    /// Returned after the peer has gracefully closed the connection using [shutdown](struct.TcpStream.html#method.shutdown)
+    /// or when writing to a connection that was shut down
     ConnectionClosed,
 
-    /// Received header of message that would exceed size limit (4 MiB)
+    /// TCP connection was closed without close notification
+    ///
+    /// The peer has crashed or the stream was truncated
+    Truncated,
+
+    /// Received header of message that would exceed size limit
+    ///
+    /// The message is skipped and the stream can be used further.
+    /// If this happens during connection initialization, the connection is closed.
+    /// See [set_size_limit](struct.TcpStream.html#method.set_size_limit)
     SizeLimitExceeded,
+
+    /// Peer has not responded to heartbeats
+    ///
+    /// See [set_heartbeat](struct.TcpStream.html#method.set_heartbeat)
+    PeerTimeout,
+
+    /// Received frame that could not be parsed or failed authentication
+    InvalidFrame,
+
+    /// Server key fingerprint differs from the expected one
+    FingerprintMismatch,
+
+    /// Stream transfer was cancelled by the peer
+    ///
+    /// See [write_stream](struct.TcpStream.html#method.write_stream)
+    StreamCancelled,
+
+    /// Outbound queue is full, message was not queued
+    ///
+    /// Returned when pending data exceed the limit set by [set_write_limit](struct.TcpStream.html#method.set_write_limit)
+    /// or when the queue of [ReconnectingStream](struct.ReconnectingStream.html) is full
+    Backpressure,
+
+    /// Channel does not exist or was closed
+    ///
+    /// See [open_channel](struct.TcpStream.html#method.open_channel)
+    ChannelClosed,
+
+    /// Messages received on a channel were dropped because its queue was full
+    ///
+    /// Returned once by [read_channel](struct.TcpStream.html#method.read_channel), the channel can be used further.
+    /// See [set_channel_queue_limit](struct.TcpStream.html#method.set_channel_queue_limit)
+    ChannelOverflow {
+        /// Number of dropped messages
+        dropped: usize,
+    },
+
+    /// Value could not be encoded into a message or decoded from it
+    MessageError(MessageError),
 }
 
 impl fmt::Debug for Error {
@@ -69,11 +202,35 @@ impl fmt::Debug for Error {
             }
             Error::TcpError(io_err) => f.write_fmt(format_args!("Error::TcpError: {}", io_err)),
             Error::ConnectionClosed => f.write_str("Error::ConnectionClosed"),
+            Error::Truncated => f.write_str("Error::Truncated"),
             Error::SizeLimitExceeded => f.write_str("Error::SizeLimitExceeded"),
+            Error::PeerTimeout => f.write_str("Error::PeerTimeout"),
+            Error::InvalidFrame => f.write_str("Error::InvalidFrame"),
+            Error::FingerprintMismatch => f.write_str("Error::FingerprintMismatch"),
+            Error::StreamCancelled => f.write_str("Error::StreamCancelled"),
+            Error::Backpressure => f.write_str("Error::Backpressure"),
+            Error::ChannelClosed => f.write_str("Error::ChannelClosed"),
+            Error::ChannelOverflow { dropped } => {
+                f.write_fmt(format_args!("Error::ChannelOverflow: {} dropped", dropped))
+            }
+            Error::MessageError(err) => f.write_fmt(format_args!("Error::MessageError: {:?}", err)),
         };
     }
 }
 
+impl Error {
+    // Errors after which the stream can still be used
+    fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            Error::NotReady
+                | Error::Backpressure
+                | Error::SizeLimitExceeded
+                | Error::ChannelOverflow { .. }
+        )
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(io_err: io::Error) -> Self {
         Error::TcpError(io_err)
@@ -86,6 +243,12 @@ impl From<ErrorStack> for Error {
     }
 }
 
+impl From<MessageError> for Error {
+    fn from(err: MessageError) -> Self {
+        Error::MessageError(err)
+    }
+}
+
 /// Internal state of [TcpStream](struct.TcpStream.html)
 #[derive(PartialEq)]
 pub enum State {
@@ -108,6 +271,12 @@ pub enum State {
 pub struct TcpServer {
     socket: net::TcpListener,
     key: Rsa<Private>,
+    size_limit: usize,
+    handshake_size_limit: usize,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    channel_limit: usize,
+    channel_queue_limit: usize,
 }
 
 impl TcpServer {
@@ -144,7 +313,16 @@ impl TcpServer {
         }
         let socket = net::TcpListener::bind(addr)?;
         socket.set_nonblocking(true)?;
-        return Ok(Self { socket, key });
+        return Ok(Self {
+            socket,
+            key,
+            size_limit: DEFAULT_SIZE_LIMIT,
+            handshake_size_limit: DEFAULT_HANDSHAKE_SIZE_LIMIT,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            channel_limit: DEFAULT_CHANNEL_LIMIT,
+            channel_queue_limit: DEFAULT_CHANNEL_QUEUE_LIMIT,
+        });
     }
 
     /// Returns RSA key in DER format
@@ -152,6 +330,78 @@ impl TcpServer {
         self.key.private_key_to_der().unwrap()
     }
 
+    /// Sets size limit of received messages for accepted streams
+    ///
+    /// See [TcpStream::set_size_limit](struct.TcpStream.html#method.set_size_limit)
+    pub fn set_size_limit(&mut self, limit: usize) {
+        self.size_limit = limit;
+    }
+
+    /// Gets size limit of received messages for accepted streams
+    pub fn size_limit(&self) -> usize {
+        self.size_limit
+    }
+
+    /// Sets size limit of messages received during initialization of accepted streams
+    ///
+    /// See [TcpStream::set_handshake_size_limit](struct.TcpStream.html#method.set_handshake_size_limit)
+    pub fn set_handshake_size_limit(&mut self, limit: usize) {
+        self.handshake_size_limit = limit;
+    }
+
+    /// Gets size limit of messages received during initialization of accepted streams
+    pub fn handshake_size_limit(&self) -> usize {
+        self.handshake_size_limit
+    }
+
+    /// Sets compression of written messages for accepted streams
+    ///
+    /// See [TcpStream::set_compression](struct.TcpStream.html#method.set_compression), including its security notes
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Gets compression of written messages for accepted streams
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Sets minimal size of a message to be compressed for accepted streams
+    ///
+    /// See [TcpStream::set_compression_threshold](struct.TcpStream.html#method.set_compression_threshold)
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Gets minimal size of a message to be compressed for accepted streams
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Sets limit of channels opened by the peer for accepted streams
+    ///
+    /// See [TcpStream::set_channel_limit](struct.TcpStream.html#method.set_channel_limit)
+    pub fn set_channel_limit(&mut self, limit: usize) {
+        self.channel_limit = limit;
+    }
+
+    /// Gets limit of channels opened by the peer for accepted streams
+    pub fn channel_limit(&self) -> usize {
+        self.channel_limit
+    }
+
+    /// Sets limit of received messages queued on one channel for accepted streams
+    ///
+    /// See [TcpStream::set_channel_queue_limit](struct.TcpStream.html#method.set_channel_queue_limit)
+    pub fn set_channel_queue_limit(&mut self, limit: usize) {
+        self.channel_queue_limit = limit;
+    }
+
+    /// Gets limit of received messages queued on one channel for accepted streams
+    pub fn channel_queue_limit(&self) -> usize {
+        self.channel_queue_limit
+    }
+
     /// Accepts a client
     ///
     /// # Returns
@@ -161,6 +411,12 @@ impl TcpServer {
         match self.socket.accept() {
             Ok((socket, _addr)) => {
                 let mut stream = TcpStream::from_socket(socket)?;
+                stream.size_limit = self.size_limit;
+                stream.handshake_size_limit = self.handshake_size_limit;
+                stream.compression = self.compression;
+                stream.compression_threshold = self.compression_threshold;
+                stream.channel_limit = self.channel_limit;
+                stream.channel_queue_limit = self.channel_queue_limit;
                 stream.server_init(&self.key)?;
                 Ok(Some(stream))
             }
@@ -204,35 +460,51 @@ impl AsRawSocket for TcpServer {
     }
 }
 
-macro_rules! try_io {
-    ($r: expr, $wb_closure: expr) => {
-        match $r {
-            Ok(res) => res,
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => {
-                    $wb_closure();
-                    return Ok(None);
-                }
-                _ => {
-                    return Err(Error::TcpError(e));
-                }
-            },
-        }
-    };
-}
-
 /// Encrypted TCP stream
 ///
-/// Communication is encrypted using 256-bit AES-CBC, key is negotiated using 4096-bit RSA.
+/// Communication is encrypted using 256-bit AES-CBC and authenticated using HMAC-SHA256, keys are negotiated using 4096-bit RSA.
 pub struct TcpStream {
     socket: net::TcpStream,
     read_buffer: Vec<u8>,
+    read_start: usize,
+    read_end: usize,
     write_buffer: DequeueBuffer,
     key: [u8; 32],
+    send_mac_key: [u8; 32],
+    recv_mac_key: [u8; 32],
+    send_seq: u64,
+    recv_seq: u64,
     state: State,
     rsa_key: Option<Rsa<Private>>,
     fingerprint: [u8; 32],
     rand: StdRng,
+    heartbeat: Option<Duration>,
+    max_missed_pings: u32,
+    missed_pings: u32,
+    last_ping: Instant,
+    pending_ping: Option<(u64, Instant)>,
+    ping_seq: u64,
+    rtt: Option<Duration>,
+    local_closed: bool,
+    peer_closed: bool,
+    size_limit: usize,
+    handshake_size_limit: usize,
+    write_limit: Option<usize>,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    peer_compression: u8,
+    frame_buffer: Vec<u8>,
+    discard: usize,
+    incoming: VecDeque<Message>,
+    incoming_transfer: Option<IncomingTransfer>,
+    outgoing_transfer: Option<OutgoingTransfer>,
+    next_transfer_id: u64,
+    channels: BTreeMap<u32, Channel>,
+    accepted_channels: VecDeque<u32>,
+    next_channel_id: u32,
+    channel_cursor: u32,
+    channel_limit: usize,
+    channel_queue_limit: usize,
 }
 
 impl TcpStream {
@@ -241,12 +513,45 @@ impl TcpStream {
         Ok(Self {
             socket,
             read_buffer: Vec::new(),
+            read_start: 0,
+            read_end: 0,
             write_buffer: DequeueBuffer::new(),
             key: Default::default(),
+            send_mac_key: Default::default(),
+            recv_mac_key: Default::default(),
+            send_seq: 0,
+            recv_seq: 0,
             state: NotInitialized,
             rsa_key: None,
             fingerprint: [0; 32],
             rand: StdRng::from_entropy(),
+            heartbeat: None,
+            max_missed_pings: 0,
+            missed_pings: 0,
+            last_ping: Instant::now(),
+            pending_ping: None,
+            ping_seq: 0,
+            rtt: None,
+            local_closed: false,
+            peer_closed: false,
+            size_limit: DEFAULT_SIZE_LIMIT,
+            handshake_size_limit: DEFAULT_HANDSHAKE_SIZE_LIMIT,
+            write_limit: None,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            peer_compression: 0,
+            frame_buffer: Vec::new(),
+            discard: 0,
+            incoming: VecDeque::new(),
+            incoming_transfer: None,
+            outgoing_transfer: None,
+            next_transfer_id: 0,
+            channels: BTreeMap::new(),
+            accepted_channels: VecDeque::new(),
+            next_channel_id: 1,
+            channel_cursor: 0,
+            channel_limit: DEFAULT_CHANNEL_LIMIT,
+            channel_queue_limit: DEFAULT_CHANNEL_QUEUE_LIMIT,
         })
     }
 
@@ -257,26 +562,34 @@ impl TcpStream {
     /// * `addr` - Address of remote [TcpServer](struct.TcpServer.html)
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
         let socket = net::TcpStream::connect(addr)?;
-        socket.set_nonblocking(true)?;
+        let mut stream = Self::from_socket(socket)?;
+        stream.state = WaitingForPublicKey;
+        Ok(stream)
+    }
 
-        Ok(Self {
-            socket,
-            read_buffer: Vec::new(),
-            write_buffer: DequeueBuffer::new(),
-            key: Default::default(),
-            state: WaitingForPublicKey,
-            rsa_key: None,
-            rand: StdRng::from_entropy(),
-            fingerprint: [0; 32],
-        })
+    /// Connects to remote [TcpServer](struct.TcpServer.html) with timeout
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Address of remote [TcpServer](struct.TcpServer.html)
+    /// * `timeout` - Timeout of establishing the TCP connection, connection initialization is not included
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Self, Error> {
+        let socket = net::TcpStream::connect_timeout(addr, timeout)?;
+        let mut stream = Self::from_socket(socket)?;
+        stream.state = WaitingForPublicKey;
+        Ok(stream)
     }
 
     fn server_init(&mut self, rsa_key: &Rsa<Private>) -> Result<(), Error> {
         let raw = rsa_key.public_key_to_der()?;
         self.fingerprint = sha256(&raw);
+        // Supported compression is appended to handshake messages
+        let mut raw = raw;
+        raw.push(compression::supported());
         self.write_raw(&raw)?;
         self.rsa_key = Some(rsa_key.clone());
         self.state = WaitingForSymmKey;
+        self.next_channel_id = 2;
         Ok(())
     }
 
@@ -284,7 +597,8 @@ impl TcpStream {
         match self.state {
             NotInitialized => panic!("TcpStream init_step state NotInitialized"),
             WaitingForPublicKey => match self.read_raw()? {
-                Some(rsa_key) => {
+                Some(mut rsa_key) => {
+                    self.peer_compression = rsa_key.pop().ok_or(Error::InvalidFrame)?;
                     self.rand.fill_bytes(&mut self.key);
                     self.fingerprint = sha256(&rsa_key);
                     let rsa_key = Rsa::public_key_from_der(&rsa_key)?;
@@ -295,13 +609,16 @@ impl TcpStream {
                         Padding::PKCS1_OAEP,
                     )?;
                     encrypted_key.resize(encrypted_size, 0);
+                    encrypted_key.push(compression::supported());
                     self.write_raw(&encrypted_key)?;
+                    self.derive_keys(self.peer_compression, compression::supported(), false)?;
                     self.state = Ready;
                 }
                 None => {}
             },
             WaitingForSymmKey => match self.read_raw()? {
-                Some(encrypted_key) => {
+                Some(mut encrypted_key) => {
+                    self.peer_compression = encrypted_key.pop().ok_or(Error::InvalidFrame)?;
                     let rsa_key = self.rsa_key.as_ref().unwrap();
                     let mut key: Vec<u8> = vec![0; rsa_key.size() as usize];
                     let key_size =
@@ -310,6 +627,7 @@ impl TcpStream {
                     assert_eq!(key_size, 32);
 
                     self.key.copy_from_slice(&key);
+                    self.derive_keys(compression::supported(), self.peer_compression, true)?;
                     self.state = Ready;
                 }
                 None => {}
@@ -320,8 +638,39 @@ impl TcpStream {
         Ok(())
     }
 
+    // Derives encryption and MAC keys from the secret sent by the client
+    //
+    // Server key and supported compression of both sides are bound to the keys,
+    // so if they are tampered with, the first frame fails verification
+    fn derive_keys(
+        &mut self,
+        server_compression: u8,
+        client_compression: u8,
+        server: bool,
+    ) -> Result<(), Error> {
+        let secret = self.key;
+        let context = [server_compression, client_compression];
+        let derive = |label: &[u8]| hmac_sha256(&secret, &[label, &self.fingerprint, &context]);
+        let key = derive(b"simpletcp key")?;
+        let client_mac_key = derive(b"simpletcp client mac")?;
+        let server_mac_key = derive(b"simpletcp server mac")?;
+
+        self.key = key;
+        if server {
+            self.send_mac_key = server_mac_key;
+            self.recv_mac_key = client_mac_key;
+        } else {
+            self.send_mac_key = client_mac_key;
+            self.recv_mac_key = server_mac_key;
+        }
+        Ok(())
+    }
+
     /// Reads a message non-blocking
     ///
+    /// Heartbeat frames are handled internally, so this should be called regularly when heartbeats are enabled.
+    /// Data are read from the socket in large chunks, so messages may be buffered without the socket being readable.
+    /// When waiting using [poll](../utils/fn.poll.html), call this until it returns `None` first.
     /// # Returns
     /// Returns `Some(Message)` or `None` if no message has arrived
     pub fn read(&mut self) -> Result<Option<Message>, Error> {
@@ -329,16 +678,78 @@ impl TcpStream {
             return Err(Error::NotReady);
         }
 
-        match self.read_raw()? {
-            None => Ok(None),
-            Some(buf) => {
-                let iv = &buf[..16];
-                let decrypted =
-                    symm::decrypt(Cipher::aes_256_cbc(), &self.key, Some(iv), &buf[16..])?;
+        loop {
+            if let Some(msg) = self.incoming.pop_front() {
+                return Ok(Some(msg));
+            }
+            if self.peer_closed {
+                return Err(Error::ConnectionClosed);
+            }
+            if !self.receive_frame()? {
+                self.heartbeat_tick()?;
+                return Ok(None);
+            }
+        }
+    }
+
+    // Receives and handles one frame, returns `false` if no frame is available
+    fn receive_frame(&mut self) -> Result<bool, Error> {
+        let (start, end) = match self.next_frame()? {
+            None => return Ok(false),
+            Some(range) => range,
+        };
 
-                Ok(Some(Message::from_buffer(decrypted)))
+        let seq = self.recv_seq;
+        self.recv_seq += 1;
+        let (kind, payload) = self.decrypt_frame(seq, &self.read_buffer[start..end])?;
+        self.missed_pings = 0;
+        // Frames below the size of a stream chunk are not limited by next_frame, so messages are checked here
+        let message_len = match kind {
+            FRAME_DATA | FRAME_DATA_TAGGED => payload.len(),
+            FRAME_CHANNEL_DATA | FRAME_CHANNEL_DATA_TAGGED => payload.len().saturating_sub(4),
+            _ => 0,
+        };
+        if message_len > self.size_limit {
+            return Err(Error::SizeLimitExceeded);
+        }
+        match kind {
+            FRAME_DATA => self.incoming.push_back(Message::from_buffer(payload)),
+            FRAME_DATA_TAGGED => {
+                let mut msg = Message::from_buffer(payload);
+                msg.tagged = true;
+                self.incoming.push_back(msg);
+            }
+            FRAME_DATA_COMPRESSED => {
+                let msg = self.decompress_frame(&payload)?;
+                self.incoming.push_back(msg);
             }
+            FRAME_PING => {
+                if !self.local_closed {
+                    self.write_frame(FRAME_PONG, &payload)?;
+                }
+            }
+            FRAME_PONG => self.handle_pong(&payload)?,
+            FRAME_CLOSE => self.peer_closed = true,
+            FRAME_STREAM_BEGIN..=FRAME_STREAM_CANCEL => self.handle_transfer_frame(kind, &payload)?,
+            FRAME_CHANNEL_OPEN..=FRAME_CHANNEL_CLOSE | FRAME_CHANNEL_DATA_TAGGED => {
+                self.handle_channel_frame(kind, payload)?
+            }
+            _ => return Err(Error::InvalidFrame),
+        }
+        Ok(true)
+    }
+
+    // Blocks until a frame is received and handled
+    fn wait_frame(&mut self) -> Result<(), Error> {
+        while !self.receive_frame()? {
+            self.heartbeat_tick()?;
+            let mut events = EV_POLLIN;
+            if !self.flush()? {
+                events |= EV_POLLOUT;
+            }
+            poll_timeout(self, events, self.heartbeat_timeout());
         }
+        Ok(())
     }
 
     /// Reads a message blocking
@@ -349,7 +760,7 @@ impl TcpStream {
         loop {
             match self.read()? {
                 None => {
-                    poll(self, EV_POLLIN);
+                    poll_timeout(self, EV_POLLIN, self.heartbeat_timeout());
                 }
                 Some(msg) => {
                     return Ok(msg);
@@ -370,10 +781,15 @@ impl TcpStream {
         loop {
             match self.read()? {
                 None => {
-                    if timeout < time.elapsed().as_millis() as i32 {
+                    let elapsed = time.elapsed().as_millis() as i32;
+                    if timeout < elapsed {
                         return Ok(None);
                     }
-                    if !poll_timeout(self, EV_POLLIN, timeout) {
+                    let remaining = timeout - elapsed;
+                    let heartbeat_timeout = self.heartbeat_timeout();
+                    if heartbeat_timeout >= 0 && heartbeat_timeout < remaining {
+                        poll_timeout(self, EV_POLLIN, heartbeat_timeout);
+                    } else if !poll_timeout(self, EV_POLLIN, remaining) {
                         return Ok(None);
                     }
                 }
@@ -390,49 +806,324 @@ impl TcpStream {
     /// # Arguments
     ///
     /// * `msg` - Message to be sent
+    /// # Returns
+    /// [Backpressure](enum.Error.html#variant.Backpressure) if pending data exceed the [write limit](struct.TcpStream.html#method.set_write_limit)
     pub fn write(&mut self, msg: &Message) -> Result<(), Error> {
         if self.state != Ready {
             return Err(Error::NotReady);
         }
+        if self.local_closed {
+            return Err(Error::ConnectionClosed);
+        }
+        self.check_write_limit()?;
+
+        let mut buf = self.take_frame_buffer();
+        let res = self
+            .encrypt_message_into(msg, &mut buf)
+            .and_then(|_| self.write_encoded(&buf));
+        self.return_frame_buffer(buf);
+        res
+    }
 
-        let mut iv = [0; 16];
-        self.rand.fill_bytes(&mut iv);
-
-        let mut encrypted =
-            symm::encrypt(Cipher::aes_256_cbc(), &self.key, Some(&iv), &msg.buffer)?;
-
-        let mut raw = iv.to_vec();
-        raw.append(&mut encrypted);
-        self.write_raw(&raw)
+    /// Writes multiple messages at once
+    ///
+    /// Messages are encrypted into one buffer and written by a single syscall, which is much faster than separate [write](struct.TcpStream.html#method.write) calls for small messages.
+    /// The [write limit](struct.TcpStream.html#method.set_write_limit) is checked once for the whole batch.
+    /// You should call [flush](struct.TcpStream.html#method.flush) afterwards
+    /// # Arguments
+    ///
+    /// * `msgs` - Messages to be sent
+    pub fn write_many(&mut self, msgs: &[Message]) -> Result<(), Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+        if self.local_closed {
+            return Err(Error::ConnectionClosed);
+        }
+        self.check_write_limit()?;
+
+        let mut buf = self.take_frame_buffer();
+        let seq = self.send_seq;
+        let mut res = Ok(());
+        for msg in msgs {
+            res = self.encrypt_message_into(msg, &mut buf);
+            if res.is_err() {
+                break;
+            }
+        }
+        if res.is_ok() {
+            res = self.write_encoded(&buf);
+        } else {
+            // Frames of the batch are discarded, so their sequence numbers are reused
+            self.send_seq = seq;
+        }
+        self.return_frame_buffer(buf);
+        res
     }
 
     /// Writes a message and blocks until it's completely flushed
     ///
+    /// If pending data exceed the [write limit](struct.TcpStream.html#method.set_write_limit), blocks until they are flushed below it
     /// # Arguments
     ///
     /// * `msg` - Message to be sent
     pub fn write_blocking(&mut self, msg: &Message) -> Result<(), Error> {
+        loop {
+            match self.write(msg) {
+                Err(Error::Backpressure) => {
+                    poll(self, EV_POLLOUT);
+                }
+                res => break res?,
+            }
+        }
+
+        while !self.flush()? {
+            poll(self, EV_POLLOUT);
+        }
+
+        Ok(())
+    }
+
+    /// Gracefully shuts down the writing half of the connection
+    ///
+    /// Flushes all pending data, sends close notification and shuts down the socket for writing.
+    /// Messages can still be read until the peer shuts down its side, after that [read](struct.TcpStream.html#method.read)
+    /// returns [ConnectionClosed](enum.Error.html#variant.ConnectionClosed).
+    /// Dropping the stream without shutting it down may discard unflushed data.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
         if self.state != Ready {
             return Err(Error::NotReady);
         }
+        if self.local_closed {
+            return Ok(());
+        }
+
+        self.write_frame(FRAME_CLOSE, &[])?;
+        self.local_closed = true;
+        while !self.flush()? {
+            poll(self, EV_POLLOUT);
+        }
+        self.socket.shutdown(Shutdown::Write)?;
+        Ok(())
+    }
+
+    /// Gracefully closes the connection
+    ///
+    /// Same as [shutdown](struct.TcpStream.html#method.shutdown), but consumes the stream
+    pub fn close(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    /// Returns `true` if the peer has gracefully closed the connection
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed
+    }
+
+    // Control frames are not limited, only messages written by the user
+    fn check_write_limit(&mut self) -> Result<(), Error> {
+        let limit = match self.write_limit {
+            None => return Ok(()),
+            Some(limit) => limit,
+        };
+        if self.pending_bytes() >= limit {
+            self.flush()?;
+            // A message is always accepted when nothing is pending
+            let pending = self.pending_bytes();
+            if pending > 0 && pending >= limit {
+                return Err(Error::Backpressure);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, kind: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut buf = self.take_frame_buffer();
+        let res = self
+            .encrypt_frame_into(kind, &[payload], &mut buf)
+            .and_then(|_| self.write_encoded(&buf));
+        self.return_frame_buffer(buf);
+        res
+    }
+
+    // Returns encrypted frame that must be passed to seal_frame right before it is sent
+    fn encrypt_frame(&mut self, kind: u8, parts: &[&[u8]]) -> Result<Vec<u8>, Error> {
+        let mut raw = Vec::new();
+        self.encrypt_unsealed_into(kind, parts, &mut raw)?;
+        Ok(raw)
+    }
+
+    // Appends length-prefixed encrypted and authenticated frame to `out`
+    fn encrypt_frame_into(
+        &mut self,
+        kind: u8,
+        parts: &[&[u8]],
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let start = out.len();
+        self.encrypt_unsealed_into(kind, parts, out)?;
+        self.seal_frame(out, start)
+    }
 
+    // Appends encrypted frame without length and MAC to `out`, leaving room for the length
+    fn encrypt_unsealed_into(
+        &mut self,
+        kind: u8,
+        parts: &[&[u8]],
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
         let mut iv = [0; 16];
         self.rand.fill_bytes(&mut iv);
 
-        let mut encrypted =
-            symm::encrypt(Cipher::aes_256_cbc(), &self.key, Some(&iv), &msg.buffer)?;
+        let cipher = Cipher::aes_256_cbc();
+        let mut crypter = Crypter::new(cipher, Mode::Encrypt, &self.key, Some(&iv))?;
+        let payload_len: usize = parts.iter().map(|part| part.len()).sum();
+        let start = out.len();
+        out.resize(start + 4 + iv.len() + payload_len + 1 + cipher.block_size(), 0);
+        out[start + 4..start + 4 + iv.len()].copy_from_slice(&iv);
+        let mut len = start + 4 + iv.len();
+        for part in parts {
+            len += crypter.update(part, &mut out[len..])?;
+        }
+        len += crypter.update(&[kind], &mut out[len..])?;
+        len += crypter.finalize(&mut out[len..])?;
+        out.truncate(len);
+        Ok(())
+    }
 
-        let mut raw = iv.to_vec();
-        raw.append(&mut encrypted);
-        self.write_raw(&raw)?;
+    // Writes length of the frame starting at `start` and appends its MAC
+    //
+    // The MAC covers sequence number of the frame, so frames must be sealed in the order they are sent
+    fn seal_frame(&mut self, out: &mut Vec<u8>, start: usize) -> Result<(), Error> {
+        let frame_len = (out.len() - start - 4 + MAC_LEN) as u32;
+        out[start..start + 4].copy_from_slice(&frame_len.to_le_bytes());
+        let mac = hmac_sha256(
+            &self.send_mac_key,
+            &[&self.send_seq.to_le_bytes(), &out[start..]],
+        )?;
+        out.extend_from_slice(&mac);
+        self.send_seq += 1;
+        Ok(())
+    }
 
-        while !self.flush().unwrap() {
-            poll(self, EV_POLLOUT);
+    fn take_frame_buffer(&mut self) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.frame_buffer);
+        buf.clear();
+        buf
+    }
+
+    fn return_frame_buffer(&mut self, buf: Vec<u8>) {
+        // Do not keep memory of one huge message for the lifetime of the stream
+        if buf.capacity() <= FRAME_BUFFER_RETAIN {
+            self.frame_buffer = buf;
+        }
+    }
+
+    // Verifies MAC of the frame before decrypting it
+    fn decrypt_frame(&self, seq: u64, buf: &[u8]) -> Result<(u8, Vec<u8>), Error> {
+        if buf.len() < 16 + MAC_LEN {
+            return Err(Error::InvalidFrame);
+        }
+        let (buf, mac) = buf.split_at(buf.len() - MAC_LEN);
+        let frame_len = ((buf.len() + MAC_LEN) as u32).to_le_bytes();
+        let expected = hmac_sha256(&self.recv_mac_key, &[&seq.to_le_bytes(), &frame_len, buf])?;
+        if !memcmp::eq(&expected, mac) {
+            return Err(Error::InvalidFrame);
+        }
+        let iv = &buf[..16];
+        let mut decrypted = symm::decrypt(Cipher::aes_256_cbc(), &self.key, Some(iv), &buf[16..])?;
+        match decrypted.pop() {
+            Some(kind) => Ok((kind, decrypted)),
+            None => Err(Error::InvalidFrame),
+        }
+    }
+
+    /// Enables or disables heartbeats
+    ///
+    /// When enabled, ping frame is sent every `interval` and the peer answers with pong frame.
+    /// Heartbeats are only processed during [read](struct.TcpStream.html#method.read) calls on both sides.
+    /// # Arguments
+    ///
+    /// * `interval`
+    ///     * `Some(Duration)` - Interval between pings
+    ///     * `None` - Heartbeats are disabled
+    /// * `max_missed` - Number of consecutive unanswered pings after which [read](struct.TcpStream.html#method.read) returns [PeerTimeout](enum.Error.html#variant.PeerTimeout)
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>, max_missed: u32) {
+        self.heartbeat = interval;
+        self.max_missed_pings = max_missed;
+        self.missed_pings = 0;
+        self.pending_ping = None;
+        self.last_ping = Instant::now();
+    }
+
+    /// Gets the heartbeat interval
+    pub fn heartbeat(&self) -> Option<Duration> {
+        self.heartbeat
+    }
+
+    /// Returns round-trip time measured by the last answered ping
+    ///
+    /// # Returns
+    /// `Some(Duration)` or `None` if no ping was answered yet
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    fn heartbeat_tick(&mut self) -> Result<(), Error> {
+        let interval = match self.heartbeat {
+            None => return Ok(()),
+            Some(_) if self.local_closed => return Ok(()),
+            Some(interval) => interval,
+        };
+
+        if let Some((_, sent)) = self.pending_ping {
+            if sent.elapsed() < interval {
+                return Ok(());
+            }
+            self.pending_ping = None;
+            self.missed_pings += 1;
+            if self.missed_pings >= self.max_missed_pings {
+                return Err(Error::PeerTimeout);
+            }
+        }
+
+        if self.last_ping.elapsed() >= interval {
+            self.ping_seq = self.ping_seq.wrapping_add(1);
+            let now = Instant::now();
+            self.last_ping = now;
+            self.pending_ping = Some((self.ping_seq, now));
+            self.write_frame(FRAME_PING, &self.ping_seq.to_le_bytes())?;
         }
 
         Ok(())
     }
 
+    fn handle_pong(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let seq = match payload.try_into() {
+            Ok(seq) => u64::from_le_bytes(seq),
+            Err(_) => return Err(Error::InvalidFrame),
+        };
+        if let Some((pending_seq, sent)) = self.pending_ping {
+            if pending_seq == seq {
+                self.rtt = Some(sent.elapsed());
+                self.pending_ping = None;
+            }
+        }
+        Ok(())
+    }
+
+    // Time in milliseconds until next heartbeat action, -1 if heartbeats are disabled
+    fn heartbeat_timeout(&self) -> i32 {
+        let interval = match self.heartbeat {
+            None => return -1,
+            Some(interval) => interval,
+        };
+        let since = match self.pending_ping {
+            Some((_, sent)) => sent,
+            None => self.last_ping,
+        };
+        interval.saturating_sub(since.elapsed()).as_millis() as i32 + 1
+    }
+
     /// Blocks the thread until connection is ready to read and write messages
     pub fn wait_until_ready(&mut self) -> Result<(), Error> {
         while !self.get_ready()? {
@@ -480,103 +1171,180 @@ impl TcpStream {
     /// `true` if all pending operations were flushed, `false` if there are more operations to flush
     pub fn flush(&mut self) -> Result<bool, Error> {
         while poll_timeout(self, EV_POLLOUT, 0) {
-            if self.write_buffer.is_empty() {
+            if self.write_buffer.is_empty() && !self.schedule_channel_frame()? {
                 return Ok(true);
             }
-            let bytes_written = self.socket.write(self.write_buffer.peek());
-            if bytes_written.is_err() {
-                let err = bytes_written.as_ref().err().unwrap();
-                if err.kind() != ErrorKind::WouldBlock {
-                    return Err(TcpError(bytes_written.err().unwrap()));
-                }
-            } else if bytes_written.as_ref().unwrap() == &0 {
-                return Err(Error::ConnectionClosed);
+            if self.write_buffer.is_empty() {
+                continue;
             }
+            // Queued buffers are coalesced into one syscall
+            let bytes_written = self.socket.write_vectored(&self.write_buffer.peek_vectored());
+            let bytes_written = Self::check_written(bytes_written)?;
+            self.write_buffer.advance(bytes_written);
+        }
+        Ok(self.write_buffer.is_empty() && !self.has_pending_channel_frames())
+    }
+
+    // Converts result of a socket write to number of written bytes, `WouldBlock` means 0
+    fn check_written(bytes_written: io::Result<usize>) -> Result<usize, Error> {
+        match bytes_written {
+            Ok(0) => Err(Error::ConnectionClosed),
+            Ok(n) => Ok(n),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(TcpError(err)),
+        }
+    }
+
+    // Writes data that are already length-prefixed
+    fn write_encoded(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if !self.write_buffer.is_empty() {
+            // Preserve ordering of pending data
+            self.write_buffer.enqueue(buf);
+            return Ok(());
+        }
 
-            let bytes_written = bytes_written.unwrap_or(0);
+        let bytes_written = Self::check_written(self.socket.write(buf))?;
+        if bytes_written != buf.len() {
+            self.write_buffer.enqueue(&buf[bytes_written..]);
+        }
+        Ok(())
+    }
+
+    // Same as write_encoded, but unwritten data are queued without copying
+    fn write_encoded_vec(&mut self, buf: Vec<u8>) -> Result<(), Error> {
+        if !self.write_buffer.is_empty() {
+            self.write_buffer.enqueue_vec(buf);
+            return Ok(());
+        }
+
+        let bytes_written = Self::check_written(self.socket.write(&buf))?;
+        if bytes_written != buf.len() {
+            self.write_buffer.enqueue_vec(buf);
             self.write_buffer.advance(bytes_written);
         }
-        Ok(self.write_buffer.is_empty())
+        Ok(())
     }
 
     fn write_raw(&mut self, msg: &[u8]) -> Result<(), Error> {
         let length = msg.len() as u32;
         let length_bytes = length.to_le_bytes();
-        let bytes_written = self.socket.write(&length_bytes);
-        if bytes_written.is_err() {
-            let err = bytes_written.as_ref().err().unwrap();
-            if err.kind() != ErrorKind::WouldBlock {
-                return Err(TcpError(bytes_written.err().unwrap()));
-            }
-        } else if bytes_written.as_ref().unwrap() == &0 {
-            return Err(Error::ConnectionClosed);
+        if !self.write_buffer.is_empty() {
+            // Preserve ordering of pending data
+            self.write_buffer.enqueue(&length_bytes);
+            self.write_buffer.enqueue(msg);
+            return Ok(());
         }
 
-        let bytes_written = bytes_written.unwrap_or(0);
-        if bytes_written != 4 {
+        let bufs = [IoSlice::new(&length_bytes), IoSlice::new(msg)];
+        let bytes_written = Self::check_written(self.socket.write_vectored(&bufs))?;
+        if bytes_written < 4 {
             self.write_buffer.enqueue(&length_bytes[bytes_written..]);
-        }
-        let bytes_written = self.socket.write(msg);
-
-        if bytes_written.is_err() {
-            let err = bytes_written.as_ref().err().unwrap();
-            if err.kind() != ErrorKind::WouldBlock {
-                return Err(TcpError(bytes_written.err().unwrap()));
-            }
-        } else if bytes_written.as_ref().unwrap() == &0 {
-            return Err(Error::ConnectionClosed);
-        }
-
-        let bytes_written = bytes_written.unwrap_or(0);
-        if bytes_written != msg.len() {
-            self.write_buffer.enqueue(&msg[bytes_written..]);
+            self.write_buffer.enqueue(msg);
+        } else if bytes_written - 4 != msg.len() {
+            self.write_buffer.enqueue(&msg[bytes_written - 4..]);
         }
 
         Ok(())
     }
 
     fn read_raw(&mut self) -> Result<Option<Vec<u8>>, Error> {
-        if self.read_buffer.len() < 4 {
-            let start = self.read_buffer.len();
-            self.read_buffer.resize(4, 0);
-            let bytes_read = try_io!(self.socket.read(&mut self.read_buffer[start..]), || {
-                self.read_buffer.resize(start, 0);
-            });
-
-            if bytes_read == 0 {
-                return Err(Error::ConnectionClosed);
-            }
+        Ok(self
+            .next_frame()?
+            .map(|(start, end)| self.read_buffer[start..end].to_vec()))
+    }
 
-            self.read_buffer.resize(start + bytes_read, 0);
-            if self.read_buffer.len() != 4 {
+    // Returns range of the next complete frame in the read buffer
+    //
+    // Data are read from the socket in large chunks, so one syscall may provide many frames.
+    // The range is valid until the next call.
+    fn next_frame(&mut self) -> Result<Option<(usize, usize)>, Error> {
+        // Skip rest of an oversized frame
+        while self.discard > 0 {
+            let buffered = (self.read_end - self.read_start).min(self.discard);
+            self.read_start += buffered;
+            self.discard -= buffered;
+            if self.discard == 0 {
+                break;
+            }
+            if !self.fill_read_buffer(0)? {
                 return Ok(None);
             }
         }
 
-        let len = u32::from_le_bytes(self.read_buffer[..4].try_into().unwrap()) as usize;
-        if len > MSG_SIZE_LIMIT {
-            self.read_buffer.clear();
-            return Err(Error::SizeLimitExceeded);
-        }
-
-        let start = self.read_buffer.len();
-        self.read_buffer.resize(4 + len, 0);
-        let bytes_read = try_io!(self.socket.read(&mut self.read_buffer[start..]), || {
-            self.read_buffer.resize(start, 0);
-        });
+        loop {
+            let available = self.read_end - self.read_start;
+            if available >= 4 {
+                let len_bytes = &self.read_buffer[self.read_start..self.read_start + 4];
+                let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+                let limit = if self.state == Ready {
+                    self.size_limit
+                        .max(MAX_CHUNK_FRAME)
+                        .saturating_add(FRAME_OVERHEAD)
+                } else {
+                    self.handshake_size_limit
+                };
+                if len > limit {
+                    let buffered = (available - 4).min(len);
+                    self.read_start += 4 + buffered;
+                    if self.state == Ready {
+                        self.discard = len - buffered;
+                        // Skipped frame still takes a sequence number
+                        self.recv_seq += 1;
+                    } else {
+                        self.socket.shutdown(Shutdown::Both)?;
+                    }
+                    return Err(Error::SizeLimitExceeded);
+                }
 
-        if bytes_read == 0 {
-            return Err(Error::ConnectionClosed);
+                if available >= 4 + len {
+                    let start = self.read_start + 4;
+                    self.read_start = start + len;
+                    return Ok(Some((start, start + len)));
+                }
+                if !self.fill_read_buffer(4 + len)? {
+                    return Ok(None);
+                }
+            } else if !self.fill_read_buffer(4)? {
+                return Ok(None);
+            }
         }
+    }
 
-        self.read_buffer.resize(start + bytes_read, 0);
-        if self.read_buffer.len() == len + 4 {
-            let result = self.read_buffer[4..].to_vec();
-            self.read_buffer.clear();
-            return Ok(Some(result));
+    // Reads available data from the socket, making room for at least `needed` bytes of the current frame
+    //
+    // Returns `false` if no data are available
+    fn fill_read_buffer(&mut self, needed: usize) -> Result<bool, Error> {
+        if self.read_start == self.read_end {
+            self.read_start = 0;
+            self.read_end = 0;
+            if self.read_buffer.len() > READ_BUFFER_RETAIN && needed <= READ_CHUNK {
+                self.read_buffer = vec![0; READ_CHUNK];
+            }
+        }
+        if self.read_buffer.len() - self.read_start < needed
+            || self.read_end == self.read_buffer.len()
+        {
+            // Move unparsed data to the front
+            self.read_buffer
+                .copy_within(self.read_start..self.read_end, 0);
+            self.read_end -= self.read_start;
+            self.read_start = 0;
+        }
+        let size = needed.max(READ_CHUNK);
+        if self.read_buffer.len() < size {
+            self.read_buffer.resize(size, 0);
         }
 
-        Ok(None)
+        let bytes_read = match self.socket.read(&mut self.read_buffer[self.read_end..]) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(Error::TcpError(e)),
+        };
+        if bytes_read == 0 {
+            return Err(Error::Truncated);
+        }
+        self.read_end += bytes_read;
+        Ok(true)
     }
 
     /// Sets the value of `TCP_NODELAY`
@@ -590,6 +1358,72 @@ impl TcpStream {
         let nodelay = self.socket.nodelay()?;
         Ok(nodelay)
     }
+
+    /// Sets size limit of received messages
+    ///
+    /// Messages exceeding the limit are skipped and [read](struct.TcpStream.html#method.read) returns [SizeLimitExceeded](enum.Error.html#variant.SizeLimitExceeded).
+    /// Chunks of [streams](struct.TcpStream.html#method.write_stream) are accepted regardless of the limit,
+    /// so frames up to 64 KiB are always received before they are checked.
+    /// Default is [DEFAULT_SIZE_LIMIT](constant.DEFAULT_SIZE_LIMIT.html), it can be raised up to 4 GiB for trusted peers.
+    pub fn set_size_limit(&mut self, limit: usize) {
+        self.size_limit = limit;
+    }
+
+    /// Gets size limit of received messages
+    pub fn size_limit(&self) -> usize {
+        self.size_limit
+    }
+
+    /// Sets size limit of messages received during connection initialization
+    ///
+    /// Connection is closed when the limit is exceeded.
+    /// Default is [DEFAULT_HANDSHAKE_SIZE_LIMIT](constant.DEFAULT_HANDSHAKE_SIZE_LIMIT.html)
+    pub fn set_handshake_size_limit(&mut self, limit: usize) {
+        self.handshake_size_limit = limit;
+    }
+
+    /// Gets size limit of messages received during connection initialization
+    pub fn handshake_size_limit(&self) -> usize {
+        self.handshake_size_limit
+    }
+
+    /// Sets high-water mark of pending outgoing data
+    ///
+    /// When the number of [pending bytes](struct.TcpStream.html#method.pending_bytes) reaches the limit, [write](struct.TcpStream.html#method.write)
+    /// and [write_channel](struct.TcpStream.html#method.write_channel) return [Backpressure](enum.Error.html#variant.Backpressure)
+    /// and [write_blocking](struct.TcpStream.html#method.write_blocking) blocks.
+    /// A message is accepted whenever pending data are below the limit or nothing is pending, so the buffer may exceed the limit by one message.
+    /// Default is `None` (unlimited)
+    ///
+    /// # Panics
+    /// Panics if the limit is `Some(0)`
+    pub fn set_write_limit(&mut self, limit: Option<usize>) {
+        assert_ne!(limit, Some(0), "Write limit must be greater than zero");
+        self.write_limit = limit;
+    }
+
+    /// Gets high-water mark of pending outgoing data
+    pub fn write_limit(&self) -> Option<usize> {
+        self.write_limit
+    }
+
+    /// Returns number of bytes written but not yet sent to the socket
+    ///
+    /// Includes messages queued on channels
+    pub fn pending_bytes(&self) -> usize {
+        self.write_buffer.len() + self.pending_channel_bytes()
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Result<[u8; 32], ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+    let mut mac = [0; 32];
+    signer.sign(&mut mac)?;
+    Ok(mac)
 }
 
 #[cfg(unix)]
@@ -609,6 +1443,7 @@ impl AsRawSocket for TcpStream {
 struct DequeueBuffer {
     buffers: VecDeque<Vec<u8>>,
     start: usize,
+    len: usize,
 }
 
 impl DequeueBuffer {
@@ -616,20 +1451,44 @@ impl DequeueBuffer {
         DequeueBuffer {
             buffers: VecDeque::new(),
             start: 0,
+            len: 0,
         }
     }
 
     fn enqueue(&mut self, buf: &[u8]) {
-        self.buffers.push_back(buf.to_vec());
+        self.enqueue_vec(buf.to_vec());
     }
 
-    fn peek(&self) -> &[u8] {
-        &self.buffers[0][self.start..]
+    fn enqueue_vec(&mut self, buf: Vec<u8>) {
+        if buf.is_empty() {
+            return;
+        }
+        self.len += buf.len();
+        self.buffers.push_back(buf);
     }
 
-    fn advance(&mut self, n: usize) {
-        self.start += n;
-        if self.start == self.buffers[0].len() {
+    // Returns slices of first queued buffers for vectored write
+    fn peek_vectored(&self) -> Vec<IoSlice<'_>> {
+        let mut slices = Vec::with_capacity(self.buffers.len().min(MAX_WRITE_SLICES));
+        for (i, buf) in self.buffers.iter().take(MAX_WRITE_SLICES).enumerate() {
+            if i == 0 {
+                slices.push(IoSlice::new(&buf[self.start..]));
+            } else {
+                slices.push(IoSlice::new(buf));
+            }
+        }
+        slices
+    }
+
+    fn advance(&mut self, mut n: usize) {
+        self.len -= n;
+        while n > 0 {
+            let remaining = self.buffers[0].len() - self.start;
+            if n < remaining {
+                self.start += n;
+                return;
+            }
+            n -= remaining;
             self.buffers.pop_front();
             self.start = 0;
         }
@@ -638,17 +1497,52 @@ impl DequeueBuffer {
     fn is_empty(&self) -> bool {
         self.buffers.is_empty()
     }
+
+    fn len(&self) -> usize {
+        self.len
+    }
 }
 
 /// Message to be transmitted using [write](struct.TcpStream.html#method.write) or [read](struct.TcpStream.html#method.read)
 pub struct Message {
     buffer: Vec<u8>,
     read_pos: usize,
+    // Bounds of a nested message read by `read_message`
+    read_start: usize,
+    read_end: Option<usize>,
+    // Number of sequences, maps and nested messages being decoded
+    depth: usize,
+    compressible: bool,
+    tagged: bool,
 }
 
 /// Error occurred when encoding or decoding message
 pub enum MessageError {
     UnexpectedEnd,
+
+    /// String is not valid UTF-8
+    InvalidUtf8,
+
+    /// Value is out of range of its type, e.g. bool other than 0 or 1
+    InvalidValue,
+
+    /// Error reported by a serde implementation
+    Custom(String),
+
+    /// Varint does not fit into its type
+    VarintOverflow,
+
+    /// Sequence or map has more elements than allowed, or values are nested too deeply
+    ///
+    /// Sequences, maps and nested messages may be nested at most 64 levels deep when decoding.
+    LimitExceeded,
+
+    /// Value in a [tagged](struct.Message.html#method.new_tagged) message has different type than requested
+    TypeMismatch {
+        expected: ValueType,
+        found: ValueType,
+        offset: usize,
+    },
 }
 
 impl fmt::Debug for MessageError {
@@ -657,16 +1551,44 @@ impl fmt::Debug for MessageError {
             UnexpectedEnd => {
                 return f.write_str("Message has ended unexpectedly.");
             }
+            MessageError::InvalidUtf8 => f.write_str("Message contains invalid UTF-8."),
+            MessageError::InvalidValue => f.write_str("Message contains invalid value."),
+            MessageError::Custom(msg) => f.write_str(msg),
+            MessageError::VarintOverflow => f.write_str("Varint does not fit into its type."),
+            MessageError::LimitExceeded => {
+                f.write_str("Message contains too many elements or too deeply nested values.")
+            }
+            MessageError::TypeMismatch {
+                expected,
+                found,
+                offset,
+            } => f.write_fmt(format_args!(
+                "Expected {} but found {} at offset {}.",
+                expected, found, offset
+            )),
         }
     }
 }
 
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for MessageError {}
+
 impl Message {
     /// Creates a new empty message
     pub fn new() -> Message {
         Message {
             buffer: Vec::new(),
             read_pos: 0,
+            read_start: 0,
+            read_end: None,
+            depth: 0,
+            compressible: true,
+            tagged: false,
         }
     }
 
@@ -674,72 +1596,152 @@ impl Message {
         Message {
             buffer,
             read_pos: 0,
+            read_start: 0,
+            read_end: None,
+            depth: 0,
+            compressible: true,
+            tagged: false,
         }
     }
 
+    // Appends a message wrapped by another protocol, e.g. RPC arguments, preserving its tagged flag
+    pub(crate) fn write_nested(&mut self, msg: &Message) {
+        self.write_bool(msg.tagged);
+        self.write_buffer(msg.as_bytes());
+    }
+
+    // Reads a message written by `write_nested`
+    pub(crate) fn read_nested(&mut self) -> Result<Message, MessageError> {
+        let tagged = self.read_bool()?;
+        let mut msg = Message::from_buffer(self.read_buffer()?.to_vec());
+        msg.tagged = tagged;
+        Ok(msg)
+    }
+
+    /// Sets whether the message may be compressed
+    ///
+    /// Disable for data that is already compressed or encoded, default is `true`.
+    /// See [set_compression](struct.TcpStream.html#method.set_compression)
+    pub fn set_compressible(&mut self, compressible: bool) {
+        self.compressible = compressible;
+    }
+
+    /// Checks whether the message may be compressed
+    pub fn is_compressible(&self) -> bool {
+        self.compressible
+    }
+
     /// Appends 8-bit unsigned integer to the message
     pub fn write_u8(&mut self, n: u8) {
+        self.write_tag(ValueType::U8);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 8-bit signed integer to the message
     pub fn write_i8(&mut self, n: i8) {
+        self.write_tag(ValueType::I8);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 16-bit unsigned integer to the message
     pub fn write_u16(&mut self, n: u16) {
+        self.write_tag(ValueType::U16);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 16-bit signed integer to the message
     pub fn write_i16(&mut self, n: i16) {
+        self.write_tag(ValueType::I16);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 32-bit unsigned integer to the message
     pub fn write_u32(&mut self, n: u32) {
+        self.write_tag(ValueType::U32);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 32-bit signed integer to the message
     pub fn write_i32(&mut self, n: i32) {
+        self.write_tag(ValueType::I32);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 64-bit unsigned integer to the message
     pub fn write_u64(&mut self, n: u64) {
+        self.write_tag(ValueType::U64);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 64-bit signed integer to the message
     pub fn write_i64(&mut self, n: i64) {
+        self.write_tag(ValueType::I64);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 128-bit unsigned integer to the message
     pub fn write_u128(&mut self, n: u128) {
+        self.write_tag(ValueType::U128);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 128-bit signed integer to the message
     pub fn write_i128(&mut self, n: i128) {
+        self.write_tag(ValueType::I128);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 32-bit float to the message
     pub fn write_f32(&mut self, n: f32) {
+        self.write_tag(ValueType::F32);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends 64-bit float to the message
     pub fn write_f64(&mut self, n: f64) {
+        self.write_tag(ValueType::F64);
         self.buffer.extend_from_slice(&n.to_le_bytes());
     }
 
     /// Appends buffer to the message
+    ///
+    /// # Panics
+    /// Panics if the buffer is longer than `u32::MAX` bytes
     pub fn write_buffer(&mut self, buf: &[u8]) {
-        self.write_u32(buf.len() as u32);
+        self.write_tag(ValueType::Buffer);
+        self.put_buffer(buf);
+    }
+
+    /// Appends string to the message
+    ///
+    /// Encoded as UTF-8 [buffer](struct.Message.html#method.write_buffer)
+    /// # Panics
+    /// Panics if the string is longer than `u32::MAX` bytes
+    pub fn write_str(&mut self, s: &str) {
+        self.write_tag(ValueType::Str);
+        self.put_buffer(s.as_bytes());
+    }
+
+    /// Appends bool to the message
+    ///
+    /// Encoded as `u8` 0 or 1
+    pub fn write_bool(&mut self, b: bool) {
+        self.write_tag(ValueType::Bool);
+        self.buffer.push(b as u8);
+    }
+
+    /// Appends char to the message
+    ///
+    /// Encoded as `u32` code point
+    pub fn write_char(&mut self, c: char) {
+        self.write_tag(ValueType::Char);
+        self.buffer.extend_from_slice(&(c as u32).to_le_bytes());
+    }
+
+    // Length-prefixed bytes without type tag
+    fn put_buffer(&mut self, buf: &[u8]) {
+        assert!(buf.len() <= u32::MAX as usize, "Buffer is longer than u32::MAX bytes");
+        self.buffer.extend_from_slice(&(buf.len() as u32).to_le_bytes());
         self.buffer.extend_from_slice(buf);
     }
 
@@ -747,7 +1749,8 @@ impl Message {
     /// # Returns
     /// `u8` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_u8(&mut self) -> Result<u8, MessageError> {
-        if self.buffer.len() - self.read_pos < 1 {
+        self.read_tag(ValueType::U8)?;
+        if self.remaining() < 1 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 1];
@@ -759,7 +1762,8 @@ impl Message {
     /// # Returns
     /// `i8` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_i8(&mut self) -> Result<i8, MessageError> {
-        if self.buffer.len() - self.read_pos < 1 {
+        self.read_tag(ValueType::I8)?;
+        if self.remaining() < 1 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 1];
@@ -771,7 +1775,8 @@ impl Message {
     /// # Returns
     /// `u16` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_u16(&mut self) -> Result<u16, MessageError> {
-        if self.buffer.len() - self.read_pos < 2 {
+        self.read_tag(ValueType::U16)?;
+        if self.remaining() < 2 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 2];
@@ -783,7 +1788,8 @@ impl Message {
     /// # Returns
     /// `i16` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_i16(&mut self) -> Result<i16, MessageError> {
-        if self.buffer.len() - self.read_pos < 2 {
+        self.read_tag(ValueType::I16)?;
+        if self.remaining() < 2 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 2];
@@ -795,7 +1801,8 @@ impl Message {
     /// # Returns
     /// `u32` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_u32(&mut self) -> Result<u32, MessageError> {
-        if self.buffer.len() - self.read_pos < 4 {
+        self.read_tag(ValueType::U32)?;
+        if self.remaining() < 4 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 4];
@@ -807,7 +1814,8 @@ impl Message {
     /// # Returns
     /// `i32` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_i32(&mut self) -> Result<i32, MessageError> {
-        if self.buffer.len() - self.read_pos < 4 {
+        self.read_tag(ValueType::I32)?;
+        if self.remaining() < 4 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 4];
@@ -819,7 +1827,8 @@ impl Message {
     /// # Returns
     /// `u64` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_u64(&mut self) -> Result<u64, MessageError> {
-        if self.buffer.len() - self.read_pos < 8 {
+        self.read_tag(ValueType::U64)?;
+        if self.remaining() < 8 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 8];
@@ -831,7 +1840,8 @@ impl Message {
     /// # Returns
     /// `i64` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_i64(&mut self) -> Result<i64, MessageError> {
-        if self.buffer.len() - self.read_pos < 8 {
+        self.read_tag(ValueType::I64)?;
+        if self.remaining() < 8 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 8];
@@ -843,7 +1853,8 @@ impl Message {
     /// # Returns
     /// `u128` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_u128(&mut self) -> Result<u128, MessageError> {
-        if self.buffer.len() - self.read_pos < 16 {
+        self.read_tag(ValueType::U128)?;
+        if self.remaining() < 16 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 16];
@@ -855,7 +1866,8 @@ impl Message {
     /// # Returns
     /// `i128` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_i128(&mut self) -> Result<i128, MessageError> {
-        if self.buffer.len() - self.read_pos < 16 {
+        self.read_tag(ValueType::I128)?;
+        if self.remaining() < 16 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 16];
@@ -867,7 +1879,8 @@ impl Message {
     /// # Returns
     /// `f32` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_f32(&mut self) -> Result<f32, MessageError> {
-        if self.buffer.len() - self.read_pos < 4 {
+        self.read_tag(ValueType::F32)?;
+        if self.remaining() < 4 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 4];
@@ -879,7 +1892,8 @@ impl Message {
     /// # Returns
     /// `f64` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_f64(&mut self) -> Result<f64, MessageError> {
-        if self.buffer.len() - self.read_pos < 4 {
+        self.read_tag(ValueType::F64)?;
+        if self.remaining() < 8 {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + 8];
@@ -891,8 +1905,52 @@ impl Message {
     /// # Returns
     /// `&[u8]` or [MessageError](enum.MessageError.html) if reading failed
     pub fn read_buffer(&mut self) -> Result<&[u8], MessageError> {
-        let len = self.read_u32()? as usize;
-        if self.buffer.len() - self.read_pos < len {
+        self.read_tag(ValueType::Buffer)?;
+        self.take_buffer()
+    }
+
+    /// Reads string and moves read cursor
+    /// # Returns
+    /// `&str` or [MessageError](enum.MessageError.html) if reading failed or the string is not valid UTF-8
+    pub fn read_str(&mut self) -> Result<&str, MessageError> {
+        self.read_tag(ValueType::Str)?;
+        std::str::from_utf8(self.take_buffer()?).map_err(|_| MessageError::InvalidUtf8)
+    }
+
+    /// Reads bool and moves read cursor
+    /// # Returns
+    /// `bool` or [MessageError](enum.MessageError.html) if reading failed or the value is not 0 or 1
+    pub fn read_bool(&mut self) -> Result<bool, MessageError> {
+        self.read_tag(ValueType::Bool)?;
+        match self.take::<1>()? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(MessageError::InvalidValue),
+        }
+    }
+
+    /// Reads char and moves read cursor
+    /// # Returns
+    /// `char` or [MessageError](enum.MessageError.html) if reading failed or the value is not a valid code point
+    pub fn read_char(&mut self) -> Result<char, MessageError> {
+        self.read_tag(ValueType::Char)?;
+        std::char::from_u32(u32::from_le_bytes(self.take()?)).ok_or(MessageError::InvalidValue)
+    }
+
+    // Reads bytes without type tag
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], MessageError> {
+        if self.remaining() < N {
+            return Err(UnexpectedEnd);
+        }
+        let bytes = self.buffer[self.read_pos..self.read_pos + N].try_into().unwrap();
+        self.read_pos += N;
+        Ok(bytes)
+    }
+
+    // Reads length-prefixed bytes without type tag
+    fn take_buffer(&mut self) -> Result<&[u8], MessageError> {
+        let len = u32::from_le_bytes(self.take()?) as usize;
+        if self.remaining() < len {
             return Err(UnexpectedEnd);
         }
         let slice = &self.buffer[self.read_pos..self.read_pos + len];
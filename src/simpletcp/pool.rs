@@ -0,0 +1,115 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use super::event::{Acceptor, EventLoop};
+use super::{Error, Handler, TcpServer, TcpStream};
+use crate::utils::{get_fd, poll, EV_POLLIN};
+
+enum Job {
+    Client(u64, Box<TcpStream>),
+    AcceptError(Error),
+}
+
+struct Worker {
+    sender: Sender<Job>,
+    // Wakes the worker from poll when a client is sent to it
+    waker: net::TcpStream,
+}
+
+impl Worker {
+    fn spawn<H: Handler + Send + 'static>(mut handler: H) -> Result<Self, Error> {
+        let listener = net::TcpListener::bind("127.0.0.1:0")?;
+        let waker = net::TcpStream::connect(listener.local_addr()?)?;
+        let (mut wake, _) = listener.accept()?;
+        waker.set_nonblocking(true)?;
+        wake.set_nonblocking(true)?;
+
+        let (sender, receiver): (_, Receiver<Job>) = channel();
+        thread::spawn(move || {
+            let mut event_loop = EventLoop::new();
+            let mut buf = [0; 64];
+            loop {
+                while let Ok(n) = wake.read(&mut buf) {
+                    if n == 0 {
+                        // Pool has been dropped
+                        return;
+                    }
+                }
+                while let Ok(job) = receiver.try_recv() {
+                    match job {
+                        Job::Client(id, stream) => event_loop.add(id, *stream, &mut handler),
+                        Job::AcceptError(err) => handler.on_accept_error(err),
+                    }
+                }
+                event_loop.process(&mut handler);
+                event_loop.wait(Some(get_fd(&wake)), -1);
+            }
+        });
+
+        Ok(Worker { sender, waker })
+    }
+
+    fn send(&mut self, job: Job) {
+        if self.sender.send(job).is_ok() {
+            // Full buffer means a wake-up is already pending
+            if let Err(err) = self.waker.write(&[0]) {
+                debug_assert_eq!(err.kind(), ErrorKind::WouldBlock);
+            }
+        }
+    }
+}
+
+impl TcpServer {
+    /// Runs an event loop on a pool of worker threads
+    ///
+    /// The calling thread only accepts clients, connection initialization and message handling are done by the workers.
+    /// Every client stays on one worker, so its messages are handled in order.
+    /// Clients are assigned to workers in turns.
+    /// Errors of accepting clients are reported to the handler of the worker next in turn.
+    /// See [run](struct.TcpServer.html#method.run)
+    /// # Arguments
+    ///
+    /// * `workers` - Number of worker threads
+    /// * `factory` - Creates [Handler](trait.Handler.html) for each worker
+    /// # Returns
+    /// Returns only if a worker could not be started
+    pub fn run_pool<H, F>(&self, workers: usize, mut factory: F) -> Error
+    where
+        H: Handler + Send + 'static,
+        F: FnMut() -> H,
+    {
+        assert!(workers > 0, "run_pool(): workers must be greater than zero");
+        let mut pool = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            match Worker::spawn(factory()) {
+                Ok(worker) => pool.push(worker),
+                Err(err) => return err,
+            }
+        }
+
+        let mut acceptor = Acceptor::new();
+        let mut next_id = 0;
+        loop {
+            acceptor.accept(self, |result| {
+                let worker = &mut pool[(next_id % workers as u64) as usize];
+                match result {
+                    Ok(stream) => {
+                        worker.send(Job::Client(next_id, Box::new(stream)));
+                        next_id += 1;
+                    }
+                    Err(err) => worker.send(Job::AcceptError(err)),
+                }
+            });
+            let paused = acceptor.timeout();
+            if paused < 0 {
+                poll(self, EV_POLLIN);
+            } else {
+                sleep(Duration::from_millis(paused as u64));
+            }
+        }
+    }
+}
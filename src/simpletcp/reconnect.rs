@@ -0,0 +1,413 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rand::prelude::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::{Error, Message, TcpStream};
+use crate::utils::{poll_timeout, EV_POLLIN, EV_POLLOUT};
+
+/// Default timeout of one connection attempt of [ReconnectingStream](struct.ReconnectingStream.html) (1 second)
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Maximal number of events kept for next_event, oldest events are dropped
+const MAX_EVENTS: usize = 64;
+
+/// Event reported by [ReconnectingStream](struct.ReconnectingStream.html)
+#[derive(Debug)]
+pub enum ReconnectEvent {
+    /// Connection was established and is ready to send and receive data
+    Connected,
+
+    /// Connection was lost, reconnection is scheduled
+    Disconnected(Error),
+}
+
+/// Client stream that automatically reconnects
+///
+/// Wraps [TcpStream](struct.TcpStream.html) and re-dials the server with exponential backoff and jitter when the connection is lost.
+/// Server fingerprint is pinned on the first successful handshake (or set with [set_fingerprint](struct.ReconnectingStream.html#method.set_fingerprint))
+/// and verified on every reconnection.
+///
+/// Messages written while disconnected are queued and sent once the connection is ready.
+/// Messages that were already passed to the underlying [TcpStream](struct.TcpStream.html) may be lost when the connection drops.
+///
+/// Connection attempts are made by calls of the other methods when the backoff expires, such call blocks until the TCP connection
+/// is established or the [connect timeout](struct.ReconnectingStream.html#method.set_connect_timeout) elapses.
+/// An attempt whose handshake is not finished within the connect timeout is treated as failed.
+/// If the server has multiple addresses, each attempt uses the next one.
+pub struct ReconnectingStream {
+    addrs: Vec<SocketAddr>,
+    stream: Option<TcpStream>,
+    ready: bool,
+    fingerprint: Option<[u8; 32]>,
+    queue: VecDeque<Message>,
+    queue_limit: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    attempts: u32,
+    next_attempt: Instant,
+    handshake_deadline: Instant,
+    connect_timeout: Duration,
+    heartbeat: Option<Duration>,
+    max_missed_pings: u32,
+    write_limit: Option<usize>,
+    events: VecDeque<ReconnectEvent>,
+    rand: StdRng,
+}
+
+impl ReconnectingStream {
+    /// Creates new ReconnectingStream and tries to connect
+    ///
+    /// Failure to connect is not an error, the connection is retried later.
+    /// The first attempt uses [DEFAULT_CONNECT_TIMEOUT](constant.DEFAULT_CONNECT_TIMEOUT.html).
+    /// # Arguments
+    ///
+    /// * `addr` - Address of remote [TcpServer](struct.TcpServer.html)
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(Error::TcpError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to connect to",
+            )));
+        }
+
+        let mut stream = Self {
+            addrs,
+            stream: None,
+            ready: false,
+            fingerprint: None,
+            queue: VecDeque::new(),
+            queue_limit: 1024,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            attempts: 0,
+            next_attempt: Instant::now(),
+            handshake_deadline: Instant::now(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            heartbeat: None,
+            max_missed_pings: 0,
+            write_limit: None,
+            events: VecDeque::new(),
+            rand: StdRng::from_entropy(),
+        };
+        stream.poll_connection();
+        Ok(stream)
+    }
+
+    /// Sets expected server key fingerprint
+    ///
+    /// # Arguments
+    ///
+    /// * `fingerprint`
+    ///     * `Some([u8; 32])` - Connections to servers with different fingerprint are rejected
+    ///     * `None` - Fingerprint is pinned on the next successful handshake
+    pub fn set_fingerprint(&mut self, fingerprint: Option<[u8; 32]>) {
+        self.fingerprint = fingerprint;
+    }
+
+    /// Returns pinned server key fingerprint
+    pub fn fingerprint(&self) -> Option<[u8; 32]> {
+        self.fingerprint
+    }
+
+    /// Sets reconnection backoff
+    ///
+    /// Delay before n-th attempt is `initial * 2^n` capped at `max`, randomly shortened by up to a half
+    /// # Arguments
+    ///
+    /// * `initial` - Delay before first reconnection attempt
+    /// * `max` - Maximal delay between attempts
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+    }
+
+    /// Sets timeout of one connection attempt
+    ///
+    /// Bounds both establishing the TCP connection and the handshake.
+    /// Default is [DEFAULT_CONNECT_TIMEOUT](constant.DEFAULT_CONNECT_TIMEOUT.html)
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Sets maximal number of messages queued while disconnected
+    pub fn set_queue_limit(&mut self, limit: usize) {
+        self.queue_limit = limit;
+    }
+
+    /// Returns number of queued messages
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Sets heartbeat for every new connection
+    ///
+    /// See [TcpStream::set_heartbeat](struct.TcpStream.html#method.set_heartbeat)
+    pub fn set_heartbeat(&mut self, interval: Option<Duration>, max_missed: u32) {
+        self.heartbeat = interval;
+        self.max_missed_pings = max_missed;
+        if let Some(stream) = &mut self.stream {
+            stream.set_heartbeat(interval, max_missed);
+        }
+    }
+
+    /// Sets high-water mark of pending outgoing data for every new connection
+    ///
+    /// See [TcpStream::set_write_limit](struct.TcpStream.html#method.set_write_limit)
+    ///
+    /// # Panics
+    /// Panics if the limit is `Some(0)`
+    pub fn set_write_limit(&mut self, limit: Option<usize>) {
+        assert_ne!(limit, Some(0), "Write limit must be greater than zero");
+        self.write_limit = limit;
+        if let Some(stream) = &mut self.stream {
+            stream.set_write_limit(limit);
+        }
+    }
+
+    /// Returns `true` if the connection is ready to send and receive data
+    pub fn is_connected(&self) -> bool {
+        self.ready
+    }
+
+    /// Returns next connection event
+    ///
+    /// Consecutive [Disconnected](enum.ReconnectEvent.html#variant.Disconnected) events are coalesced into the latest one
+    /// and only the last 64 events are kept.
+    /// # Returns
+    /// `Some(ReconnectEvent)` or `None` if there are no more events
+    pub fn next_event(&mut self) -> Option<ReconnectEvent> {
+        self.events.pop_front()
+    }
+
+    /// Writes a message
+    ///
+    /// If the connection is not ready, message is queued.
+    /// # Arguments
+    ///
+    /// * `msg` - Message to be sent
+    /// # Returns
+    /// [Backpressure](enum.Error.html#variant.Backpressure) if the queue is full
+    /// or the [write limit](struct.ReconnectingStream.html#method.set_write_limit) of the connection is reached
+    pub fn write(&mut self, msg: &Message) -> Result<(), Error> {
+        self.poll_connection();
+        if self.ready && self.queue.is_empty() {
+            let result = self.stream.as_mut().unwrap().write(msg);
+            match result {
+                Ok(()) => return Ok(()),
+                Err(Error::Backpressure) => return Err(Error::Backpressure),
+                Err(e) => self.disconnect(e),
+            }
+        }
+
+        if self.queue.len() >= self.queue_limit {
+            return Err(Error::Backpressure);
+        }
+        self.queue.push_back(msg.clone());
+        Ok(())
+    }
+
+    /// Reads a message non-blocking
+    ///
+    /// Also drives reconnection, so it should be called regularly
+    /// # Returns
+    /// Returns `Some(Message)` or `None` if no message has arrived or the connection is not ready
+    pub fn read(&mut self) -> Result<Option<Message>, Error> {
+        self.poll_connection();
+        if !self.ready {
+            return Ok(None);
+        }
+
+        let stream = self.stream.as_mut().unwrap();
+        let result = match stream.flush() {
+            Ok(_) => stream.read(),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(msg) => Ok(msg),
+            Err(e) => {
+                self.disconnect(e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads a message blocking with timeout
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Timeout in milliseconds
+    /// # Returns
+    /// Returns `Some(Message)` or `None` if reading timed out
+    pub fn read_timeout(&mut self, timeout: i32) -> Result<Option<Message>, Error> {
+        let time = Instant::now();
+        loop {
+            if let Some(msg) = self.read()? {
+                return Ok(Some(msg));
+            }
+
+            let elapsed = time.elapsed().as_millis() as i32;
+            if timeout < elapsed {
+                return Ok(None);
+            }
+            let mut wait = timeout - elapsed;
+            match &self.stream {
+                Some(stream) => {
+                    let heartbeat_timeout = stream.heartbeat_timeout();
+                    if heartbeat_timeout >= 0 && heartbeat_timeout < wait {
+                        wait = heartbeat_timeout;
+                    }
+                    let mut events = EV_POLLIN;
+                    if !stream.write_buffer.is_empty() {
+                        events |= EV_POLLOUT;
+                    }
+                    if !self.ready {
+                        let handshake_timeout = self
+                            .handshake_deadline
+                            .saturating_duration_since(Instant::now())
+                            .as_millis() as i32;
+                        wait = wait.min(handshake_timeout);
+                    }
+                    poll_timeout(stream, events, wait);
+                }
+                None => {
+                    let until = self
+                        .next_attempt
+                        .saturating_duration_since(Instant::now())
+                        .as_millis() as i32;
+                    sleep(Duration::from_millis(until.min(wait) as u64));
+                }
+            }
+        }
+    }
+
+    /// Attempts to flush pending write operations
+    ///
+    /// # Returns
+    /// `true` if all pending operations were flushed, `false` if there are more operations to flush or the connection is not ready
+    pub fn flush(&mut self) -> Result<bool, Error> {
+        self.poll_connection();
+        if !self.ready {
+            return Ok(false);
+        }
+
+        match self.stream.as_mut().unwrap().flush() {
+            Ok(flushed) => Ok(flushed),
+            Err(e) => {
+                self.disconnect(e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Gracefully closes the connection
+    ///
+    /// See [TcpStream::shutdown](struct.TcpStream.html#method.shutdown)
+    pub fn close(self) -> Result<(), Error> {
+        match self.stream {
+            Some(stream) if self.ready => stream.close(),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll_connection(&mut self) {
+        if self.ready {
+            // Queue may be left over when the write limit was reached
+            self.flush_queue();
+            return;
+        }
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                if Instant::now() < self.next_attempt {
+                    return;
+                }
+                let addr = self.addrs[self.attempts as usize % self.addrs.len()];
+                self.handshake_deadline = Instant::now() + self.connect_timeout;
+                match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                    Ok(stream) => self.stream.insert(stream),
+                    Err(e) => {
+                        self.disconnect(e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        match stream.get_ready() {
+            Ok(false) => {
+                if Instant::now() >= self.handshake_deadline {
+                    self.disconnect(Error::TcpError(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "handshake timed out",
+                    )));
+                }
+            }
+            Ok(true) => {
+                let fingerprint = stream.fingerprint();
+                match self.fingerprint {
+                    Some(expected) if expected != fingerprint => {
+                        self.disconnect(Error::FingerprintMismatch);
+                        return;
+                    }
+                    _ => self.fingerprint = Some(fingerprint),
+                }
+
+                stream.set_heartbeat(self.heartbeat, self.max_missed_pings);
+                stream.set_write_limit(self.write_limit);
+                self.ready = true;
+                self.attempts = 0;
+                self.push_event(ReconnectEvent::Connected);
+                self.flush_queue();
+            }
+            Err(e) => self.disconnect(e),
+        }
+    }
+
+    fn flush_queue(&mut self) {
+        while let Some(msg) = self.queue.front() {
+            let result = self.stream.as_mut().unwrap().write(msg);
+            match result {
+                Ok(()) => {
+                    self.queue.pop_front();
+                }
+                Err(Error::Backpressure) => return,
+                Err(e) => {
+                    self.disconnect(e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn disconnect(&mut self, err: Error) {
+        self.stream = None;
+        self.ready = false;
+
+        let exp = self
+            .initial_backoff
+            .saturating_mul(1 << self.attempts.min(16));
+        let delay = exp.min(self.max_backoff);
+        let jitter = self.rand.gen_range(0.0..0.5);
+        self.next_attempt = Instant::now() + delay.mul_f64(1.0 - jitter);
+        self.attempts = self.attempts.saturating_add(1);
+
+        if let Some(ReconnectEvent::Disconnected(_)) = self.events.back() {
+            self.events.pop_back();
+        }
+        self.push_event(ReconnectEvent::Disconnected(err));
+    }
+
+    fn push_event(&mut self, event: ReconnectEvent) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
@@ -0,0 +1,343 @@
+use std::fmt;
+use std::fmt::{Formatter, Write};
+
+use super::{Message, MessageError};
+
+// Nesting depth printed by `inspect`, deeper values are printed as corrupted so untrusted input cannot overflow the stack
+const MAX_INSPECT_DEPTH: usize = 64;
+
+/// Type of a value in a tagged [Message](struct.Message.html)
+///
+/// See [new_tagged](struct.Message.html#method.new_tagged)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    U128,
+    I128,
+    F32,
+    F64,
+    Buffer,
+    Str,
+    Bool,
+    Char,
+    Option,
+    Seq,
+    Map,
+    Varint,
+    VarintSigned,
+    BufferVarint,
+
+    /// Big-endian values are tagged separately, so they are never read with the wrong byte order
+    U16Be,
+    I16Be,
+    U32Be,
+    I32Be,
+    U64Be,
+    I64Be,
+    U128Be,
+    I128Be,
+    F32Be,
+    F64Be,
+
+    /// Tag that does not belong to any type, the message is corrupted or not tagged
+    Unknown(u8),
+}
+
+const TYPES: [ValueType; 32] = [
+    ValueType::U8,
+    ValueType::I8,
+    ValueType::U16,
+    ValueType::I16,
+    ValueType::U32,
+    ValueType::I32,
+    ValueType::U64,
+    ValueType::I64,
+    ValueType::U128,
+    ValueType::I128,
+    ValueType::F32,
+    ValueType::F64,
+    ValueType::Buffer,
+    ValueType::Str,
+    ValueType::Bool,
+    ValueType::Char,
+    ValueType::Option,
+    ValueType::Seq,
+    ValueType::Map,
+    ValueType::Varint,
+    ValueType::VarintSigned,
+    ValueType::BufferVarint,
+    ValueType::U16Be,
+    ValueType::I16Be,
+    ValueType::U32Be,
+    ValueType::I32Be,
+    ValueType::U64Be,
+    ValueType::I64Be,
+    ValueType::U128Be,
+    ValueType::I128Be,
+    ValueType::F32Be,
+    ValueType::F64Be,
+];
+
+impl ValueType {
+    // Tags start at 1, so zeroed data is not mistaken for a value
+    fn tag(self) -> u8 {
+        match self {
+            ValueType::Unknown(tag) => tag,
+            ty => TYPES.iter().position(|t| *t == ty).unwrap() as u8 + 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Self {
+        match tag.checked_sub(1).and_then(|i| TYPES.get(i as usize)) {
+            Some(ty) => *ty,
+            None => ValueType::Unknown(tag),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ValueType::U8 => "u8",
+            ValueType::I8 => "i8",
+            ValueType::U16 => "u16",
+            ValueType::I16 => "i16",
+            ValueType::U32 => "u32",
+            ValueType::I32 => "i32",
+            ValueType::U64 => "u64",
+            ValueType::I64 => "i64",
+            ValueType::U128 => "u128",
+            ValueType::I128 => "i128",
+            ValueType::F32 => "f32",
+            ValueType::F64 => "f64",
+            ValueType::Buffer => "buffer",
+            ValueType::Str => "str",
+            ValueType::Bool => "bool",
+            ValueType::Char => "char",
+            ValueType::Option => "option",
+            ValueType::Seq => "seq",
+            ValueType::Map => "map",
+            ValueType::Varint => "varint",
+            ValueType::VarintSigned => "varint_signed",
+            ValueType::BufferVarint => "buffer_varint",
+            ValueType::U16Be => "u16_be",
+            ValueType::I16Be => "i16_be",
+            ValueType::U32Be => "u32_be",
+            ValueType::I32Be => "i32_be",
+            ValueType::U64Be => "u64_be",
+            ValueType::I64Be => "i64_be",
+            ValueType::U128Be => "u128_be",
+            ValueType::I128Be => "i128_be",
+            ValueType::F32Be => "f32_be",
+            ValueType::F64Be => "f64_be",
+            ValueType::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Debug for ValueType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Unknown(tag) => f.write_fmt(format_args!("unknown tag {}", tag)),
+            ty => f.write_str(ty.name()),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl Message {
+    /// Creates a new empty tagged message
+    ///
+    /// Every value in a tagged message is preceded by a 1-byte [type tag](enum.ValueType.html).
+    /// Reading a value of different type fails with [TypeMismatch](enum.MessageError.html#variant.TypeMismatch)
+    /// and the message can be printed without knowing its layout by [inspect](struct.Message.html#method.inspect).
+    /// Received messages are tagged if they were tagged when written.
+    pub fn new_tagged() -> Message {
+        let mut msg = Message::new();
+        msg.tagged = true;
+        msg
+    }
+
+    /// Sets whether values are tagged
+    ///
+    /// This should be set before anything is written, see [new_tagged](struct.Message.html#method.new_tagged)
+    pub fn set_tagged(&mut self, tagged: bool) {
+        self.tagged = tagged;
+    }
+
+    /// Checks whether values are tagged
+    pub fn is_tagged(&self) -> bool {
+        self.tagged
+    }
+
+    /// Formats the whole message, regardless of the read cursor
+    ///
+    /// Every value is printed on its own line with its offset and type, elements of sequences, maps and options are indented.
+    /// Values nested more than 64 levels deep are printed as corrupted.
+    /// Messages that are not tagged are printed as hex dump.
+    pub fn inspect(&self) -> String {
+        if !self.tagged {
+            return format!("untagged {}", hex(self.as_bytes()));
+        }
+
+        let mut out = String::new();
+        let mut msg = Message::from_buffer(self.as_bytes().to_vec());
+        msg.tagged = true;
+        while msg.read_pos < msg.buffer.len() {
+            if !msg.inspect_value(&mut out, 0) {
+                break;
+            }
+        }
+        out
+    }
+
+    // Formats one value and moves read cursor, returns `false` if the message is corrupted
+    fn inspect_value(&mut self, out: &mut String, depth: usize) -> bool {
+        let offset = self.read_pos;
+        let indent = "  ".repeat(depth);
+        if depth > MAX_INSPECT_DEPTH {
+            let rest = hex(&self.buffer[offset..]);
+            writeln!(out, "{}{}: corrupted {}", indent, offset, rest).unwrap();
+            return false;
+        }
+        let ty = ValueType::from_tag(self.buffer[offset]);
+
+        // Formatted value and number of nested values following it
+        let value = match ty {
+            ValueType::U8 => self.read_u8().map(|n| (n.to_string(), 0)),
+            ValueType::I8 => self.read_i8().map(|n| (n.to_string(), 0)),
+            ValueType::U16 => self.read_u16().map(|n| (n.to_string(), 0)),
+            ValueType::I16 => self.read_i16().map(|n| (n.to_string(), 0)),
+            ValueType::U32 => self.read_u32().map(|n| (n.to_string(), 0)),
+            ValueType::I32 => self.read_i32().map(|n| (n.to_string(), 0)),
+            ValueType::U64 => self.read_u64().map(|n| (n.to_string(), 0)),
+            ValueType::I64 => self.read_i64().map(|n| (n.to_string(), 0)),
+            ValueType::U128 => self.read_u128().map(|n| (n.to_string(), 0)),
+            ValueType::I128 => self.read_i128().map(|n| (n.to_string(), 0)),
+            ValueType::F32 => self.read_f32().map(|n| (n.to_string(), 0)),
+            ValueType::F64 => self.read_f64().map(|n| (n.to_string(), 0)),
+            ValueType::U16Be => self.read_u16_be().map(|n| (n.to_string(), 0)),
+            ValueType::I16Be => self.read_i16_be().map(|n| (n.to_string(), 0)),
+            ValueType::U32Be => self.read_u32_be().map(|n| (n.to_string(), 0)),
+            ValueType::I32Be => self.read_i32_be().map(|n| (n.to_string(), 0)),
+            ValueType::U64Be => self.read_u64_be().map(|n| (n.to_string(), 0)),
+            ValueType::I64Be => self.read_i64_be().map(|n| (n.to_string(), 0)),
+            ValueType::U128Be => self.read_u128_be().map(|n| (n.to_string(), 0)),
+            ValueType::I128Be => self.read_i128_be().map(|n| (n.to_string(), 0)),
+            ValueType::F32Be => self.read_f32_be().map(|n| (n.to_string(), 0)),
+            ValueType::F64Be => self.read_f64_be().map(|n| (n.to_string(), 0)),
+            ValueType::Varint => self.read_varint_u64().map(|n| (n.to_string(), 0)),
+            ValueType::VarintSigned => self.read_varint_i64().map(|n| (n.to_string(), 0)),
+            ValueType::Bool => self.read_bool().map(|b| (b.to_string(), 0)),
+            ValueType::Char => self.read_char().map(|c| (format!("{:?}", c), 0)),
+            ValueType::Str => self.read_str().map(|s| (format!("{:?}", s), 0)),
+            ValueType::Buffer => self.read_buffer().map(|buf| (hex(buf), 0)),
+            ValueType::BufferVarint => self.read_buffer_varint().map(|buf| (hex(buf), 0)),
+            ValueType::Option => self.read_option_flag().map(|some| {
+                let value = if some { "some" } else { "none" };
+                (value.to_string(), some as usize)
+            }),
+            ValueType::Seq => self
+                .read_count(ty, usize::MAX)
+                .map(|len| (len.to_string(), len)),
+            ValueType::Map => self
+                .read_count(ty, usize::MAX)
+                .map(|len| (len.to_string(), 2 * len)),
+            ValueType::Unknown(_) => Err(MessageError::InvalidValue),
+        };
+
+        let nested = match value {
+            Ok((value, nested)) => {
+                writeln!(out, "{}{}: {} {}", indent, offset, ty.name(), value).unwrap();
+                nested
+            }
+            Err(_) => {
+                let rest = hex(&self.buffer[offset..]);
+                writeln!(out, "{}{}: corrupted {}", indent, offset, rest).unwrap();
+                return false;
+            }
+        };
+        for _ in 0..nested {
+            if self.read_pos >= self.buffer.len() || !self.inspect_value(out, depth + 1) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(super) fn write_tag(&mut self, ty: ValueType) {
+        if self.tagged {
+            self.buffer.push(ty.tag());
+        }
+    }
+
+    pub(super) fn read_tag(&mut self, expected: ValueType) -> Result<(), MessageError> {
+        if !self.tagged {
+            return Ok(());
+        }
+        if self.remaining() < 1 {
+            return Err(MessageError::UnexpectedEnd);
+        }
+        let found = ValueType::from_tag(self.buffer[self.read_pos]);
+        if found != expected {
+            return Err(MessageError::TypeMismatch {
+                expected,
+                found,
+                offset: self.position(),
+            });
+        }
+        self.read_pos += 1;
+        Ok(())
+    }
+
+    // Number of elements of a sequence or map
+    pub(super) fn write_count(&mut self, ty: ValueType, len: usize) {
+        assert!(len <= u32::MAX as usize, "{} has more than u32::MAX elements", ty);
+        self.write_tag(ty);
+        self.buffer.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+
+    pub(super) fn read_count(
+        &mut self,
+        ty: ValueType,
+        limit: usize,
+    ) -> Result<usize, MessageError> {
+        self.read_tag(ty)?;
+        let len = u32::from_le_bytes(self.take()?) as usize;
+        if len > limit {
+            return Err(MessageError::LimitExceeded);
+        }
+        Ok(len)
+    }
+
+    // Whether an optional value follows
+    pub(super) fn write_option_flag(&mut self, some: bool) {
+        self.write_tag(ValueType::Option);
+        self.buffer.push(some as u8);
+    }
+
+    pub(super) fn read_option_flag(&mut self) -> Result<bool, MessageError> {
+        self.read_tag(ValueType::Option)?;
+        match self.take::<1>()? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(MessageError::InvalidValue),
+        }
+    }
+}
+
+// Formats bytes as `[01 02 03]`
+fn hex(buf: &[u8]) -> String {
+    let bytes: Vec<String> = buf.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("[{}]", bytes.join(" "))
+}
@@ -1,6 +1,12 @@
-use crate::simpletcp::{Error, Message, TcpServer, TcpStream};
-use std::io::Write;
+use crate::simpletcp::{
+    ClientSet, Error, Handler, Message, ReconnectEvent, ReconnectingStream, TcpServer, TcpStream,
+    FRAME_CHANNEL_OPEN, FRAME_CLOSE, FRAME_DATA, FRAME_STREAM_ACK, FRAME_STREAM_BEGIN,
+};
+use std::io;
+use std::io::{Read, Write};
 use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::thread::{sleep, spawn};
 use std::time::{Duration, Instant};
 
@@ -451,4 +457,1507 @@ fn fingerprint(){
     client = TcpStream::connect("127.0.0.1:12415").expect("Failed to connect to server");
     client.wait_until_ready().unwrap();
     assert_eq!(client.fingerprint(), fingerprint);
-}
\ No newline at end of file
+}
+
+#[test]
+fn heartbeat_rtt() {
+    let server = TcpServer::new("127.0.0.1:1542").expect("Failed to create server");
+
+    spawn(move || {
+        let mut s_client = server.accept_blocking().unwrap();
+        s_client.wait_until_ready().unwrap();
+        let time = Instant::now();
+        while time.elapsed().as_millis() < 1000 {
+            s_client.read_timeout(100).unwrap();
+        }
+    });
+
+    let mut client = TcpStream::connect("127.0.0.1:1542").expect("Failed to connect to server");
+    client.wait_until_ready().unwrap();
+    client.set_heartbeat(Some(Duration::from_millis(100)), 3);
+
+    let time = Instant::now();
+    while client.rtt().is_none() {
+        assert!(client.read_timeout(100).unwrap().is_none());
+        if time.elapsed().as_millis() > 500 {
+            panic!("Timeout");
+        }
+    }
+}
+
+#[test]
+fn heartbeat_peer_timeout() {
+    let server = TcpServer::new("127.0.0.1:1543").expect("Failed to create server");
+
+    spawn(move || {
+        let mut s_client = server.accept_blocking().unwrap();
+        s_client.wait_until_ready().unwrap();
+        // Keep the connection open without reading, long enough for loaded machines
+        sleep(Duration::from_millis(5000));
+    });
+
+    let mut client = TcpStream::connect("127.0.0.1:1543").expect("Failed to connect to server");
+    client.wait_until_ready().unwrap();
+    client.set_heartbeat(Some(Duration::from_millis(100)), 3);
+
+    let time = Instant::now();
+    match client.read_blocking() {
+        Err(Error::PeerTimeout) => {}
+        _ => panic!("Expected PeerTimeout"),
+    }
+    let time = time.elapsed().as_millis();
+    // Timeout cannot be detected before 3 intervals
+    assert!((300..5000).contains(&time));
+}
+
+#[test]
+fn shutdown() {
+    let server = TcpServer::new("127.0.0.1:1544").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1544").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let mut msg = Message::new();
+        msg.write_u32(42);
+        client.write(&msg).unwrap();
+        client.shutdown().unwrap();
+        assert!(client.write(&msg).is_err());
+
+        let mut msg = client.read_blocking().unwrap();
+        assert_eq!(msg.read_u32().unwrap(), 43);
+        match client.read_blocking() {
+            Err(Error::ConnectionClosed) => {}
+            _ => panic!("Expected ConnectionClosed"),
+        }
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    let mut msg = s_client.read_blocking().unwrap();
+    assert_eq!(msg.read_u32().unwrap(), 42);
+    match s_client.read_blocking() {
+        Err(Error::ConnectionClosed) => {}
+        _ => panic!("Expected ConnectionClosed"),
+    }
+    assert!(s_client.is_peer_closed());
+
+    let mut msg = Message::new();
+    msg.write_u32(43);
+    s_client.write_blocking(&msg).unwrap();
+    s_client.close().unwrap();
+    sleep(Duration::from_millis(100));
+}
+
+#[test]
+fn truncated() {
+    let server = TcpServer::new("127.0.0.1:1545").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1545").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        client.socket.write_all(&[16, 0, 0, 0, 1, 2]).unwrap();
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    match s_client.read_blocking() {
+        Err(Error::Truncated) => {}
+        _ => panic!("Expected Truncated"),
+    }
+}
+
+#[test]
+fn forged_close() {
+    let server = TcpServer::new("127.0.0.1:1571").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1571").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        // Flipping bits of the IV turns an empty data frame into a close frame
+        let mut frame = Vec::new();
+        client
+            .encrypt_frame_into(FRAME_DATA, &[], &mut frame)
+            .unwrap();
+        frame[4] ^= FRAME_DATA ^ FRAME_CLOSE;
+        client.write_encoded(&frame).unwrap();
+        while !client.flush().unwrap() {}
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    match s_client.read_blocking() {
+        Err(Error::InvalidFrame) => {}
+        _ => panic!("Expected InvalidFrame"),
+    }
+    assert!(!s_client.is_peer_closed());
+}
+
+#[test]
+fn reconnect() {
+    let server = TcpServer::new("127.0.0.1:1546").expect("Failed to create server");
+    spawn(move || {
+        let mut s_client = server.accept_blocking().unwrap();
+        s_client.wait_until_ready().unwrap();
+        assert_eq!(s_client.read_blocking().unwrap().read_u32().unwrap(), 1);
+        drop(s_client);
+
+        let mut s_client = server.accept_blocking().unwrap();
+        s_client.wait_until_ready().unwrap();
+        // Queued message keeps its tagged flag
+        let mut msg = s_client.read_blocking().unwrap();
+        assert!(msg.is_tagged());
+        assert_eq!(msg.read_u32().unwrap(), 2);
+        let mut msg = Message::new();
+        msg.write_u32(3);
+        s_client.write_blocking(&msg).unwrap();
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut client = ReconnectingStream::connect("127.0.0.1:1546").unwrap();
+    client.set_backoff(Duration::from_millis(10), Duration::from_millis(100));
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    client.write(&msg).unwrap();
+
+    let time = Instant::now();
+    let mut disconnected = false;
+    let mut connected = 0;
+    loop {
+        if let Some(mut msg) = client.read_timeout(50).unwrap() {
+            assert_eq!(msg.read_u32().unwrap(), 3);
+            break;
+        }
+        while let Some(event) = client.next_event() {
+            match event {
+                ReconnectEvent::Connected => connected += 1,
+                ReconnectEvent::Disconnected(_) if !disconnected => {
+                    disconnected = true;
+                    let mut msg = Message::new_tagged();
+                    msg.write_u32(2);
+                    client.write(&msg).unwrap();
+                }
+                ReconnectEvent::Disconnected(_) => {}
+            }
+        }
+        if time.elapsed().as_millis() > 2000 {
+            panic!("Timeout");
+        }
+    }
+    assert!(disconnected);
+    assert_eq!(connected, 2);
+    assert!(client.fingerprint().is_some());
+}
+
+#[test]
+fn reconnect_queue_limit() {
+    let mut client = ReconnectingStream::connect("127.0.0.1:1547").unwrap();
+    client.set_queue_limit(2);
+    let msg = Message::new();
+    client.write(&msg).unwrap();
+    client.write(&msg).unwrap();
+    match client.write(&msg) {
+        Err(Error::Backpressure) => {}
+        _ => panic!("Expected Backpressure"),
+    }
+    assert_eq!(client.queued(), 2);
+    match client.next_event() {
+        Some(ReconnectEvent::Disconnected(Error::TcpError(_))) => {}
+        _ => panic!("Expected Disconnected event"),
+    }
+}
+
+#[test]
+fn reconnect_unreachable() {
+    // Listener accepts TCP connections but never completes the handshake
+    let _listener = net::TcpListener::bind("127.0.0.1:1578").unwrap();
+    let time = Instant::now();
+    let mut client = ReconnectingStream::connect("127.0.0.1:1578").unwrap();
+    client.set_connect_timeout(Duration::from_millis(100));
+    client.set_backoff(Duration::from_millis(10), Duration::from_millis(10));
+    // First attempt uses the default connect timeout
+    for _ in 0..5 {
+        assert!(client.read_timeout(300).unwrap().is_none());
+    }
+    assert!(!client.is_connected());
+    assert!(time.elapsed() < Duration::from_secs(5));
+
+    // Failed attempts are coalesced into one event
+    match client.next_event() {
+        Some(ReconnectEvent::Disconnected(Error::TcpError(err))) => {
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut)
+        }
+        event => panic!("Expected handshake timeout, got {:?}", event),
+    }
+    assert!(client.next_event().is_none());
+}
+
+// Writes big messages until Backpressure, the peer must not be reading
+//
+// Returns number of written messages
+fn fill_write_buffer<F: FnMut(&Message) -> Result<(), Error>>(mut write: F) -> usize {
+    let mut msg = Message::new();
+    msg.write_buffer(&[0; 1024 * 1024]);
+    for written in 0..1000 {
+        match write(&msg) {
+            Ok(()) => {}
+            Err(Error::Backpressure) => return written,
+            Err(err) => panic!("Expected Backpressure, got {:?}", err),
+        }
+    }
+    panic!("Expected Backpressure");
+}
+
+#[test]
+fn write_limit_nothing_pending() {
+    let server = TcpServer::new("127.0.0.1:1575").expect("Failed to create server");
+    let mut client = TcpStream::connect("127.0.0.1:1575").expect("Failed to connect to server");
+    let mut s_client = server.accept_blocking().unwrap();
+    client.wait_until_ready().unwrap();
+    s_client.wait_until_ready().unwrap();
+
+    // Message bigger than the limit is accepted when nothing is pending
+    client.set_write_limit(Some(1));
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    client.write_blocking(&msg).unwrap();
+    assert_eq!(client.pending_bytes(), 0);
+    assert_eq!(s_client.read_blocking().unwrap().read_u32().unwrap(), 1);
+
+    assert!(fill_write_buffer(|msg| client.write(msg)) > 0);
+}
+
+#[test]
+#[should_panic(expected = "Write limit must be greater than zero")]
+fn write_limit_zero() {
+    let server = TcpServer::new("127.0.0.1:1576").expect("Failed to create server");
+    let mut client = TcpStream::connect("127.0.0.1:1576").expect("Failed to connect to server");
+    drop(server);
+    client.set_write_limit(Some(0));
+}
+
+#[test]
+fn reconnect_backpressure() {
+    let server = TcpServer::new("127.0.0.1:1568").expect("Failed to create server");
+    let mut client = ReconnectingStream::connect("127.0.0.1:1568").unwrap();
+    client.set_write_limit(Some(1));
+    let mut s_client = server.accept_blocking().unwrap();
+    while !client.is_connected() {
+        client.read_timeout(10).unwrap();
+    }
+    s_client.wait_until_ready().unwrap();
+
+    let filled = fill_write_buffer(|msg| client.write(msg));
+    // Full write buffer does not drop the connection
+    assert!(client.is_connected());
+    client.set_write_limit(None);
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    client.write(&msg).unwrap();
+    let mut received = 0;
+    while received <= filled {
+        client.flush().unwrap();
+        if let Some(mut msg) = s_client.read_timeout(10).unwrap() {
+            received += 1;
+            if received > filled {
+                assert_eq!(msg.read_u32().unwrap(), 1);
+            }
+        }
+    }
+    assert!(matches!(client.next_event(), Some(ReconnectEvent::Connected)));
+    assert!(client.next_event().is_none());
+}
+
+#[test]
+fn size_limit() {
+    let mut server = TcpServer::new("127.0.0.1:1548").expect("Failed to create server");
+    server.set_size_limit(1000);
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1548").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let mut msg = Message::new();
+        msg.write_buffer(&[1; 2000]);
+        client.write_blocking(&msg).unwrap();
+
+        let mut msg = Message::new();
+        msg.write_buffer(&[2; 996]);
+        client.write_blocking(&msg).unwrap();
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    assert_eq!(s_client.size_limit(), 1000);
+    s_client.wait_until_ready().unwrap();
+    match s_client.read_blocking() {
+        Err(Error::SizeLimitExceeded) => {}
+        _ => panic!("Expected SizeLimitExceeded"),
+    }
+    let mut msg = s_client.read_blocking().unwrap();
+    assert_eq!(msg.read_buffer().unwrap(), &[2; 996][..]);
+}
+
+#[test]
+fn handshake_size_limit() {
+    let server = TcpServer::new("127.0.0.1:1549").expect("Failed to create server");
+    let mut client = TcpStream::connect("127.0.0.1:1549").expect("Failed to connect to server");
+    client.set_handshake_size_limit(100);
+    let _s_client = server.accept_blocking().unwrap();
+
+    let time = Instant::now();
+    loop {
+        match client.get_ready() {
+            Ok(false) => {}
+            Err(Error::SizeLimitExceeded) => break,
+            _ => panic!("Expected SizeLimitExceeded"),
+        }
+        if time.elapsed().as_millis() > 500 {
+            panic!("Timeout");
+        }
+    }
+}
+
+#[test]
+fn stream_transfer() {
+    let server = TcpServer::new("127.0.0.1:1550").expect("Failed to create server");
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 123).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+
+    spawn(move || {
+        let mut client = TcpStream::connect("127.0.0.1:1550").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let mut msg = Message::new();
+        msg.write_u32(1);
+        client.write(&msg).unwrap();
+        assert_eq!(client.write_stream(&mut &data[..]).unwrap(), data.len() as u64);
+        msg.write_u32(2);
+        client.write_blocking(&msg).unwrap();
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    let mut received = Vec::new();
+    s_client
+        .read_stream_blocking()
+        .unwrap()
+        .read_to_end(&mut received)
+        .unwrap();
+    assert_eq!(received, expected);
+
+    assert_eq!(s_client.read_blocking().unwrap().read_u32().unwrap(), 1);
+    let mut msg = s_client.read_blocking().unwrap();
+    assert_eq!(msg.read_u32().unwrap(), 1);
+    assert_eq!(msg.read_u32().unwrap(), 2);
+}
+
+#[test]
+fn stream_size_limit() {
+    let mut server = TcpServer::new("127.0.0.1:1570").expect("Failed to create server");
+    server.set_size_limit(1000);
+    let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+
+    spawn(move || {
+        let mut client = TcpStream::connect("127.0.0.1:1570").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        assert_eq!(client.write_stream(&mut &data[..]).unwrap(), data.len() as u64);
+
+        // Second transfer begins before the first one has finished
+        client
+            .write_frame(FRAME_STREAM_BEGIN, &100u64.to_le_bytes())
+            .unwrap();
+        client
+            .write_frame(FRAME_STREAM_BEGIN, &101u64.to_le_bytes())
+            .unwrap();
+        while !client.flush().unwrap() {}
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    let mut received = Vec::new();
+    s_client
+        .read_stream_blocking()
+        .unwrap()
+        .read_to_end(&mut received)
+        .unwrap();
+    assert_eq!(received, expected);
+
+    match s_client.read_blocking() {
+        Err(Error::InvalidFrame) => {}
+        _ => panic!("Expected InvalidFrame"),
+    }
+}
+
+#[test]
+fn stream_early_ack() {
+    let server = TcpServer::new("127.0.0.1:1574").expect("Failed to create server");
+    let handle = spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1574").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let data = vec![0; 2 * 1024 * 1024];
+        client.write_stream(&mut &data[..])
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    // Sender stops after a window of 16 chunks, acknowledge the end of stream before it was sent
+    sleep(Duration::from_millis(500));
+    assert!(s_client.read().unwrap().is_none());
+    let mut ack = 0u64.to_le_bytes().to_vec();
+    ack.extend_from_slice(&17u32.to_le_bytes());
+    s_client.write_frame(FRAME_STREAM_ACK, &ack).unwrap();
+    while !s_client.flush().unwrap() {}
+
+    match handle.join().unwrap() {
+        Err(Error::InvalidFrame) => {}
+        _ => panic!("Expected InvalidFrame"),
+    }
+}
+
+#[test]
+fn stream_cancel() {
+    let server = TcpServer::new("127.0.0.1:1551").expect("Failed to create server");
+
+    let handle = spawn(move || {
+        let mut client = TcpStream::connect("127.0.0.1:1551").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let data = vec![0; 4 * 1024 * 1024];
+        client.write_stream(&mut &data[..])
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    let mut stream = s_client.read_stream_blocking().unwrap();
+    let mut buf = [0; 1024];
+    stream.read_exact(&mut buf).unwrap();
+    stream.cancel().unwrap();
+
+    match handle.join().unwrap() {
+        Err(Error::StreamCancelled) => {}
+        _ => panic!("Expected StreamCancelled"),
+    }
+}
+
+#[test]
+fn channels() {
+    let server = TcpServer::new("127.0.0.1:1552").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1552").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let bulk = client.open_channel().unwrap();
+        let control = client.open_channel().unwrap();
+        assert_ne!(bulk, control);
+
+        let mut msg = Message::new();
+        msg.write_buffer(&[0; 256 * 1024]);
+        for _ in 0..8 {
+            client.write_channel(bulk, &msg).unwrap();
+        }
+        client.close_channel(bulk).unwrap();
+
+        let mut msg = Message::new_tagged();
+        msg.write_u32(7);
+        client.write_channel(control, &msg).unwrap();
+        let mut msg = Message::new();
+        msg.write_u32(8);
+        client.write(&msg).unwrap();
+
+        let mut reply = client.read_channel_blocking(control).unwrap();
+        assert!(!reply.is_tagged());
+        assert_eq!(reply.read_u32().unwrap(), 14);
+        while !client.flush().unwrap() {}
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    let bulk = s_client.accept_channel_blocking().unwrap();
+    let control = s_client.accept_channel_blocking().unwrap();
+
+    let mut msg = s_client.read_channel_blocking(control).unwrap();
+    assert!(msg.is_tagged());
+    let n = msg.read_u32().unwrap();
+    assert_eq!(n, 7);
+    let mut reply = Message::new();
+    reply.write_u32(n * 2);
+    s_client.write_channel(control, &reply).unwrap();
+
+    assert_eq!(s_client.read_blocking().unwrap().read_u32().unwrap(), 8);
+
+    for _ in 0..8 {
+        let mut msg = s_client.read_channel_blocking(bulk).unwrap();
+        assert_eq!(msg.read_buffer().unwrap().len(), 256 * 1024);
+    }
+    match s_client.read_channel_blocking(bulk) {
+        Err(Error::ChannelClosed) => {}
+        _ => panic!("Expected ChannelClosed"),
+    }
+    while !s_client.flush().unwrap() {}
+
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    match s_client.write_channel(1234, &msg) {
+        Err(Error::ChannelClosed) => {}
+        _ => panic!("Expected ChannelClosed"),
+    }
+}
+
+#[test]
+fn channel_limits() {
+    let mut server = TcpServer::new("127.0.0.1:1569").expect("Failed to create server");
+    server.set_channel_limit(1);
+    server.set_channel_queue_limit(2);
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1569").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let accepted = client.open_channel().unwrap();
+        let refused = client.open_channel().unwrap();
+        for i in 0..4 {
+            let mut msg = Message::new();
+            msg.write_u32(i);
+            client.write_channel(accepted, &msg).unwrap();
+        }
+        match client.read_channel_blocking(refused) {
+            Err(Error::ChannelClosed) => {}
+            _ => panic!("Expected ChannelClosed"),
+        }
+
+        // Even IDs belong to the server
+        sleep(Duration::from_millis(300));
+        client
+            .write_frame(FRAME_CHANNEL_OPEN, &2u32.to_le_bytes())
+            .unwrap();
+        while !client.flush().unwrap() {}
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    assert_eq!(s_client.channel_limit(), 1);
+    let id = s_client.accept_channel_blocking().unwrap();
+    sleep(Duration::from_millis(200));
+    assert!(s_client.read().unwrap().is_none());
+    assert!(s_client.accept_channel().unwrap().is_none());
+
+    match s_client.read_channel(id) {
+        Err(Error::ChannelOverflow { dropped: 2 }) => {}
+        _ => panic!("Expected ChannelOverflow"),
+    }
+    for i in 0..2 {
+        let mut msg = s_client.read_channel(id).unwrap().unwrap();
+        assert_eq!(msg.read_u32().unwrap(), i);
+    }
+
+    match s_client.read_blocking() {
+        Err(Error::InvalidFrame) => {}
+        _ => panic!("Expected InvalidFrame"),
+    }
+}
+
+struct EventLog {
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl Handler for EventLog {
+    fn on_connect(&mut self, id: u64, _client: &mut TcpStream) {
+        self.events.lock().unwrap().push(format!("connect {}", id));
+    }
+
+    fn on_ready(&mut self, id: u64, _client: &mut TcpStream) {
+        self.events.lock().unwrap().push(format!("ready {}", id));
+    }
+
+    fn on_message(&mut self, _id: u64, client: &mut TcpStream, mut msg: Message) {
+        let mut reply = Message::new();
+        reply.write_u32(msg.read_u32().unwrap() * 2);
+        client.write(&reply).unwrap();
+    }
+
+    fn on_disconnect(&mut self, id: u64, _err: Error) {
+        self.events.lock().unwrap().push(format!("disconnect {}", id));
+    }
+}
+
+#[test]
+fn event_loop() {
+    let mut server = TcpServer::new("127.0.0.1:1557").expect("Failed to create server");
+    server.set_size_limit(1000);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut handler = EventLog {
+        events: events.clone(),
+    };
+    spawn(move || server.run(&mut handler));
+
+    let mut first = TcpStream::connect("127.0.0.1:1557").expect("Failed to connect to server");
+    first.wait_until_ready().unwrap();
+    let mut second = TcpStream::connect("127.0.0.1:1557").expect("Failed to connect to server");
+    second.wait_until_ready().unwrap();
+
+    // Several messages at once must all be handled after a single wake
+    for i in 0..10 {
+        let mut msg = Message::new();
+        msg.write_u32(i);
+        second.write(&msg).unwrap();
+    }
+    for i in 0..10 {
+        assert_eq!(second.read_blocking().unwrap().read_u32().unwrap(), i * 2);
+    }
+
+    // Oversized message is skipped without disconnecting the client
+    let mut msg = Message::new();
+    msg.write_buffer(&[0; 2000]);
+    second.write(&msg).unwrap();
+    let mut msg = Message::new();
+    msg.write_u32(7);
+    second.write(&msg).unwrap();
+    assert_eq!(second.read_blocking().unwrap().read_u32().unwrap(), 14);
+
+    let mut msg = Message::new();
+    msg.write_u32(21);
+    first.write(&msg).unwrap();
+    assert_eq!(first.read_blocking().unwrap().read_u32().unwrap(), 42);
+
+    first.close().unwrap();
+    sleep(Duration::from_millis(200));
+    let mut events = events.lock().unwrap().clone();
+    assert_eq!(events.pop().unwrap(), "disconnect 0");
+    events.sort();
+    assert_eq!(events, vec!["connect 0", "connect 1", "ready 0", "ready 1"]);
+}
+
+struct PoolHandler {
+    threads: Arc<Mutex<Vec<(u64, thread::ThreadId)>>>,
+}
+
+impl Handler for PoolHandler {
+    fn on_message(&mut self, id: u64, client: &mut TcpStream, msg: Message) {
+        self.threads
+            .lock()
+            .unwrap()
+            .push((id, thread::current().id()));
+        client.write(&msg).unwrap();
+    }
+}
+
+#[test]
+fn thread_pool() {
+    let server = TcpServer::new("127.0.0.1:1558").expect("Failed to create server");
+    let threads = Arc::new(Mutex::new(Vec::new()));
+    let server_threads = threads.clone();
+    spawn(move || {
+        server.run_pool(3, || PoolHandler {
+            threads: server_threads.clone(),
+        })
+    });
+
+    let clients: Vec<_> = (0..6)
+        .map(|_| {
+            spawn(|| {
+                let mut client =
+                    TcpStream::connect("127.0.0.1:1558").expect("Failed to connect to server");
+                client.wait_until_ready().unwrap();
+                for i in 0..20 {
+                    let mut msg = Message::new();
+                    msg.write_u32(i);
+                    client.write(&msg).unwrap();
+                }
+                for i in 0..20 {
+                    assert_eq!(client.read_blocking().unwrap().read_u32().unwrap(), i);
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    let threads = threads.lock().unwrap();
+    assert_eq!(threads.len(), 6 * 20);
+    let mut workers: Vec<_> = threads.iter().map(|(_, thread)| *thread).collect();
+    workers.dedup();
+    assert!(workers.len() > 1);
+    // Every client is handled by a single worker
+    for (id, thread) in threads.iter() {
+        assert!(threads.iter().all(|(i, t)| i != id || t == thread));
+    }
+}
+
+#[test]
+fn client_set() {
+    let server = TcpServer::new("127.0.0.1:1559").expect("Failed to create server");
+    let clients: Vec<_> = (0..3)
+        .map(|i| {
+            spawn(move || {
+                let mut client =
+                    TcpStream::connect("127.0.0.1:1559").expect("Failed to connect to server");
+                client.wait_until_ready().unwrap();
+                let mut msg = Message::new();
+                msg.write_u32(i);
+                client.write(&msg).unwrap();
+
+                assert_eq!(client.read_blocking().unwrap().read_u32().unwrap(), 100);
+                let n = client.read_blocking().unwrap().read_u32().unwrap();
+                assert_eq!(n, i * 2);
+                client.close().unwrap();
+            })
+        })
+        .collect();
+
+    let mut set = ClientSet::new();
+    let mut ready = 0;
+    let mut ids = Vec::new();
+    while ids.len() < 3 {
+        set.accept(&server).unwrap();
+        ready += set.update().len();
+        while let Some((id, mut msg)) = set.read() {
+            ids.push((id, msg.read_u32().unwrap()));
+        }
+        set.wait(&server, 100);
+    }
+    assert_eq!(ready, 3);
+    assert_eq!(set.len(), 3);
+    assert_eq!(set.ready().count(), 3);
+
+    let mut msg = Message::new();
+    msg.write_u32(100);
+    assert_eq!(set.broadcast(&msg), 3);
+    for (id, n) in &ids {
+        let mut msg = Message::new();
+        msg.write_u32(n * 2);
+        set.send_to(*id, &msg).unwrap();
+    }
+
+    for client in clients {
+        client.join().unwrap();
+    }
+    while !set.is_empty() {
+        set.update();
+        set.read();
+        set.wait(&server, 100);
+    }
+    let mut closed: Vec<u64> = set.take_closed().into_iter().map(|(id, _)| id).collect();
+    closed.sort();
+    assert_eq!(closed, vec![0, 1, 2]);
+    match set.send_to(0, &msg) {
+        Err(Error::ConnectionClosed) => {}
+        _ => panic!("Expected ConnectionClosed"),
+    }
+}
+
+#[test]
+fn client_set_backpressure() {
+    let server = TcpServer::new("127.0.0.1:1577").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1577").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        sleep(Duration::from_millis(1000));
+    });
+
+    let mut set = ClientSet::new();
+    while set.ready().count() == 0 {
+        set.accept(&server).unwrap();
+        set.update();
+        set.wait(&server, 100);
+    }
+    let id = set.ids()[0];
+
+    // Backpressure does not remove the client
+    set.get_mut(id).unwrap().set_write_limit(Some(1));
+    fill_write_buffer(|msg| set.send_to(id, msg));
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    assert_eq!(set.broadcast(&msg), 0);
+    assert!(set.contains(id));
+    assert!(set.take_closed().is_empty());
+}
+
+#[test]
+fn client_set_send_error() {
+    let server = TcpServer::new("127.0.0.1:1579").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1579").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut set = ClientSet::new();
+    while set.ready().count() == 0 {
+        set.accept(&server).unwrap();
+        set.update();
+        set.wait(&server, 100);
+    }
+    let id = set.ids()[0];
+
+    // Failed write removes the client and reports its error
+    set.get_mut(id).unwrap().shutdown().unwrap();
+    match set.send_to(id, &Message::new()) {
+        Err(Error::ConnectionClosed) => {}
+        _ => panic!("Expected ConnectionClosed"),
+    }
+    assert!(!set.contains(id));
+    let closed = set.take_closed();
+    assert_eq!(closed.len(), 1);
+    assert!(matches!(closed[0], (closed_id, Error::ConnectionClosed) if closed_id == id));
+}
+
+#[test]
+fn client_set_heartbeat() {
+    let server = TcpServer::new("127.0.0.1:1580").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1580").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        sleep(Duration::from_millis(2000));
+    });
+
+    let mut set = ClientSet::new();
+    while set.ready().count() == 0 {
+        set.accept(&server).unwrap();
+        set.update();
+        set.wait(&server, 100);
+    }
+    let id = set.ids()[0];
+    set.get_mut(id)
+        .unwrap()
+        .set_heartbeat(Some(Duration::from_millis(100)), 3);
+
+    // Idle client, waiting returns when the heartbeat is due
+    let time = Instant::now();
+    set.wait(&server, -1);
+    assert!(time.elapsed() < Duration::from_millis(1000));
+}
+
+#[test]
+fn write_limit() {
+    let server = TcpServer::new("127.0.0.1:1560").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1560").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        // Let the server fill its buffers
+        sleep(Duration::from_millis(500));
+        for _ in 0..24 {
+            let mut msg = client.read_blocking().unwrap();
+            assert_eq!(msg.read_buffer().unwrap().len(), 512 * 1024);
+        }
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    s_client.set_write_limit(Some(1024 * 1024));
+    assert_eq!(s_client.write_limit(), Some(1024 * 1024));
+
+    let mut msg = Message::new();
+    msg.write_buffer(&[0; 512 * 1024]);
+    let mut written = 0;
+    loop {
+        match s_client.write(&msg) {
+            Ok(()) => written += 1,
+            Err(Error::Backpressure) => break,
+            Err(err) => panic!("Unexpected error {:?}", err),
+        }
+    }
+    assert!(written < 24);
+    assert!(s_client.pending_bytes() >= 1024 * 1024);
+    assert!(s_client.pending_bytes() < 2 * 1024 * 1024);
+
+    for _ in written..24 {
+        s_client.write_blocking(&msg).unwrap();
+    }
+    while !s_client.flush().unwrap() {}
+    assert_eq!(s_client.pending_bytes(), 0);
+    s_client.close().unwrap();
+}
+
+#[test]
+fn write_many() {
+    let server = TcpServer::new("127.0.0.1:1561").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1561").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let msgs: Vec<Message> = (0..1000)
+            .map(|i| {
+                let mut msg = Message::new();
+                msg.write_u32(i);
+                msg
+            })
+            .collect();
+        for batch in msgs.chunks(100) {
+            client.write_many(batch).unwrap();
+        }
+        let mut msg = Message::new();
+        msg.write_buffer(&[7; 3 * 1024 * 1024]);
+        client.write(&msg).unwrap();
+        client.write_many(&msgs[..10]).unwrap();
+        client.close().unwrap();
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    for i in 0..1000 {
+        assert_eq!(s_client.read_blocking().unwrap().read_u32().unwrap(), i);
+    }
+    let mut msg = s_client.read_blocking().unwrap();
+    assert!(msg.read_buffer().unwrap().iter().all(|b| *b == 7));
+    for i in 0..10 {
+        assert_eq!(s_client.read_blocking().unwrap().read_u32().unwrap(), i);
+    }
+}
+
+#[test]
+fn buffered_read() {
+    let server = TcpServer::new("127.0.0.1:1562").expect("Failed to create server");
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1562").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        let msgs: Vec<Message> = (0..500)
+            .map(|i| {
+                let mut msg = Message::new();
+                msg.write_u32(i);
+                msg
+            })
+            .collect();
+        client.write_many(&msgs).unwrap();
+        client.close().unwrap();
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    sleep(Duration::from_millis(200));
+    // All messages have arrived, so they are read without waiting
+    for i in 0..500 {
+        let mut msg = s_client.read().unwrap().expect("Message was not buffered");
+        assert_eq!(msg.read_u32().unwrap(), i);
+    }
+    match s_client.read() {
+        Err(Error::ConnectionClosed) => {}
+        _ => panic!("Expected ConnectionClosed"),
+    }
+}
+
+#[test]
+fn compression_downgrade() {
+    let server = TcpServer::new("127.0.0.1:1572").expect("Failed to create server");
+    let proxy = net::TcpListener::bind("127.0.0.1:1573").unwrap();
+    spawn(move || {
+        let (mut client_side, _) = proxy.accept().unwrap();
+        let mut server_side = net::TcpStream::connect("127.0.0.1:1572").unwrap();
+        // Alter supported compression appended to the server key
+        let mut len = [0; 4];
+        server_side.read_exact(&mut len).unwrap();
+        let mut key = vec![0; u32::from_le_bytes(len) as usize];
+        server_side.read_exact(&mut key).unwrap();
+        *key.last_mut().unwrap() ^= 1;
+        client_side.write_all(&len).unwrap();
+        client_side.write_all(&key).unwrap();
+
+        let mut to_server = server_side.try_clone().unwrap();
+        let mut from_client = client_side.try_clone().unwrap();
+        spawn(move || std::io::copy(&mut from_client, &mut to_server));
+        let _ = std::io::copy(&mut server_side, &mut client_side);
+    });
+    spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1573").expect("Failed to connect to proxy");
+        client.wait_until_ready().unwrap();
+        let mut msg = Message::new();
+        msg.write_u32(1);
+        client.write_blocking(&msg).unwrap();
+        sleep(Duration::from_millis(500));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    match s_client.read_blocking() {
+        Err(Error::InvalidFrame) => {}
+        _ => panic!("Expected InvalidFrame"),
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compression() {
+    use super::Compression;
+
+    let mut server = TcpServer::new("127.0.0.1:1563").expect("Failed to create server");
+    server.set_compression(Some(Compression::Zstd));
+    let client = spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1563").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+        client.set_size_limit(1024 * 1024);
+
+        let mut msg = client.read_blocking().unwrap();
+        assert_eq!(msg.read_buffer().unwrap(), vec![b'a'; 100 * 1024]);
+        let mut msg = client.read_blocking().unwrap();
+        assert_eq!(msg.read_buffer().unwrap(), vec![b'b'; 100 * 1024]);
+        assert!(matches!(client.read_blocking(), Err(Error::SizeLimitExceeded)));
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    assert_eq!(s_client.negotiated_compression(), Some(Compression::Zstd));
+
+    let mut msg = Message::new();
+    msg.write_buffer(&[b'a'; 100 * 1024]);
+    let mut buf = Vec::new();
+    s_client.encrypt_message_into(&msg, &mut buf).unwrap();
+    assert!(buf.len() < 1024);
+    // Every frame has a sequence number, so the encrypted one has to be sent
+    s_client.write_encoded(&buf).unwrap();
+
+    let mut msg = Message::new();
+    msg.write_buffer(&[b'b'; 100 * 1024]);
+    msg.set_compressible(false);
+    let mut buf = Vec::new();
+    s_client.encrypt_message_into(&msg, &mut buf).unwrap();
+    assert!(buf.len() > 100 * 1024);
+    s_client.write_encoded(&buf).unwrap();
+
+    // Small on the wire, but over the size limit of the client once decompressed
+    let mut msg = Message::new();
+    msg.write_buffer(&[b'c'; 2 * 1024 * 1024]);
+    s_client.write_blocking(&msg).unwrap();
+    client.join().unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_value() {
+    use super::MessageError;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Scene {
+        name: String,
+        visible: bool,
+        marker: char,
+        parent: Option<u64>,
+        shapes: Vec<Shape>,
+        tags: BTreeMap<String, i16>,
+        origin: (i32, i32),
+    }
+
+    let mut tags = BTreeMap::new();
+    tags.insert("layer".to_string(), -3);
+    let scene = Scene {
+        name: "ščéna".to_string(),
+        visible: true,
+        marker: '✓',
+        parent: None,
+        shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+        tags,
+        origin: (-1, 1),
+    };
+
+    let server = TcpServer::new("127.0.0.1:1564").expect("Failed to create server");
+    let mut client = TcpStream::connect("127.0.0.1:1564").expect("Failed to connect to server");
+    let mut s_client = server.accept_blocking().unwrap();
+    client.wait_until_ready().unwrap();
+    s_client.wait_until_ready().unwrap();
+
+    client.write_value(&scene).unwrap();
+    client.write_value(&(7u8, "after")).unwrap();
+    client.flush().unwrap();
+
+    let mut msg = s_client.read_blocking().unwrap();
+    assert_eq!(msg.to_value::<Scene>().unwrap(), scene);
+    assert!(msg.read_u8().is_err());
+    let mut msg = s_client.read_blocking().unwrap();
+    assert_eq!(msg.to_value::<(u8, String)>().unwrap(), (7, "after".to_string()));
+
+    // Values written by hand are read back
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    msg.write_f64(2.5);
+    assert_eq!(msg.to_value::<Shape>().unwrap(), Shape::Circle(2.5));
+
+    let mut msg = Message::new();
+    msg.write_buffer(&[0xff, 0xfe]);
+    assert!(matches!(msg.to_value::<String>(), Err(MessageError::InvalidUtf8)));
+    let mut msg = Message::new();
+    msg.write_u8(2);
+    assert!(matches!(msg.to_value::<bool>(), Err(MessageError::InvalidValue)));
+    let mut msg = Message::new();
+    msg.write_u32(7);
+    assert!(matches!(msg.to_value::<Shape>(), Err(MessageError::Custom(_))));
+}
+
+#[test]
+fn message_collections() {
+    use super::MessageError;
+    use std::collections::{BTreeMap, HashMap};
+
+    let mut map = HashMap::new();
+    map.insert("one".to_string(), 1u32);
+    map.insert("two".to_string(), 2u32);
+
+    let mut msg = Message::new();
+    msg.write_str("žluťoučký kůň");
+    msg.write_bool(true);
+    msg.write_char('🦀');
+    msg.write_option(Some(&7u16));
+    msg.write_option::<u16>(None);
+    msg.write_seq(&["a".to_string(), "b".to_string()]);
+    msg.write_map(&map);
+    msg.write_seq(&[1u8, 2, 3]);
+
+    assert_eq!(msg.read_str().unwrap(), "žluťoučký kůň");
+    assert!(msg.read_bool().unwrap());
+    assert_eq!(msg.read_char().unwrap(), '🦀');
+    assert_eq!(msg.read_option::<u16>().unwrap(), Some(7));
+    assert_eq!(msg.read_option::<u16>().unwrap(), None);
+    assert_eq!(msg.read_seq::<String>(2).unwrap(), vec!["a", "b"]);
+    let read: BTreeMap<String, u32> = msg.read_map(2).unwrap();
+    assert_eq!(read.into_iter().collect::<HashMap<_, _>>(), map);
+    // Sequence of bytes is encoded the same way as a buffer
+    assert_eq!(msg.read_buffer().unwrap(), &[1, 2, 3]);
+
+    let mut msg = Message::new();
+    msg.write_seq(&[1u32, 2, 3]);
+    assert!(matches!(msg.read_seq::<u32>(2), Err(MessageError::LimitExceeded)));
+
+    let mut msg = Message::new();
+    msg.write_u32(u32::MAX);
+    assert!(matches!(msg.decode::<Vec<u8>>(), Err(MessageError::LimitExceeded)));
+
+    let mut msg = Message::new();
+    msg.write_buffer(&[0xc3, 0x28]);
+    msg.write_u8(2);
+    msg.write_u32(0xd800);
+    assert!(matches!(msg.read_str(), Err(MessageError::InvalidUtf8)));
+    assert!(matches!(msg.read_bool(), Err(MessageError::InvalidValue)));
+    assert!(matches!(msg.read_char(), Err(MessageError::InvalidValue)));
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+#[should_panic(expected = "map has more than u32::MAX elements")]
+fn message_length_overflow() {
+    let mut msg = Message::new();
+    msg.write_map((0..u32::MAX as usize + 1).map(|_| (&0u8, &0u8)));
+}
+
+#[test]
+fn message_varint() {
+    use super::MessageError;
+
+    let mut msg = Message::new();
+    msg.write_varint_u64(0);
+    msg.write_varint_u64(127);
+    msg.write_varint_u64(128);
+    msg.write_varint_u64(u64::MAX);
+    assert_eq!(msg.buffer.len(), 1 + 1 + 2 + 10);
+    msg.write_varint_i64(-1);
+    msg.write_varint_i64(63);
+    msg.write_varint_i64(-64);
+    msg.write_varint_i64(i64::MIN);
+    msg.write_varint_i32(i32::MAX);
+    msg.write_varint_u16(300);
+    msg.write_buffer_varint(&[1, 2, 3]);
+    assert_eq!(msg.buffer.len(), 14 + 1 + 1 + 1 + 10 + 5 + 2 + 4);
+
+    assert_eq!(msg.read_varint_u64().unwrap(), 0);
+    assert_eq!(msg.read_varint_u64().unwrap(), 127);
+    assert_eq!(msg.read_varint_u64().unwrap(), 128);
+    assert_eq!(msg.read_varint_u64().unwrap(), u64::MAX);
+    assert_eq!(msg.read_varint_i64().unwrap(), -1);
+    assert_eq!(msg.read_varint_i64().unwrap(), 63);
+    assert_eq!(msg.read_varint_i64().unwrap(), -64);
+    assert_eq!(msg.read_varint_i64().unwrap(), i64::MIN);
+    assert_eq!(msg.read_varint_i32().unwrap(), i32::MAX);
+    assert_eq!(msg.read_varint_u16().unwrap(), 300);
+    assert_eq!(msg.read_buffer_varint().unwrap(), &[1, 2, 3]);
+    assert!(matches!(msg.read_varint_u64(), Err(MessageError::UnexpectedEnd)));
+
+    let mut msg = Message::new();
+    msg.write_varint_u32(70000);
+    msg.write_varint_i64(i32::MIN as i64 - 1);
+    assert!(matches!(msg.read_varint_u16(), Err(MessageError::VarintOverflow)));
+    assert!(matches!(msg.read_varint_i32(), Err(MessageError::VarintOverflow)));
+
+    // 10th byte may only hold the highest bit of u64
+    let mut msg = Message::from_buffer(vec![0xff; 9]);
+    msg.write_u8(0x02);
+    assert!(matches!(msg.read_varint_u64(), Err(MessageError::VarintOverflow)));
+    let mut msg = Message::from_buffer(vec![0x80; 11]);
+    assert!(matches!(msg.read_varint_u64(), Err(MessageError::VarintOverflow)));
+    let mut msg = Message::from_buffer(vec![0x80, 0x80]);
+    assert!(matches!(msg.read_varint_u64(), Err(MessageError::UnexpectedEnd)));
+
+    let mut msg = Message::new();
+    msg.write_varint_u64(u64::MAX);
+    assert!(matches!(msg.read_buffer_varint(), Err(MessageError::UnexpectedEnd)));
+}
+
+#[test]
+fn message_tagged() {
+    use super::{MessageError, ValueType};
+
+    let mut msg = Message::new_tagged();
+    msg.write_u32(5);
+    msg.write_str("hi");
+    msg.write_seq(&[1u8, 2]);
+    msg.write_option(Some(&true));
+    msg.write_varint_i64(-1);
+    assert_eq!(
+        msg.inspect(),
+        "0: u32 5\n\
+         5: str \"hi\"\n\
+         12: seq 2\n  17: u8 1\n  19: u8 2\n\
+         21: option some\n  23: bool true\n\
+         25: varint_signed -1\n"
+    );
+
+    assert_eq!(msg.read_u32().unwrap(), 5);
+    match msg.read_buffer() {
+        Err(MessageError::TypeMismatch {
+            expected: ValueType::Buffer,
+            found: ValueType::Str,
+            offset: 5,
+        }) => {}
+        _ => panic!("Expected type mismatch"),
+    }
+    // Cursor is not moved by mismatched read
+    assert_eq!(msg.read_str().unwrap(), "hi");
+    assert_eq!(msg.read_seq::<u8>(2).unwrap(), vec![1, 2]);
+    assert_eq!(msg.read_option::<bool>().unwrap(), Some(true));
+    assert!(matches!(msg.read_varint_u64(), Err(MessageError::TypeMismatch { .. })));
+    assert_eq!(msg.read_varint_i64().unwrap(), -1);
+
+    msg.write_u8(0xee);
+    msg.buffer.push(0xee);
+    assert!(msg.inspect().ends_with("27: u8 238\n29: corrupted [ee]\n"));
+
+    // Deeply nested input does not overflow the stack
+    let mut msg = Message::new_tagged();
+    for _ in 0..100_000 {
+        msg.write_count(ValueType::Seq, 1);
+    }
+    let inspected = msg.inspect();
+    assert_eq!(inspected.lines().count(), 66);
+    assert!(inspected.lines().last().unwrap().trim_start().starts_with("325: corrupted"));
+
+    let mut msg = Message::new();
+    msg.write_u16(0x0201);
+    assert_eq!(msg.inspect(), "untagged [01 02]");
+}
+
+#[test]
+fn tagged_transfer() {
+    let server = TcpServer::new("127.0.0.1:1565").expect("Failed to create server");
+    let client = spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1565").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+
+        let mut msg = client.read_blocking().unwrap();
+        assert!(msg.is_tagged());
+        assert_eq!(msg.read_str().unwrap(), "tagged");
+        let mut msg = client.read_blocking().unwrap();
+        assert!(!msg.is_tagged());
+        assert_eq!(msg.read_str().unwrap(), "untagged");
+    });
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    let mut msg = Message::new_tagged();
+    msg.write_str("tagged");
+    s_client.write_blocking(&msg).unwrap();
+    let mut msg = Message::new();
+    msg.write_str("untagged");
+    s_client.write_blocking(&msg).unwrap();
+    client.join().unwrap();
+}
+
+#[test]
+fn message_cursor() {
+    use super::MessageError;
+
+    let mut inner = Message::new();
+    inner.write_u16(7);
+    inner.write_str("inner");
+
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    msg.write_buffer(inner.as_bytes());
+    msg.write_u8(2);
+
+    assert_eq!(msg.peek_u32().unwrap(), 1);
+    assert_eq!(msg.position(), 0);
+    assert_eq!(msg.read_u32().unwrap(), 1);
+    assert_eq!(msg.remaining(), 4 + 2 + 4 + 5 + 1);
+    {
+        let mut view = msg.read_message().unwrap();
+        assert_eq!(view.as_bytes(), inner.as_bytes());
+        assert_eq!(view.read_u16().unwrap(), 7);
+        assert_eq!(view.position(), 2);
+        assert_eq!(view.peek_str().unwrap(), "inner");
+        assert_eq!(view.read_str().unwrap(), "inner");
+        assert!(matches!(view.read_u8(), Err(MessageError::UnexpectedEnd)));
+        view.reset();
+        assert_eq!(view.read_u16().unwrap(), 7);
+    }
+    // Parent cursor skips the whole nested message
+    assert_eq!(msg.read_u8().unwrap(), 2);
+    assert_eq!(msg.remaining(), 0);
+
+    msg.set_position(4).unwrap();
+    assert_eq!(msg.peek_buffer().unwrap(), inner.as_bytes());
+    assert_eq!(msg.position(), 4);
+    assert!(matches!(msg.set_position(21), Err(MessageError::UnexpectedEnd)));
+    msg.reset();
+    assert_eq!(msg.peek(|msg| msg.decode::<u32>()).unwrap(), 1);
+
+    let bytes = msg.into_bytes();
+    let mut msg = Message::from(bytes);
+    assert_eq!(msg.read_u32().unwrap(), 1);
+    msg.set_position(19).unwrap();
+    assert!(matches!(msg.peek_str(), Err(MessageError::UnexpectedEnd)));
+    assert_eq!(msg.position(), 19);
+}
+
+#[test]
+fn message_view_transfer() {
+    let server = TcpServer::new("127.0.0.1:1567").expect("Failed to create server");
+    let client = spawn(|| {
+        let mut client = TcpStream::connect("127.0.0.1:1567").expect("Failed to connect to server");
+        client.wait_until_ready().unwrap();
+
+        let mut msg = client.read_blocking().unwrap();
+        assert_eq!(msg.read_str().unwrap(), "inner");
+        assert_eq!(msg.remaining(), 0);
+    });
+
+    let mut inner = Message::new();
+    inner.write_str("inner");
+    let mut msg = Message::new();
+    msg.write_u32(1);
+    msg.write_buffer(inner.as_bytes());
+    msg.write_u32(2);
+    msg.read_u32().unwrap();
+
+    let mut s_client = server.accept_blocking().unwrap();
+    s_client.wait_until_ready().unwrap();
+    // Only the nested message is sent
+    let view = msg.read_message().unwrap();
+    s_client.write_blocking(&view).unwrap();
+    client.join().unwrap();
+}
+
+#[test]
+fn message_io() {
+    use std::io::{BufRead, Read, Write};
+
+    let mut msg = Message::new();
+    msg.write_u16(2);
+    write!(msg, "{}-{}", 1, 2).unwrap();
+    msg.write_all(b"\nline").unwrap();
+
+    assert_eq!(msg.read_u16().unwrap(), 2);
+    let mut line = String::new();
+    msg.read_line(&mut line).unwrap();
+    assert_eq!(line, "1-2\n");
+    let mut rest = Vec::new();
+    msg.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"line");
+    assert_eq!(msg.read(&mut [0; 4]).unwrap(), 0);
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn message_bytes() {
+    use bytes::{Buf, BufMut};
+
+    let mut msg = Message::new();
+    msg.put_u32_le(7);
+    msg.put_slice(b"abc");
+    msg.write_u8(1);
+
+    assert_eq!(msg.get_u32_le(), 7);
+    assert_eq!(msg.chunk(), b"abc\x01");
+    msg.advance(3);
+    assert_eq!(msg.read_u8().unwrap(), 1);
+    assert!(!msg.has_remaining());
+}
+
+#[test]
+fn message_big_endian() {
+    use super::{MessageError, ValueType};
+
+    let mut msg = Message::new();
+    msg.write_u16_be(0x0102);
+    msg.write_i32_be(-2);
+    msg.write_u64_be(0x0102030405060708);
+    msg.write_f32_be(1.5);
+    msg.write_u16(0x0102);
+    assert_eq!(
+        msg.as_bytes(),
+        &[1, 2, 0xff, 0xff, 0xff, 0xfe, 1, 2, 3, 4, 5, 6, 7, 8, 0x3f, 0xc0, 0, 0, 2, 1]
+    );
+
+    assert_eq!(msg.read_u16_be().unwrap(), 0x0102);
+    assert_eq!(msg.read_i32_be().unwrap(), -2);
+    assert_eq!(msg.read_u64_be().unwrap(), 0x0102030405060708);
+    assert_eq!(msg.read_f32_be().unwrap(), 1.5);
+    assert_eq!(msg.read_u16_be().unwrap(), 0x0201);
+    assert!(msg.read_i128_be().is_err());
+
+    // Tagged values cannot be read in the other byte order
+    let mut msg = Message::new_tagged();
+    msg.write_u32_be(1);
+    msg.write_u32(2);
+    assert_eq!(msg.inspect(), "0: u32_be 1\n5: u32 2\n");
+    assert!(matches!(
+        msg.read_u32(),
+        Err(MessageError::TypeMismatch {
+            expected: ValueType::U32,
+            found: ValueType::U32Be,
+            offset: 0,
+        })
+    ));
+    assert_eq!(msg.read_u32_be().unwrap(), 1);
+    assert!(matches!(
+        msg.read_u32_be(),
+        Err(MessageError::TypeMismatch {
+            expected: ValueType::U32Be,
+            found: ValueType::U32,
+            offset: 5,
+        })
+    ));
+    assert_eq!(msg.read_u32().unwrap(), 2);
+}
+
+#[test]
+fn typed_stream() {
+    use super::{MessageError, TypedStream};
+
+    let server = TcpServer::new("127.0.0.1:1566").expect("Failed to create server");
+    let client = spawn(|| {
+        let client = TcpStream::connect("127.0.0.1:1566").expect("Failed to connect to server");
+        let mut client: TypedStream<u32, String> = TypedStream::new(client);
+        client.get_mut().wait_until_ready().unwrap();
+
+        client.send_blocking(&21).unwrap();
+        assert_eq!(client.recv().unwrap(), "42");
+        assert!(client.try_recv().unwrap().is_none());
+        assert!(matches!(
+            client.recv(),
+            Err(Error::MessageError(MessageError::UnexpectedEnd))
+        ));
+    });
+
+    let s_client = server.accept_blocking().unwrap();
+    let mut s_client: TypedStream<String, u32> = TypedStream::new(s_client);
+    s_client.get_mut().wait_until_ready().unwrap();
+    let n = s_client.recv().unwrap();
+    s_client.send_blocking(&(2 * n).to_string()).unwrap();
+    assert!(s_client.recv_timeout(100).unwrap().is_none());
+
+    // Not a string
+    let mut msg = Message::new();
+    msg.write_u8(1);
+    s_client.get_mut().write_blocking(&msg).unwrap();
+    client.join().unwrap();
+}
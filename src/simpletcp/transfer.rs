@@ -0,0 +1,404 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+
+use openssl::sha::Sha256;
+
+use super::{
+    Error, TcpStream, FRAME_STREAM_ACK, FRAME_STREAM_BEGIN, FRAME_STREAM_CANCEL,
+    FRAME_STREAM_CHUNK, FRAME_STREAM_END,
+};
+use crate::simpletcp::State::Ready;
+use crate::utils::{poll, EV_POLLOUT};
+
+// Size of a single chunk of streamed data
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Size of a chunk frame payload, such frames are received even if the size limit is lower
+pub(super) const MAX_CHUNK_FRAME: usize = 8 + CHUNK_SIZE;
+
+// Number of chunks that can be sent before they are acknowledged
+const WINDOW: u32 = 16;
+
+// Receiver acknowledges every ACK_INTERVAL consumed chunks
+const ACK_INTERVAL: u32 = 4;
+
+pub(super) struct OutgoingTransfer {
+    id: u64,
+    acked: u32,
+    cancelled: bool,
+}
+
+pub(super) struct IncomingTransfer {
+    id: u64,
+    chunks: VecDeque<Vec<u8>>,
+    offset: usize,
+    consumed: u32,
+    acked: u32,
+    hasher: Option<Sha256>,
+    hash: Option<[u8; 32]>,
+    cancelled: bool,
+    reading: bool,
+}
+
+/// Reader of a payload streamed using [write_stream](struct.TcpStream.html#method.write_stream)
+///
+/// Returned by [read_stream](struct.TcpStream.html#method.read_stream).
+/// Reading blocks until data arrives, messages received in the meantime can be read after the stream ends.
+/// Integrity of the whole payload is verified before the end of stream is reported,
+/// [read](https://doc.rust-lang.org/std/io/trait.Read.html#tymethod.read) returns `ErrorKind::InvalidData` if verification fails.
+pub struct StreamReader<'a> {
+    stream: &'a mut TcpStream,
+    id: u64,
+    finished: bool,
+}
+
+impl TcpStream {
+    /// Sends data from reader as a chunked stream
+    ///
+    /// Blocks until all data is sent and acknowledged by the peer.
+    /// At most 16 chunks of 64 KiB are in flight at once, so the payload can be of any size.
+    /// Messages received in the meantime can be read afterwards.
+    /// Chunks are not subject to the peer's [size limit](struct.TcpStream.html#method.set_size_limit).
+    /// If the reader returns an error, the transfer is cancelled.
+    /// # Arguments
+    ///
+    /// * `reader` - Source of the data
+    /// # Returns
+    /// Number of bytes sent or [StreamCancelled](enum.Error.html#variant.StreamCancelled) if the peer has cancelled the transfer
+    pub fn write_stream<R: Read>(&mut self, reader: &mut R) -> Result<u64, Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+        if self.local_closed {
+            return Err(Error::ConnectionClosed);
+        }
+
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        self.outgoing_transfer = Some(OutgoingTransfer {
+            id,
+            acked: 0,
+            cancelled: false,
+        });
+        self.write_frame(FRAME_STREAM_BEGIN, &id.to_le_bytes())?;
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; MAX_CHUNK_FRAME];
+        buf[..8].copy_from_slice(&id.to_le_bytes());
+        let mut sent = 0;
+        let mut total = 0;
+        loop {
+            while sent - self.wait_ack(sent)? >= WINDOW {
+                self.wait_frame()?;
+            }
+
+            let len = match reader.read(&mut buf[8..]) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.outgoing_transfer = None;
+                    self.write_frame(FRAME_STREAM_CANCEL, &id.to_le_bytes())?;
+                    return Err(Error::TcpError(e));
+                }
+            };
+            if len == 0 {
+                break;
+            }
+
+            hasher.update(&buf[8..8 + len]);
+            self.write_frame(FRAME_STREAM_CHUNK, &buf[..8 + len])?;
+            self.flush()?;
+            sent += 1;
+            total += len as u64;
+        }
+
+        let mut end = id.to_le_bytes().to_vec();
+        end.extend_from_slice(&hasher.finish());
+        self.write_frame(FRAME_STREAM_END, &end)?;
+        while !self.flush()? {
+            poll(self, EV_POLLOUT);
+        }
+
+        // End of stream is acknowledged as an additional chunk after the hash is verified
+        while self.wait_ack(sent + 1)? != sent + 1 {
+            self.wait_frame()?;
+        }
+        self.outgoing_transfer = None;
+        Ok(total)
+    }
+
+    /// Accepts incoming stream non-blocking
+    ///
+    /// # Returns
+    /// `Some(StreamReader)` or `None` if no stream has arrived
+    pub fn read_stream(&mut self) -> Result<Option<StreamReader<'_>>, Error> {
+        if self.state != Ready {
+            return Err(Error::NotReady);
+        }
+
+        loop {
+            if let Some(transfer) = &mut self.incoming_transfer {
+                if !transfer.reading {
+                    transfer.reading = true;
+                    let id = transfer.id;
+                    return Ok(Some(StreamReader {
+                        stream: self,
+                        id,
+                        finished: false,
+                    }));
+                }
+            }
+            if self.peer_closed {
+                return Err(Error::ConnectionClosed);
+            }
+            if !self.receive_frame()? {
+                self.heartbeat_tick()?;
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Accepts incoming stream blocking
+    ///
+    /// # Returns
+    /// [StreamReader](struct.StreamReader.html)
+    pub fn read_stream_blocking(&mut self) -> Result<StreamReader<'_>, Error> {
+        while !self.has_stream() {
+            if self.peer_closed {
+                return Err(Error::ConnectionClosed);
+            }
+            self.wait_frame()?;
+        }
+        Ok(self.read_stream()?.unwrap())
+    }
+
+    fn has_stream(&self) -> bool {
+        match &self.incoming_transfer {
+            Some(transfer) => !transfer.reading,
+            None => false,
+        }
+    }
+
+    // Returns number of acknowledged chunks of the outgoing transfer
+    //
+    // Acknowledgement of more than `max` chunks is a protocol violation,
+    // the end of stream counts as a chunk only after it was sent
+    fn wait_ack(&mut self, max: u32) -> Result<u32, Error> {
+        let transfer = self.outgoing_transfer.as_ref().unwrap();
+        if transfer.cancelled {
+            self.outgoing_transfer = None;
+            return Err(Error::StreamCancelled);
+        }
+        if self.peer_closed {
+            return Err(Error::ConnectionClosed);
+        }
+        if transfer.acked > max {
+            return Err(Error::InvalidFrame);
+        }
+        Ok(transfer.acked)
+    }
+
+    pub(super) fn handle_transfer_frame(&mut self, kind: u8, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() < 8 {
+            return Err(Error::InvalidFrame);
+        }
+        let id = u64::from_le_bytes(payload[..8].try_into().unwrap());
+        let data = &payload[8..];
+
+        match kind {
+            FRAME_STREAM_BEGIN => {
+                // The peer starts a new transfer only after the previous one has finished or was cancelled
+                if let Some(transfer) = &self.incoming_transfer {
+                    if !transfer.cancelled {
+                        return Err(Error::InvalidFrame);
+                    }
+                }
+                self.incoming_transfer = Some(IncomingTransfer {
+                    id,
+                    chunks: VecDeque::new(),
+                    offset: 0,
+                    consumed: 0,
+                    acked: 0,
+                    hasher: Some(Sha256::new()),
+                    hash: None,
+                    cancelled: false,
+                    reading: false,
+                });
+            }
+            FRAME_STREAM_CHUNK | FRAME_STREAM_END => {
+                let transfer = match &mut self.incoming_transfer {
+                    Some(transfer) if transfer.id == id => transfer,
+                    // Unknown or already finished transfer
+                    _ => return Ok(()),
+                };
+                // Transfer was cancelled by us, chunks sent in the meantime are dropped
+                if transfer.cancelled || transfer.hash.is_some() {
+                    return Ok(());
+                }
+                if kind == FRAME_STREAM_CHUNK {
+                    if transfer.chunks.len() >= WINDOW as usize {
+                        return Err(Error::InvalidFrame);
+                    }
+                    transfer.chunks.push_back(data.to_vec());
+                } else {
+                    transfer.hash = Some(data.try_into().map_err(|_| Error::InvalidFrame)?);
+                }
+            }
+            FRAME_STREAM_ACK => {
+                if let Some(transfer) = &mut self.outgoing_transfer {
+                    if transfer.id == id {
+                        let acked = data.try_into().map_err(|_| Error::InvalidFrame)?;
+                        transfer.acked = u32::from_le_bytes(acked);
+                    }
+                }
+            }
+            FRAME_STREAM_CANCEL => {
+                if let Some(transfer) = &mut self.outgoing_transfer {
+                    if transfer.id == id {
+                        transfer.cancelled = true;
+                    }
+                }
+                if let Some(transfer) = &mut self.incoming_transfer {
+                    if transfer.id == id {
+                        transfer.cancelled = true;
+                    }
+                }
+            }
+            _ => return Err(Error::InvalidFrame),
+        }
+        Ok(())
+    }
+
+    fn send_ack(&mut self, id: u64, count: u32) -> Result<(), Error> {
+        let mut ack = id.to_le_bytes().to_vec();
+        ack.extend_from_slice(&count.to_le_bytes());
+        self.write_frame(FRAME_STREAM_ACK, &ack)?;
+        self.flush()?;
+        Ok(())
+    }
+}
+
+impl<'a> StreamReader<'a> {
+    /// Cancels the transfer
+    ///
+    /// The sender's [write_stream](struct.TcpStream.html#method.write_stream) returns [StreamCancelled](enum.Error.html#variant.StreamCancelled)
+    pub fn cancel(mut self) -> Result<(), Error> {
+        self.cancel_transfer()
+    }
+
+    fn cancel_transfer(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        let cancelled_by_peer = match &mut self.stream.incoming_transfer {
+            Some(transfer) if transfer.id == self.id => {
+                transfer.chunks.clear();
+                let cancelled = transfer.cancelled;
+                transfer.cancelled = true;
+                cancelled
+            }
+            _ => true,
+        };
+        if !cancelled_by_peer && !self.stream.local_closed {
+            self.stream
+                .write_frame(FRAME_STREAM_CANCEL, &self.id.to_le_bytes())?;
+            self.stream.flush()?;
+        }
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> Result<Option<usize>, io::Error> {
+        let transfer = match &mut self.stream.incoming_transfer {
+            Some(transfer) if transfer.id == self.id => transfer,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "stream is not active",
+                ))
+            }
+        };
+
+        if let Some(chunk) = transfer.chunks.front() {
+            let len = buf.len().min(chunk.len() - transfer.offset);
+            buf[..len].copy_from_slice(&chunk[transfer.offset..transfer.offset + len]);
+            transfer.hasher.as_mut().unwrap().update(&buf[..len]);
+            transfer.offset += len;
+            if transfer.offset == chunk.len() {
+                transfer.chunks.pop_front();
+                transfer.offset = 0;
+                transfer.consumed += 1;
+                if transfer.consumed - transfer.acked >= ACK_INTERVAL {
+                    let consumed = transfer.consumed;
+                    transfer.acked = consumed;
+                    self.stream
+                        .send_ack(self.id, consumed)
+                        .map_err(to_io_error)?;
+                }
+            }
+            return Ok(Some(len));
+        }
+
+        if transfer.cancelled || self.stream.peer_closed {
+            self.finished = true;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was cancelled by the peer",
+            ));
+        }
+
+        if let Some(hash) = transfer.hash {
+            let actual = transfer.hasher.take().unwrap().finish();
+            if actual != hash {
+                self.cancel_transfer().map_err(to_io_error)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream integrity check failed",
+                ));
+            }
+            let consumed = transfer.consumed + 1;
+            self.stream.incoming_transfer = None;
+            self.finished = true;
+            self.stream
+                .send_ack(self.id, consumed)
+                .map_err(to_io_error)?;
+            return Ok(Some(0));
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a> Read for StreamReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(len) = self.read_chunk(buf)? {
+                return Ok(len);
+            }
+            self.stream.wait_frame().map_err(to_io_error)?;
+        }
+    }
+}
+
+impl<'a> Drop for StreamReader<'a> {
+    fn drop(&mut self) {
+        let _ = self.cancel_transfer();
+    }
+}
+
+fn to_io_error(err: Error) -> io::Error {
+    match err {
+        Error::TcpError(io_err) => io_err,
+        err => io::Error::other(format!("{:?}", err)),
+    }
+}
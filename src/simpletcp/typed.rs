@@ -0,0 +1,162 @@
+use std::marker::PhantomData;
+
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::{Error, Message, MessageDecode, MessageEncode, MessageError, TcpStream};
+
+/// Conversion between values and messages used by [TypedStream](struct.TypedStream.html)
+///
+/// `Req` is the type of sent values, `Resp` is the type of received values
+pub trait Codec<Req, Resp> {
+    /// Appends sent value to the message
+    fn encode(&mut self, value: &Req, msg: &mut Message) -> Result<(), MessageError>;
+
+    /// Reads received value from the message
+    fn decode(&mut self, msg: &mut Message) -> Result<Resp, MessageError>;
+}
+
+/// Codec using [MessageEncode](trait.MessageEncode.html) and [MessageDecode](trait.MessageDecode.html)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageCodec;
+
+impl<Req: MessageEncode, Resp: MessageDecode> Codec<Req, Resp> for MessageCodec {
+    fn encode(&mut self, value: &Req, msg: &mut Message) -> Result<(), MessageError> {
+        value.encode(msg);
+        Ok(())
+    }
+
+    fn decode(&mut self, msg: &mut Message) -> Result<Resp, MessageError> {
+        msg.decode()
+    }
+}
+
+/// Codec using serde, requires `serde` cargo feature
+///
+/// See [Message::from_value](struct.Message.html#method.from_value)
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerdeCodec;
+
+#[cfg(feature = "serde")]
+impl<Req: Serialize, Resp: DeserializeOwned> Codec<Req, Resp> for SerdeCodec {
+    fn encode(&mut self, value: &Req, msg: &mut Message) -> Result<(), MessageError> {
+        msg.write_value(value)
+    }
+
+    fn decode(&mut self, msg: &mut Message) -> Result<Resp, MessageError> {
+        msg.to_value()
+    }
+}
+
+/// Stream sending and receiving values of concrete types
+///
+/// Wraps [TcpStream](struct.TcpStream.html), every value is sent as one message.
+/// Values that cannot be decoded are reported as [MessageError](enum.Error.html#variant.MessageError).
+/// The peer uses `TypedStream<Resp, Req>` with a compatible codec.
+pub struct TypedStream<Req, Resp, C = MessageCodec> {
+    stream: TcpStream,
+    codec: C,
+    types: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req, Resp> TypedStream<Req, Resp, MessageCodec>
+where
+    MessageCodec: Codec<Req, Resp>,
+{
+    /// Wraps the stream using [MessageCodec](struct.MessageCodec.html)
+    pub fn new(stream: TcpStream) -> Self {
+        TypedStream::with_codec(stream, MessageCodec)
+    }
+}
+
+impl<Req, Resp, C: Codec<Req, Resp>> TypedStream<Req, Resp, C> {
+    /// Wraps the stream
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connected stream
+    /// * `codec` - Codec of sent and received values
+    pub fn with_codec(stream: TcpStream, codec: C) -> Self {
+        TypedStream {
+            stream,
+            codec,
+            types: PhantomData,
+        }
+    }
+
+    /// Encodes and writes a value
+    ///
+    /// See [write](struct.TcpStream.html#method.write)
+    pub fn send(&mut self, value: &Req) -> Result<(), Error> {
+        let msg = self.encode(value)?;
+        self.stream.write(&msg)
+    }
+
+    /// Encodes and writes a value blocking
+    ///
+    /// See [write_blocking](struct.TcpStream.html#method.write_blocking)
+    pub fn send_blocking(&mut self, value: &Req) -> Result<(), Error> {
+        let msg = self.encode(value)?;
+        self.stream.write_blocking(&msg)
+    }
+
+    /// Reads and decodes a value blocking
+    ///
+    /// See [read_blocking](struct.TcpStream.html#method.read_blocking)
+    pub fn recv(&mut self) -> Result<Resp, Error> {
+        let mut msg = self.stream.read_blocking()?;
+        Ok(self.codec.decode(&mut msg)?)
+    }
+
+    /// Reads and decodes a value non-blocking
+    ///
+    /// See [read](struct.TcpStream.html#method.read)
+    /// # Returns
+    /// Returns `Some(Resp)` or `None` if no message has arrived
+    pub fn try_recv(&mut self) -> Result<Option<Resp>, Error> {
+        match self.stream.read()? {
+            None => Ok(None),
+            Some(mut msg) => Ok(Some(self.codec.decode(&mut msg)?)),
+        }
+    }
+
+    /// Reads and decodes a value blocking with timeout
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Timeout in milliseconds
+    /// # Returns
+    /// Returns `Some(Resp)` or `None` if reading timed out
+    pub fn recv_timeout(&mut self, timeout: i32) -> Result<Option<Resp>, Error> {
+        match self.stream.read_timeout(timeout)? {
+            None => Ok(None),
+            Some(mut msg) => Ok(Some(self.codec.decode(&mut msg)?)),
+        }
+    }
+
+    /// Returns reference to the underlying stream
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Returns mutable reference to the underlying stream
+    ///
+    /// Messages written directly must be understood by the peer's codec
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// Returns the underlying stream
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    fn encode(&mut self, value: &Req) -> Result<Message, MessageError> {
+        let mut msg = Message::new();
+        self.codec.encode(value, &mut msg)?;
+        Ok(msg)
+    }
+}
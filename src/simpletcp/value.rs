@@ -0,0 +1,682 @@
+use std::fmt::Display;
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use super::{Error, Message, MessageError, TcpStream, ValueType};
+
+/// Serde serializer appending values to a [Message](struct.Message.html)
+///
+/// Values are encoded with the same little-endian encoding as `Message::write_*` methods:
+/// * `bool` - `u8` 0 or 1
+/// * `char` - `u32`
+/// * strings and bytes - same as [write_buffer](struct.Message.html#method.write_buffer)
+/// * `Option` - `u8` tag 0 or 1 followed by the value
+/// * sequences and maps - `u32` length followed by the elements
+/// * structs and tuples - fields in order without any names
+/// * enums - `u32` variant index followed by the content
+///
+/// See [from_value](struct.Message.html#method.from_value)
+pub struct Serializer<'a> {
+    msg: &'a mut Message,
+}
+
+impl<'a> Serializer<'a> {
+    /// Creates a serializer appending to the message
+    pub fn new(msg: &'a mut Message) -> Self {
+        Serializer { msg }
+    }
+
+    fn write_len(&mut self, ty: ValueType, len: Option<usize>) -> Result<(), MessageError> {
+        let len = len
+            .ok_or_else(|| MessageError::Custom("Length of sequence must be known.".to_string()))?;
+        if len > u32::MAX as usize {
+            return Err(MessageError::InvalidValue);
+        }
+        self.msg.write_count(ty, len);
+        Ok(())
+    }
+}
+
+/// Serde deserializer reading values from a [Message](struct.Message.html)
+///
+/// Reads from the read cursor and moves it, see [Serializer](struct.Serializer.html) for the encoding.
+/// The encoding does not describe itself, so types relying on `deserialize_any` are not supported.
+/// See [to_value](struct.Message.html#method.to_value)
+pub struct Deserializer<'a> {
+    msg: &'a mut Message,
+}
+
+impl<'a> Deserializer<'a> {
+    /// Creates a deserializer reading from the message
+    pub fn new(msg: &'a mut Message) -> Self {
+        Deserializer { msg }
+    }
+}
+
+impl ser::Error for MessageError {
+    fn custom<T: Display>(msg: T) -> Self {
+        MessageError::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for MessageError {
+    fn custom<T: Display>(msg: T) -> Self {
+        MessageError::Custom(msg.to_string())
+    }
+}
+
+impl Message {
+    /// Creates a message containing serialized value
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value to be serialized
+    /// # Returns
+    /// Message or [MessageError](enum.MessageError.html) if the value cannot be encoded,
+    /// e.g. a sequence of unknown length
+    pub fn from_value<T: Serialize + ?Sized>(value: &T) -> Result<Message, MessageError> {
+        let mut msg = Message::new();
+        msg.write_value(value)?;
+        Ok(msg)
+    }
+
+    /// Appends serialized value to the message
+    ///
+    /// See [from_value](struct.Message.html#method.from_value)
+    pub fn write_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        value.serialize(&mut Serializer::new(self))
+    }
+
+    /// Deserializes value and moves read cursor
+    /// # Returns
+    /// `T` or [MessageError](enum.MessageError.html) if reading failed
+    pub fn to_value<T: DeserializeOwned>(&mut self) -> Result<T, MessageError> {
+        T::deserialize(&mut Deserializer::new(self))
+    }
+}
+
+impl TcpStream {
+    /// Serializes value into a message and writes it
+    ///
+    /// See [Message::from_value](struct.Message.html#method.from_value) and [write](struct.TcpStream.html#method.write)
+    /// # Arguments
+    ///
+    /// * `value` - Value to be sent
+    /// # Returns
+    /// [MessageError](enum.Error.html#variant.MessageError) if the value cannot be encoded
+    pub fn write_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let msg = Message::from_value(value)?;
+        self.write(&msg)
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), MessageError> {
+        self.msg.write_bool(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), MessageError> {
+        self.msg.write_i8(v);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), MessageError> {
+        self.msg.write_i16(v);
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), MessageError> {
+        self.msg.write_i32(v);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), MessageError> {
+        self.msg.write_i64(v);
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), MessageError> {
+        self.msg.write_i128(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), MessageError> {
+        self.msg.write_u8(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), MessageError> {
+        self.msg.write_u16(v);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), MessageError> {
+        self.msg.write_u32(v);
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), MessageError> {
+        self.msg.write_u64(v);
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), MessageError> {
+        self.msg.write_u128(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), MessageError> {
+        self.msg.write_f32(v);
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), MessageError> {
+        self.msg.write_f64(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), MessageError> {
+        self.msg.write_char(v);
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), MessageError> {
+        if v.len() > u32::MAX as usize {
+            return Err(MessageError::InvalidValue);
+        }
+        self.msg.write_str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), MessageError> {
+        if v.len() > u32::MAX as usize {
+            return Err(MessageError::InvalidValue);
+        }
+        self.msg.write_buffer(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), MessageError> {
+        self.msg.write_option_flag(false);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), MessageError> {
+        self.msg.write_option_flag(true);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), MessageError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), MessageError> {
+        self.msg.write_u32(variant_index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), MessageError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), MessageError> {
+        self.msg.write_u32(variant_index);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, MessageError> {
+        self.write_len(ValueType::Seq, len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, MessageError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self, MessageError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, MessageError> {
+        self.msg.write_u32(variant_index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, MessageError> {
+        self.write_len(ValueType::Map, len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, MessageError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, MessageError> {
+        self.msg.write_u32(variant_index);
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'a, 'b> ser::SerializeSeq for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTuple for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleStruct for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleVariant for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeMap for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), MessageError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeStruct for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeStructVariant for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = MessageError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), MessageError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+}
+
+impl<'de, 'a, 'b> de::Deserializer<'de> for &'a mut Deserializer<'b> {
+    type Error = MessageError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, MessageError> {
+        Err(MessageError::Custom(
+            "Message encoding does not describe itself, deserialize_any is not supported."
+                .to_string(),
+        ))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_bool(self.msg.read_bool()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i8(self.msg.read_i8()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i16(self.msg.read_i16()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i32(self.msg.read_i32()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i64(self.msg.read_i64()?)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_i128(self.msg.read_i128()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u8(self.msg.read_u8()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u16(self.msg.read_u16()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u32(self.msg.read_u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u64(self.msg.read_u64()?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_u128(self.msg.read_u128()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_f32(self.msg.read_f32()?)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_f64(self.msg.read_f64()?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_char(self.msg.read_char()?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_str(self.msg.read_str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_bytes(self.msg.read_buffer()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        if self.msg.read_option_flag()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        let len = self.msg.read_count(ValueType::Seq, usize::MAX)?;
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_seq(Access { de: self, len })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        let len = self.msg.read_count(ValueType::Map, usize::MAX)?;
+        visitor.visit_map(Access { de: self, len })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_seq(Access {
+            de: self,
+            len: fields.len(),
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MessageError> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        self.deserialize_any(visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// Elements of a sequence, tuple, struct or map with known length
+struct Access<'a, 'b> {
+    de: &'a mut Deserializer<'b>,
+    len: usize,
+}
+
+impl<'de, 'a, 'b> de::SeqAccess<'de> for Access<'a, 'b> {
+    type Error = MessageError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, MessageError> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'de, 'a, 'b> de::MapAccess<'de> for Access<'a, 'b> {
+    type Error = MessageError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, MessageError> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, MessageError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'de, 'a, 'b> de::EnumAccess<'de> for &'a mut Deserializer<'b> {
+    type Error = MessageError;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), MessageError> {
+        let index = self.msg.read_u32()?;
+        let value = seed.deserialize(index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de, 'a, 'b> de::VariantAccess<'de> for &'a mut Deserializer<'b> {
+    type Error = MessageError;
+
+    fn unit_variant(self) -> Result<(), MessageError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, MessageError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MessageError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
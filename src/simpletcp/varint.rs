@@ -0,0 +1,157 @@
+use std::convert::TryFrom;
+
+use super::{Message, MessageError, ValueType};
+
+// Maps signed integers to unsigned so that values close to zero stay small
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+impl Message {
+    /// Appends 16-bit unsigned integer encoded as LEB128 varint to the message
+    ///
+    /// See [write_varint_u64](struct.Message.html#method.write_varint_u64)
+    pub fn write_varint_u16(&mut self, n: u16) {
+        self.write_varint_u64(n as u64);
+    }
+
+    /// Appends 32-bit unsigned integer encoded as LEB128 varint to the message
+    ///
+    /// See [write_varint_u64](struct.Message.html#method.write_varint_u64)
+    pub fn write_varint_u32(&mut self, n: u32) {
+        self.write_varint_u64(n as u64);
+    }
+
+    /// Appends 64-bit unsigned integer encoded as LEB128 varint to the message
+    ///
+    /// Every byte holds 7 bits, so values below 128 take 1 byte and the largest values take 10 bytes
+    pub fn write_varint_u64(&mut self, n: u64) {
+        self.write_tag(ValueType::Varint);
+        self.put_varint(n);
+    }
+
+    /// Appends 16-bit signed integer encoded as zigzag varint to the message
+    ///
+    /// See [write_varint_i64](struct.Message.html#method.write_varint_i64)
+    pub fn write_varint_i16(&mut self, n: i16) {
+        self.write_varint_i64(n as i64);
+    }
+
+    /// Appends 32-bit signed integer encoded as zigzag varint to the message
+    ///
+    /// See [write_varint_i64](struct.Message.html#method.write_varint_i64)
+    pub fn write_varint_i32(&mut self, n: i32) {
+        self.write_varint_i64(n as i64);
+    }
+
+    /// Appends 64-bit signed integer encoded as zigzag varint to the message
+    ///
+    /// Values are mapped to unsigned as 0, -1, 1, -2, 2, ... so values between -64 and 63 take 1 byte
+    pub fn write_varint_i64(&mut self, n: i64) {
+        self.write_tag(ValueType::VarintSigned);
+        self.put_varint(zigzag(n));
+    }
+
+    /// Appends buffer with varint length to the message
+    ///
+    /// Same as [write_buffer](struct.Message.html#method.write_buffer), but short buffers take less space
+    pub fn write_buffer_varint(&mut self, buf: &[u8]) {
+        self.write_tag(ValueType::BufferVarint);
+        self.put_varint(buf.len() as u64);
+        self.buffer.extend_from_slice(buf);
+    }
+
+    /// Reads 16-bit unsigned varint and moves read cursor
+    /// # Returns
+    /// `u16` or [MessageError](enum.MessageError.html) if reading failed or the value does not fit into `u16`
+    pub fn read_varint_u16(&mut self) -> Result<u16, MessageError> {
+        u16::try_from(self.read_varint_u64()?).map_err(|_| MessageError::VarintOverflow)
+    }
+
+    /// Reads 32-bit unsigned varint and moves read cursor
+    /// # Returns
+    /// `u32` or [MessageError](enum.MessageError.html) if reading failed or the value does not fit into `u32`
+    pub fn read_varint_u32(&mut self) -> Result<u32, MessageError> {
+        u32::try_from(self.read_varint_u64()?).map_err(|_| MessageError::VarintOverflow)
+    }
+
+    /// Reads 64-bit unsigned varint and moves read cursor
+    /// # Returns
+    /// `u64` or [MessageError](enum.MessageError.html) if reading failed or the value does not fit into `u64`
+    pub fn read_varint_u64(&mut self) -> Result<u64, MessageError> {
+        self.read_tag(ValueType::Varint)?;
+        self.take_varint()
+    }
+
+    /// Reads 16-bit signed zigzag varint and moves read cursor
+    /// # Returns
+    /// `i16` or [MessageError](enum.MessageError.html) if reading failed or the value does not fit into `i16`
+    pub fn read_varint_i16(&mut self) -> Result<i16, MessageError> {
+        i16::try_from(self.read_varint_i64()?).map_err(|_| MessageError::VarintOverflow)
+    }
+
+    /// Reads 32-bit signed zigzag varint and moves read cursor
+    /// # Returns
+    /// `i32` or [MessageError](enum.MessageError.html) if reading failed or the value does not fit into `i32`
+    pub fn read_varint_i32(&mut self) -> Result<i32, MessageError> {
+        i32::try_from(self.read_varint_i64()?).map_err(|_| MessageError::VarintOverflow)
+    }
+
+    /// Reads 64-bit signed zigzag varint and moves read cursor
+    /// # Returns
+    /// `i64` or [MessageError](enum.MessageError.html) if reading failed or the value does not fit into `i64`
+    pub fn read_varint_i64(&mut self) -> Result<i64, MessageError> {
+        self.read_tag(ValueType::VarintSigned)?;
+        self.take_varint().map(unzigzag)
+    }
+
+    /// Reads buffer with varint length and moves read cursor
+    /// # Returns
+    /// `&[u8]` or [MessageError](enum.MessageError.html) if reading failed
+    pub fn read_buffer_varint(&mut self) -> Result<&[u8], MessageError> {
+        self.read_tag(ValueType::BufferVarint)?;
+        let len = usize::try_from(self.take_varint()?).map_err(|_| MessageError::VarintOverflow)?;
+        if self.remaining() < len {
+            return Err(MessageError::UnexpectedEnd);
+        }
+        let slice = &self.buffer[self.read_pos..self.read_pos + len];
+        self.read_pos += len;
+        Ok(slice)
+    }
+
+    // Varint without type tag
+    fn put_varint(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.buffer.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        self.buffer.push(n as u8);
+    }
+
+    fn take_varint(&mut self) -> Result<u64, MessageError> {
+        let mut n: u64 = 0;
+        let mut pos = self.read_pos;
+        for shift in (0..64).step_by(7) {
+            if pos >= self.end() {
+                return Err(MessageError::UnexpectedEnd);
+            }
+            let byte = self.buffer[pos];
+            pos += 1;
+            let bits = (byte & 0x7f) as u64;
+            // Only the lowest bit of the 10th byte fits into u64
+            if shift == 63 && bits > 1 {
+                return Err(MessageError::VarintOverflow);
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                self.read_pos = pos;
+                return Ok(n);
+            }
+        }
+        Err(MessageError::VarintOverflow)
+    }
+}
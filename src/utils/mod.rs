@@ -152,6 +152,20 @@ pub fn get_fd_array<A: AsRawFd>(sockets: &[A]) -> Vec<i32> {
     res
 }
 
+/// Gets descriptor of a socket
+///
+/// Useful for building sets of different socket types, see [get_fd_array](fn.get_fd_array.html)
+/// # Arguments
+///
+/// * `socket` - Socket
+///
+/// # Returns
+/// Socket descriptor
+#[cfg(unix)]
+pub fn get_fd<A: AsRawFd>(socket: &A) -> i32 {
+    socket.as_raw_fd()
+}
+
 /// Polls the socket
 ///
 /// # Arguments
@@ -289,6 +303,20 @@ pub fn get_fd_array<A: AsRawSocket>(sockets: &[A]) -> Vec<u64> {
     res
 }
 
+/// Gets descriptor of a socket
+///
+/// Useful for building sets of different socket types, see [get_fd_array](fn.get_fd_array.html)
+/// # Arguments
+///
+/// * `socket` - Socket
+///
+/// # Returns
+/// Socket descriptor
+#[cfg(windows)]
+pub fn get_fd<A: AsRawSocket>(socket: &A) -> u64 {
+    socket.as_raw_socket()
+}
+
 unsafe fn translate_event(ev: i16) -> i16 {
     let mut translated = 0;
     if (ev & EV_POLLIN) != 0 {
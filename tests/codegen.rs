@@ -0,0 +1,154 @@
+use simpletcp::simpletcp::{Message, MessageEncode, MessageError};
+use simpletcp_codegen::{check_compatible, generate, Error};
+
+mod v1 {
+    include!("schema/protocol_v1.rs");
+}
+
+mod v2 {
+    include!("schema/protocol.rs");
+}
+
+#[test]
+fn codegen_output() {
+    // Generated code is checked in, so that it is compiled and linted with the tests
+    assert_eq!(
+        generate(include_str!("schema/protocol_v1.stcp")).unwrap(),
+        include_str!("schema/protocol_v1.rs")
+    );
+    assert_eq!(
+        generate(include_str!("schema/protocol.stcp")).unwrap(),
+        include_str!("schema/protocol.rs")
+    );
+}
+
+#[test]
+fn codegen_round_trip() {
+    let mut user = v2::User {
+        id: 1,
+        name: "admin".to_string(),
+        role: v2::Role::Moderator,
+        path: vec![v2::Point { x: 1, y: -1 }],
+        email: Some("admin@example.com".to_string()),
+        r#type: 3,
+        ..Default::default()
+    };
+    user.permissions.insert(v2::Role::Admin, vec![1, 2]);
+
+    let mut msg = Message::encode(&user);
+    msg.write_u8(7);
+    assert_eq!(msg.decode::<v2::User>().unwrap(), user);
+    assert_eq!(msg.read_u8().unwrap(), 7);
+
+    let mut msg = Message::new_tagged();
+    v2::Empty {}.encode(&mut msg);
+    user.encode(&mut msg);
+    assert_eq!(msg.decode::<v2::Empty>().unwrap(), v2::Empty {});
+    assert_eq!(msg.decode::<v2::User>().unwrap(), user);
+}
+
+#[test]
+fn codegen_versions() {
+    let user = v2::User {
+        id: 1,
+        name: "user".to_string(),
+        role: v2::Role::Admin,
+        email: Some("user@example.com".to_string()),
+        ..Default::default()
+    };
+
+    // Older peer skips new fields
+    let mut msg = Message::encode(&user);
+    msg.write_u8(7);
+    let old = msg.decode::<v1::User>().unwrap();
+    assert_eq!(old.id, 1);
+    assert_eq!(old.name, "user");
+    assert_eq!(old.role, v1::Role::Admin);
+    assert_eq!(msg.read_u8().unwrap(), 7);
+
+    let mut msg = Message::encode(&v2::Point { x: 1, y: 2 });
+    assert_eq!(msg.decode::<v1::Point>().unwrap(), v1::Point { x: 1, y: 2 });
+
+    // Newer peer uses defaults for missing fields
+    let mut msg = Message::encode(&old);
+    let new = msg.decode::<v2::User>().unwrap();
+    assert_eq!(new.email, None);
+    assert_eq!(new.role, v2::Role::Admin);
+
+    // Unknown enum variant
+    let mut msg = Message::encode(&v2::Role::Moderator);
+    assert!(matches!(
+        msg.decode::<v1::Role>(),
+        Err(MessageError::InvalidValue)
+    ));
+}
+
+#[test]
+fn codegen_nesting_limit() {
+    let mut node = v2::Node::default();
+    for _ in 0..20 {
+        node = v2::Node {
+            children: vec![node],
+        };
+    }
+    let mut msg = Message::encode(&node);
+    assert_eq!(msg.decode::<v2::Node>().unwrap(), node);
+
+    // Every level is a nested message containing a list
+    for _ in 0..20 {
+        node = v2::Node {
+            children: vec![node],
+        };
+    }
+    let mut msg = Message::encode(&node);
+    assert!(matches!(
+        msg.decode::<v2::Node>(),
+        Err(MessageError::LimitExceeded)
+    ));
+}
+
+#[test]
+fn codegen_compatibility() {
+    let v1 = include_str!("schema/protocol_v1.stcp");
+    let v2 = include_str!("schema/protocol.stcp");
+    check_compatible(v1, v2).unwrap();
+    assert!(matches!(
+        check_compatible(v2, v1),
+        Err(Error::Incompatible(_))
+    ));
+
+    let old = "record A { x: u8; y: u8 @since(2); }";
+    let incompatible = [
+        "record A { x: u8; }",
+        "record A { y: u8; x: u8 @since(2); }",
+        "record A { x: u16; y: u8 @since(2); }",
+        "record A { x: u8; y: u8 @since(2); z: u8 @since(2); }",
+        "enum A { X = 0; }",
+    ];
+    for new in incompatible.iter() {
+        assert!(matches!(
+            check_compatible(old, new),
+            Err(Error::Incompatible(_))
+        ));
+    }
+    check_compatible(old, "record A { x: u8; y: u8 @since(2); z: u8 @since(3); }").unwrap();
+
+    let invalid = [
+        ("record A { x: u8 }", 1),
+        ("record A {\n x: B;\n}", 2),
+        ("record A { x: u8 @since(2); y: u8; }", 1),
+        ("record A { a: option<A>; }", 1),
+        ("record A { x: map<f32, u8>; }", 1),
+        ("enum A {\n X = 0;\n Y = 0;\n}", 3),
+        ("enum A {}", 1),
+        ("record A {}\nrecord A {}", 2),
+        ("record A { self: u8; }", 1),
+        ("message A {}", 1),
+    ];
+    for (schema, line) in invalid.iter() {
+        match generate(schema) {
+            Err(Error::Schema { line: l, .. }) => assert_eq!(l, *line, "{}", schema),
+            _ => panic!("Schema should be invalid: {}", schema),
+        }
+    }
+}
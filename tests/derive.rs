@@ -0,0 +1,140 @@
+#![cfg(feature = "derive")]
+
+use simpletcp::simpletcp::{Message, MessageDecode, MessageEncode, MessageError};
+use std::collections::HashMap;
+
+#[derive(MessageEncode, MessageDecode, Debug, PartialEq)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+#[derive(MessageEncode, MessageDecode, Debug, PartialEq)]
+struct Header(
+    #[message(varint)] u64,
+    u8,
+    #[message(varint)] i32,
+    #[message(varint)] Vec<u8>,
+);
+
+#[derive(MessageEncode, MessageDecode, Debug, PartialEq)]
+enum Shape {
+    Empty,
+    Circle { center: Point, radius: f64 },
+    Polygon(#[message(varint)] u32, #[message(buffer)] Vec<u8>),
+}
+
+#[derive(MessageEncode, MessageDecode, Debug, PartialEq)]
+struct Wrapper<T> {
+    id: u16,
+    inner: T,
+}
+
+#[derive(MessageEncode, MessageDecode, Debug, PartialEq)]
+struct User {
+    name: String,
+    active: bool,
+    initial: char,
+    email: Option<String>,
+    groups: Vec<u32>,
+    settings: HashMap<String, Option<i64>>,
+    shapes: Vec<Shape>,
+}
+
+#[derive(MessageEncode, MessageDecode, Debug, Default, PartialEq)]
+struct Tree {
+    children: Vec<Tree>,
+}
+
+#[test]
+fn derive_round_trip() {
+    let shapes = vec![
+        Shape::Empty,
+        Shape::Circle {
+            center: Point { x: -1, y: 2 },
+            radius: 0.5,
+        },
+        Shape::Polygon(300, vec![1, 2, 3]),
+    ];
+    let mut msg = Message::new();
+    Header(1 << 40, 7, -3, vec![1, 2]).encode(&mut msg);
+    for shape in &shapes {
+        shape.encode(&mut msg);
+    }
+    Wrapper {
+        id: 9,
+        inner: Point { x: 3, y: 4 },
+    }
+    .encode(&mut msg);
+
+    assert_eq!(msg.decode::<Header>().unwrap(), Header(1 << 40, 7, -3, vec![1, 2]));
+    for shape in &shapes {
+        assert_eq!(&msg.decode::<Shape>().unwrap(), shape);
+    }
+    assert_eq!(
+        msg.decode::<Wrapper<Point>>().unwrap(),
+        Wrapper {
+            id: 9,
+            inner: Point { x: 3, y: 4 }
+        }
+    );
+    assert!(matches!(msg.decode::<u8>(), Err(MessageError::UnexpectedEnd)));
+}
+
+#[test]
+fn derive_matches_manual_encoding() {
+    let mut msg = Message::encode(&Shape::Polygon(5, vec![9]));
+    assert_eq!(msg.read_u32().unwrap(), 2);
+    assert_eq!(msg.read_varint_u32().unwrap(), 5);
+    assert_eq!(msg.read_buffer().unwrap(), &[9]);
+
+    let mut msg = Message::new();
+    msg.write_u32(3);
+    assert!(matches!(msg.decode::<Shape>(), Err(MessageError::InvalidValue)));
+
+    let mut msg = Message::new();
+    msg.write_u32(2);
+    msg.write_varint_u64(1 << 40);
+    assert!(matches!(msg.decode::<Shape>(), Err(MessageError::VarintOverflow)));
+}
+
+#[test]
+fn derive_collections() {
+    let mut settings = HashMap::new();
+    settings.insert("limit".to_string(), Some(-5));
+    settings.insert("quota".to_string(), None);
+    let user = User {
+        name: "Ondra".to_string(),
+        active: true,
+        initial: 'O',
+        email: None,
+        groups: vec![1, 2],
+        settings,
+        shapes: vec![Shape::Empty, Shape::Polygon(3, vec![])],
+    };
+    let mut msg = Message::encode(&user);
+    assert_eq!(msg.read_str().unwrap(), "Ondra");
+    assert!(msg.read_bool().unwrap());
+
+    let mut msg = Message::encode(&user);
+    assert_eq!(msg.decode::<User>().unwrap(), user);
+}
+
+#[test]
+fn derive_nesting_limit() {
+    let mut tree = Tree::default();
+    for _ in 0..50 {
+        tree = Tree {
+            children: vec![tree],
+        };
+    }
+    let mut msg = Message::encode(&tree);
+    assert_eq!(msg.decode::<Tree>().unwrap(), tree);
+
+    // Sequences of one element nested deep enough to overflow the stack
+    let mut msg = Message::from([1, 0, 0, 0].repeat(100_000));
+    assert!(matches!(
+        msg.decode::<Tree>(),
+        Err(MessageError::LimitExceeded)
+    ));
+}
@@ -0,0 +1,149 @@
+// Generated by simpletcp-codegen, do not edit
+
+/// Role of a user
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Role {
+    #[default]
+    Guest,
+    Admin,
+    Moderator,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for Role {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        msg.write_u32(match self {
+            Role::Guest => 0,
+            Role::Admin => 1,
+            Role::Moderator => 5,
+        });
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for Role {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        match msg.read_u32()? {
+            0 => Ok(Role::Guest),
+            1 => Ok(Role::Admin),
+            5 => Ok(Role::Moderator),
+            _ => Err(::simpletcp::simpletcp::MessageError::InvalidValue),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for Point {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        let mut body = ::simpletcp::simpletcp::Message::new();
+        body.set_tagged(msg.is_tagged());
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.x, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.y, &mut body);
+        msg.write_buffer(body.as_bytes());
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for Point {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        let mut body = msg.read_message()?;
+        Ok(Point {
+            x: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            y: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+        })
+    }
+}
+
+/// User account
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct User {
+    /// Unique id
+    pub id: u64,
+    pub name: ::std::string::String,
+    pub role: Role,
+    /// Last known positions
+    ///
+    /// Since version 2
+    pub path: ::std::vec::Vec<Point>,
+    /// Since version 2
+    pub email: ::std::option::Option<::std::string::String>,
+    /// Since version 3
+    pub permissions: ::std::collections::HashMap<Role, ::std::vec::Vec<u8>>,
+    /// Since version 3
+    pub r#type: u8,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for User {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        let mut body = ::simpletcp::simpletcp::Message::new();
+        body.set_tagged(msg.is_tagged());
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.id, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.name, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.role, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.path, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.email, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.permissions, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.r#type, &mut body);
+        msg.write_buffer(body.as_bytes());
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for User {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        let mut body = msg.read_message()?;
+        Ok(User {
+            id: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            name: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            role: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            path: if body.remaining() > 0 { ::simpletcp::simpletcp::MessageDecode::decode(&mut body)? } else { ::std::default::Default::default() },
+            email: if body.remaining() > 0 { ::simpletcp::simpletcp::MessageDecode::decode(&mut body)? } else { ::std::default::Default::default() },
+            permissions: if body.remaining() > 0 { ::simpletcp::simpletcp::MessageDecode::decode(&mut body)? } else { ::std::default::Default::default() },
+            r#type: if body.remaining() > 0 { ::simpletcp::simpletcp::MessageDecode::decode(&mut body)? } else { ::std::default::Default::default() },
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Empty {
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for Empty {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        let body = ::simpletcp::simpletcp::Message::new();
+        msg.write_buffer(body.as_bytes());
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for Empty {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        msg.read_message()?;
+        Ok(Empty {
+        })
+    }
+}
+
+/// Node of a tree
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Node {
+    pub children: ::std::vec::Vec<Node>,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for Node {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        let mut body = ::simpletcp::simpletcp::Message::new();
+        body.set_tagged(msg.is_tagged());
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.children, &mut body);
+        msg.write_buffer(body.as_bytes());
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for Node {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        let mut body = msg.read_message()?;
+        Ok(Node {
+            children: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+        })
+    }
+}
@@ -0,0 +1,85 @@
+// Generated by simpletcp-codegen, do not edit
+
+/// Role of a user
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Role {
+    #[default]
+    Guest,
+    Admin,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for Role {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        msg.write_u32(match self {
+            Role::Guest => 0,
+            Role::Admin => 1,
+        });
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for Role {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        match msg.read_u32()? {
+            0 => Ok(Role::Guest),
+            1 => Ok(Role::Admin),
+            _ => Err(::simpletcp::simpletcp::MessageError::InvalidValue),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for Point {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        let mut body = ::simpletcp::simpletcp::Message::new();
+        body.set_tagged(msg.is_tagged());
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.x, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.y, &mut body);
+        msg.write_buffer(body.as_bytes());
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for Point {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        let mut body = msg.read_message()?;
+        Ok(Point {
+            x: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            y: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+        })
+    }
+}
+
+/// User account
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct User {
+    /// Unique id
+    pub id: u64,
+    pub name: ::std::string::String,
+    pub role: Role,
+}
+
+impl ::simpletcp::simpletcp::MessageEncode for User {
+    fn encode(&self, msg: &mut ::simpletcp::simpletcp::Message) {
+        let mut body = ::simpletcp::simpletcp::Message::new();
+        body.set_tagged(msg.is_tagged());
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.id, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.name, &mut body);
+        ::simpletcp::simpletcp::MessageEncode::encode(&self.role, &mut body);
+        msg.write_buffer(body.as_bytes());
+    }
+}
+
+impl ::simpletcp::simpletcp::MessageDecode for User {
+    fn decode(msg: &mut ::simpletcp::simpletcp::Message) -> ::std::result::Result<Self, ::simpletcp::simpletcp::MessageError> {
+        let mut body = msg.read_message()?;
+        Ok(User {
+            id: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            name: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+            role: ::simpletcp::simpletcp::MessageDecode::decode(&mut body)?,
+        })
+    }
+}